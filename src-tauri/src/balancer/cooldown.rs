@@ -0,0 +1,218 @@
+use crate::diagnostics::ErrorCategory;
+use crate::state::StateDb;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use regex::Regex;
+use std::sync::LazyLock;
+
+const BASE_BACKOFF_SECS: i64 = 60;
+const MAX_BACKOFF_SECS: i64 = 60 * 60;
+/// Reset times further out than this are treated as parse noise.
+const MAX_PARSED_COOLDOWN_DAYS: i64 = 7;
+
+static RETRY_AFTER_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)(?:retry[- ]after|try again in|retry in)[:\s]+(\d+(?:\.\d+)?)\s*(ms|milliseconds?|s|secs?|seconds?|m|mins?|minutes?|h|hrs?|hours?)?\b",
+    )
+    .unwrap()
+});
+
+static RESET_AT_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)resets?(?:\s+at)?[:\s]+(\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:\.\d+)?(?:Z|[+-]\d{2}:?\d{2}))",
+    )
+    .unwrap()
+});
+
+static RESET_EPOCH_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)reset[a-z_-]*[:=\s]+(\d{10})\b").unwrap());
+
+/// Whether a failure in `category` should put the provider into cooldown.
+pub fn triggers_cooldown(category: ErrorCategory) -> bool {
+    matches!(
+        category,
+        ErrorCategory::RateLimit | ErrorCategory::QuotaExhausted
+    )
+}
+
+/// Extract when the provider says it will accept requests again, from
+/// "retry after 30s", "Retry-After: 120", "try again in 5 minutes",
+/// "resets at 2026-02-19T12:00:00Z" or "x-ratelimit-reset: 1771502400".
+pub fn parse_reset_time(stderr: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let parsed = parse_retry_after(stderr, now)
+        .or_else(|| parse_reset_at(stderr))
+        .or_else(|| parse_reset_epoch(stderr))?;
+
+    if parsed <= now || parsed > now + Duration::days(MAX_PARSED_COOLDOWN_DAYS) {
+        return None;
+    }
+    Some(parsed)
+}
+
+fn parse_retry_after(stderr: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let caps = RETRY_AFTER_RE.captures(stderr)?;
+    let amount: f64 = caps[1].parse().ok()?;
+    let unit = caps.get(2).map(|m| m.as_str().to_lowercase());
+    let millis_per_unit = match unit.as_deref() {
+        Some(u) if u.starts_with("ms") || u.starts_with("milli") => 1.0,
+        Some(u) if u.starts_with('m') => 60_000.0,
+        Some(u) if u.starts_with('h') => 3_600_000.0,
+        _ => 1_000.0,
+    };
+    Some(now + Duration::milliseconds((amount * millis_per_unit) as i64))
+}
+
+fn parse_reset_at(stderr: &str) -> Option<DateTime<Utc>> {
+    let caps = RESET_AT_RE.captures(stderr)?;
+    let normalized = caps[1].replacen(' ', "T", 1);
+    DateTime::parse_from_rfc3339(&normalized)
+        .ok()
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
+fn parse_reset_epoch(stderr: &str) -> Option<DateTime<Utc>> {
+    let caps = RESET_EPOCH_RE.captures(stderr)?;
+    let secs: i64 = caps[1].parse().ok()?;
+    Utc.timestamp_opt(secs, 0).single()
+}
+
+/// Exponential backoff for the `strikes`-th consecutive cooldown: 1m, 2m,
+/// 4m, ... capped at one hour.
+pub fn backoff(strikes: u32) -> Duration {
+    let exponent = strikes.saturating_sub(1).min(16);
+    let secs = BASE_BACKOFF_SECS.saturating_mul(1 << exponent);
+    Duration::seconds(secs.min(MAX_BACKOFF_SECS))
+}
+
+/// Put a provider into cooldown after a rate-limit or quota failure.
+///
+/// Uses the reset time reported in stderr when there is one, otherwise an
+/// exponential backoff based on how many cooldowns the provider has had
+/// since its last success. Returns the cooldown end, or `None` if
+/// `category` does not warrant a cooldown.
+pub fn record_failure(
+    model_name: &str,
+    provider_index: usize,
+    category: ErrorCategory,
+    stderr: &str,
+    state: &StateDb,
+) -> Result<Option<DateTime<Utc>>, String> {
+    if !triggers_cooldown(category) {
+        return Ok(None);
+    }
+
+    let now = Utc::now();
+    let until = match parse_reset_time(stderr, now) {
+        Some(until) => until,
+        None => {
+            let previous = state
+                .get_cooldown(model_name, provider_index)?
+                .map(|c| c.strikes)
+                .unwrap_or(0);
+            now + backoff(previous + 1)
+        }
+    };
+
+    state.set_cooldown(model_name, provider_index, until, category.as_str())?;
+    Ok(Some(until))
+}
+
+/// Whether the provider is cooling down right now.
+pub fn is_cooling_down(model_name: &str, provider_index: usize, state: &StateDb) -> bool {
+    cooldown_until(model_name, provider_index, state).is_some()
+}
+
+/// The end of the provider's active cooldown, if it has one.
+pub fn cooldown_until(
+    model_name: &str,
+    provider_index: usize,
+    state: &StateDb,
+) -> Option<DateTime<Utc>> {
+    state
+        .get_cooldown(model_name, provider_index)
+        .ok()
+        .flatten()
+        .map(|c| c.until)
+        .filter(|until| *until > Utc::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2026-02-19T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        let t = parse_reset_time("Error 429: please retry after 30 seconds", now()).unwrap();
+        assert_eq!(t - now(), Duration::seconds(30));
+    }
+
+    #[test]
+    fn parses_retry_after_header() {
+        let t = parse_reset_time("HTTP 429\nRetry-After: 120\n", now()).unwrap();
+        assert_eq!(t - now(), Duration::seconds(120));
+    }
+
+    #[test]
+    fn parses_try_again_in_minutes() {
+        let t = parse_reset_time("Rate limited. Try again in 5 minutes.", now()).unwrap();
+        assert_eq!(t - now(), Duration::minutes(5));
+    }
+
+    #[test]
+    fn parses_reset_timestamp() {
+        let t =
+            parse_reset_time("Usage limit reached, resets at 2026-02-19T15:30:00Z", now()).unwrap();
+        assert_eq!(t - now(), Duration::minutes(210));
+    }
+
+    #[test]
+    fn parses_reset_epoch() {
+        let reset = now().timestamp() + 600;
+        let stderr = format!("x-ratelimit-reset: {reset}");
+        let t = parse_reset_time(&stderr, now()).unwrap();
+        assert_eq!(t - now(), Duration::minutes(10));
+    }
+
+    #[test]
+    fn ignores_past_and_missing_reset_times() {
+        assert!(parse_reset_time("resets at 2020-01-01T00:00:00Z", now()).is_none());
+        assert!(parse_reset_time("429 Too Many Requests", now()).is_none());
+    }
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        assert_eq!(backoff(1), Duration::seconds(60));
+        assert_eq!(backoff(2), Duration::seconds(120));
+        assert_eq!(backoff(3), Duration::seconds(240));
+        assert_eq!(backoff(30), Duration::seconds(MAX_BACKOFF_SECS));
+    }
+
+    #[test]
+    fn record_failure_uses_backoff_without_reset_time() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+
+        let first = record_failure("m", 0, ErrorCategory::RateLimit, "429", &db)
+            .unwrap()
+            .unwrap();
+        let second = record_failure("m", 0, ErrorCategory::RateLimit, "429", &db)
+            .unwrap()
+            .unwrap();
+        assert!(second - first >= Duration::seconds(55));
+        assert!(is_cooling_down("m", 0, &db));
+        assert!(!is_cooling_down("m", 1, &db));
+    }
+
+    #[test]
+    fn record_failure_ignores_other_categories() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let until = record_failure("m", 0, ErrorCategory::AuthExpired, "401", &db).unwrap();
+        assert!(until.is_none());
+        assert!(!is_cooling_down("m", 0, &db));
+    }
+}
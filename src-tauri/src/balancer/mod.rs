@@ -1,65 +1,148 @@
+pub mod cooldown;
+pub mod strategy;
+
 use crate::config::ModelConfig;
 use crate::state::StateDb;
+use std::time::Duration;
 
 const ERROR_WINDOW_MINUTES: i64 = 30;
 const ERROR_THRESHOLD: u64 = 3;
+/// How long a lease holds a slot unless renewed. Running invocations renew
+/// theirs every [`HEARTBEAT_INTERVAL`](crate::executor::HEARTBEAT_INTERVAL), so a crashed run frees its slot
+/// within this long.
+pub const LEASE_TTL: Duration = Duration::from_secs(2 * 60);
+
+/// A provider picked by [`select_and_reserve`] and the lease holding its slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reservation {
+    pub provider_index: usize,
+    pub lease_id: i64,
+}
 
-pub fn select_provider(model: &ModelConfig, state: &StateDb) -> usize {
-    let n = model.providers.len();
-    if n <= 1 {
-        return 0;
+/// Pick a provider, preferring ones that are not cooling down. `None` when
+/// every provider is at its `max_concurrent` limit.
+pub fn select_provider(model: &ModelConfig, state: &StateDb) -> Option<usize> {
+    select_provider_excluding(model, state, &[]).or_else(|| soonest_available(model, state))
+}
+
+/// Pick the best provider that is not in `exclude`, not cooling down and not
+/// at its `max_concurrent` limit, or `None` when no such provider exists.
+/// Used for failover within a single invocation, where providers that
+/// already failed must not be retried.
+///
+/// Providers with too many recent errors are skipped unless every candidate
+/// has them; the model's strategy then chooses among the rest.
+pub fn select_provider_excluding(
+    model: &ModelConfig,
+    state: &StateDb,
+    exclude: &[usize],
+) -> Option<usize> {
+    let candidates: Vec<usize> = (0..model.providers.len())
+        .filter(|i| !exclude.contains(i))
+        .filter(|&i| !cooldown::is_cooling_down(&model.name, i, state))
+        .filter(|&i| has_free_slot(model, state, i))
+        .collect();
+    if candidates.len() <= 1 {
+        return candidates.first().copied();
     }
 
-    // Collect stats for each provider
-    let mut scores: Vec<(usize, f64)> = Vec::with_capacity(n);
+    let healthy: Vec<usize> = candidates
+        .iter()
+        .copied()
+        .filter(|&i| {
+            state
+                .recent_error_count(&model.name, i, ERROR_WINDOW_MINUTES)
+                .unwrap_or(0)
+                < ERROR_THRESHOLD
+        })
+        .collect();
 
-    for i in 0..n {
-        let recent_errors = state
-            .recent_error_count(&model.name, i, ERROR_WINDOW_MINUTES)
-            .unwrap_or(0);
+    // If all providers are penalized, fall back to the one with fewest total invocations
+    if healthy.is_empty() {
+        return Some(round_robin_fallback(model, state, &candidates));
+    }
 
-        // Providers with too many recent errors get a penalty
-        if recent_errors >= ERROR_THRESHOLD {
-            scores.push((i, f64::MAX));
-            continue;
-        }
+    Some(strategy::for_kind(model.strategy).pick(model, state, &healthy))
+}
 
-        let invocation_count = state
-            .get_provider(&model.name, i)
-            .ok()
-            .flatten()
-            .map(|p| p.invocation_count)
-            .unwrap_or(0);
+/// Select a provider, count the call against it and take a lease on one of
+/// its slots, all in one write transaction, so concurrent runs (other
+/// processes included) see each other's picks instead of all choosing the
+/// same least-used provider.
+///
+/// With an empty `exclude` a provider that is cooling down is still picked
+/// if nothing else is available, like [`select_provider`]. Returns `None`
+/// when every remaining provider is excluded or at its `max_concurrent`
+/// limit. The lease lasts [`LEASE_TTL`]; keep it alive with
+/// [`renew_lease`] and release it with [`StateDb::release_lease`].
+pub fn select_and_reserve(
+    model: &ModelConfig,
+    state: &StateDb,
+    exclude: &[usize],
+) -> Result<Option<Reservation>, String> {
+    state.immediate(|state| {
+        let picked = if exclude.is_empty() {
+            select_provider(model, state)
+        } else {
+            select_provider_excluding(model, state, exclude)
+        };
+        let Some(provider_index) = picked else {
+            return Ok(None);
+        };
 
-        // Score = invocation_count + error_penalty
-        // Lower is better (round-robin effect: pick least-used)
-        let error_penalty = recent_errors as f64 * 10.0;
-        scores.push((i, invocation_count as f64 + error_penalty));
-    }
+        state.reserve_provider(&model.name, provider_index)?;
+        let lease_id = state.acquire_lease(&model.name, provider_index, lease_ttl())?;
+        Ok(Some(Reservation {
+            provider_index,
+            lease_id,
+        }))
+    })
+}
 
-    // Pick the provider with the lowest score
-    scores.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+/// Extend a lease taken by [`select_and_reserve`] by another [`LEASE_TTL`].
+pub fn renew_lease(state: &StateDb, lease_id: i64) -> Result<(), String> {
+    state.renew_lease(lease_id, lease_ttl())
+}
 
-    // If all providers are penalized, fall back to the one with fewest total invocations
-    if scores.iter().all(|(_, s)| *s == f64::MAX) {
-        return round_robin_fallback(model, state);
-    }
+fn lease_ttl() -> chrono::Duration {
+    chrono::Duration::from_std(LEASE_TTL).unwrap_or(chrono::Duration::MAX)
+}
+
+/// Whether every provider is at its `max_concurrent` limit. Always false if
+/// any provider is unlimited.
+pub fn all_saturated(model: &ModelConfig, state: &StateDb) -> bool {
+    (0..model.providers.len()).all(|i| !has_free_slot(model, state, i))
+}
 
-    scores[0].0
+fn has_free_slot(model: &ModelConfig, state: &StateDb, provider_index: usize) -> bool {
+    let Some(max) = model.providers[provider_index].max_concurrent else {
+        return true;
+    };
+    state
+        .in_flight_count(&model.name, provider_index)
+        .map(|n| n < max as u64)
+        .unwrap_or(true)
 }
 
-fn round_robin_fallback(model: &ModelConfig, state: &StateDb) -> usize {
-    let n = model.providers.len();
+/// Whether every provider of the model is currently cooling down.
+pub fn all_cooling_down(model: &ModelConfig, state: &StateDb) -> bool {
+    (0..model.providers.len()).all(|i| cooldown::is_cooling_down(&model.name, i, state))
+}
+
+/// The provider with a free slot whose cooldown ends first, for when every
+/// such provider is cooling down.
+fn soonest_available(model: &ModelConfig, state: &StateDb) -> Option<usize> {
+    (0..model.providers.len())
+        .filter(|&i| has_free_slot(model, state, i))
+        .min_by_key(|&i| cooldown::cooldown_until(&model.name, i, state))
+}
+
+fn round_robin_fallback(model: &ModelConfig, state: &StateDb, candidates: &[usize]) -> usize {
     let mut min_count = u64::MAX;
-    let mut best = 0;
-
-    for i in 0..n {
-        let count = state
-            .get_provider(&model.name, i)
-            .ok()
-            .flatten()
-            .map(|p| p.invocation_count)
-            .unwrap_or(0);
+    let mut best = candidates[0];
+
+    for &i in candidates {
+        let count = invocation_count(model, state, i);
         if count < min_count {
             min_count = count;
             best = i;
@@ -69,10 +152,20 @@ fn round_robin_fallback(model: &ModelConfig, state: &StateDb) -> usize {
     best
 }
 
+fn invocation_count(model: &ModelConfig, state: &StateDb, provider_index: usize) -> u64 {
+    state
+        .get_provider(&model.name, provider_index)
+        .ok()
+        .flatten()
+        .map(|p| p.invocation_count)
+        .unwrap_or(0)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{ProviderConfig, model::PromptMode};
+    use crate::config::{BalanceStrategy, ProviderConfig, model::PromptMode};
+    use crate::state::NewInvocation;
     use std::path::Path;
 
     fn two_provider_model() -> ModelConfig {
@@ -83,12 +176,15 @@ mod tests {
                 ProviderConfig {
                     command: "a".to_string(),
                     args: vec![],
+                    ..Default::default()
                 },
                 ProviderConfig {
                     command: "b".to_string(),
                     args: vec![],
+                    ..Default::default()
                 },
             ],
+            ..Default::default()
         }
     }
 
@@ -101,9 +197,11 @@ mod tests {
             providers: vec![ProviderConfig {
                 command: "x".to_string(),
                 args: vec![],
+                ..Default::default()
             }],
+            ..Default::default()
         };
-        assert_eq!(select_provider(&model, &db), 0);
+        assert_eq!(select_provider(&model, &db).unwrap(), 0);
     }
 
     #[test]
@@ -112,15 +210,19 @@ mod tests {
         let model = two_provider_model();
 
         // Both at 0 invocations, should pick 0
-        let first = select_provider(&model, &db);
+        let first = select_provider(&model, &db).unwrap();
         assert_eq!(first, 0);
 
         // Record invocation for provider 0
-        db.record_invocation("test", 0, true, 0, None, None)
-            .unwrap();
+        db.record_invocation(&NewInvocation {
+            model_name: "test",
+            success: true,
+            ..Default::default()
+        })
+        .unwrap();
 
         // Now should pick provider 1 (fewer invocations)
-        let second = select_provider(&model, &db);
+        let second = select_provider(&model, &db).unwrap();
         assert_eq!(second, 1);
     }
 
@@ -131,11 +233,204 @@ mod tests {
 
         // Provider 0 has 3 recent errors
         for _ in 0..3 {
-            db.record_invocation("test", 0, false, 1, None, None)
-                .unwrap();
+            db.record_invocation(&NewInvocation {
+                model_name: "test",
+                exit_code: 1,
+                ..Default::default()
+            })
+            .unwrap();
         }
 
         // Should avoid provider 0
-        assert_eq!(select_provider(&model, &db), 1);
+        assert_eq!(select_provider(&model, &db).unwrap(), 1);
+    }
+
+    #[test]
+    fn excluding_skips_tried_providers() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let model = two_provider_model();
+
+        assert_eq!(select_provider_excluding(&model, &db, &[0]), Some(1));
+        assert_eq!(select_provider_excluding(&model, &db, &[1]), Some(0));
+        assert_eq!(select_provider_excluding(&model, &db, &[0, 1]), None);
+    }
+
+    #[test]
+    fn skips_providers_in_cooldown() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let model = two_provider_model();
+
+        let until = chrono::Utc::now() + chrono::Duration::minutes(10);
+        db.set_cooldown("test", 0, until, "rate_limit").unwrap();
+
+        assert_eq!(select_provider(&model, &db).unwrap(), 1);
+        assert_eq!(select_provider_excluding(&model, &db, &[1]), None);
+        assert!(!all_cooling_down(&model, &db));
+    }
+
+    #[test]
+    fn expired_cooldown_is_ignored() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let model = two_provider_model();
+
+        let past = chrono::Utc::now() - chrono::Duration::minutes(1);
+        db.set_cooldown("test", 0, past, "rate_limit").unwrap();
+
+        assert_eq!(select_provider(&model, &db).unwrap(), 0);
+    }
+
+    #[test]
+    fn all_cooling_down_picks_soonest_expiry() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let model = two_provider_model();
+
+        let now = chrono::Utc::now();
+        db.set_cooldown(
+            "test",
+            0,
+            now + chrono::Duration::minutes(30),
+            "quota_exhausted",
+        )
+        .unwrap();
+        db.set_cooldown("test", 1, now + chrono::Duration::minutes(5), "rate_limit")
+            .unwrap();
+
+        assert!(all_cooling_down(&model, &db));
+        assert_eq!(select_provider(&model, &db).unwrap(), 1);
+    }
+
+    #[test]
+    fn reservations_count_before_the_run_finishes() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let model = two_provider_model();
+
+        let pick = |exclude: &[usize]| {
+            select_and_reserve(&model, &db, exclude)
+                .unwrap()
+                .map(|r| r.provider_index)
+        };
+        assert_eq!(pick(&[]), Some(0));
+        assert_eq!(pick(&[]), Some(1));
+        assert_eq!(pick(&[0, 1]), None);
+
+        db.record_invocation(&NewInvocation {
+            model_name: "test",
+            success: true,
+            reserved: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let provider = db.get_provider("test", 0).unwrap().unwrap();
+        assert_eq!(provider.invocation_count, 1);
+    }
+
+    #[test]
+    fn concurrent_reservations_spread_across_providers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.db");
+        StateDb::open(&path).unwrap();
+
+        let model = ModelConfig {
+            providers: (0..4)
+                .map(|i| ProviderConfig {
+                    command: format!("p{i}"),
+                    ..Default::default()
+                })
+                .collect(),
+            ..two_provider_model()
+        };
+
+        std::thread::scope(|scope| {
+            for _ in 0..16 {
+                scope.spawn(|| {
+                    let db = StateDb::open(&path).unwrap();
+                    select_and_reserve(&model, &db, &[]).unwrap().unwrap()
+                });
+            }
+        });
+
+        let db = StateDb::open(&path).unwrap();
+        for i in 0..4 {
+            let count = db
+                .get_provider("test", i)
+                .unwrap()
+                .unwrap()
+                .invocation_count;
+            assert_eq!(count, 4, "provider {i}");
+        }
+    }
+
+    #[test]
+    fn saturated_providers_are_skipped() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let mut model = two_provider_model();
+        model.providers[0].max_concurrent = Some(1);
+        model.providers[1].max_concurrent = Some(2);
+
+        let first = select_and_reserve(&model, &db, &[]).unwrap().unwrap();
+        assert_eq!(first.provider_index, 0);
+        // Provider 0 is full, so everything goes to 1 until it is full too.
+        for _ in 0..2 {
+            let r = select_and_reserve(&model, &db, &[]).unwrap().unwrap();
+            assert_eq!(r.provider_index, 1);
+        }
+        assert!(all_saturated(&model, &db));
+        assert_eq!(select_and_reserve(&model, &db, &[]).unwrap(), None);
+        assert_eq!(select_provider(&model, &db), None);
+
+        db.release_lease(first.lease_id).unwrap();
+        assert!(!all_saturated(&model, &db));
+        assert_eq!(select_provider(&model, &db).unwrap(), 0);
+    }
+
+    #[test]
+    fn cooling_down_fallback_respects_limits() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let mut model = two_provider_model();
+        model.providers[1].max_concurrent = Some(1);
+
+        let until = chrono::Utc::now() + chrono::Duration::minutes(10);
+        db.set_cooldown("test", 0, until, "rate_limit").unwrap();
+        db.set_cooldown(
+            "test",
+            1,
+            until - chrono::Duration::minutes(5),
+            "rate_limit",
+        )
+        .unwrap();
+        db.acquire_lease("test", 1, chrono::Duration::minutes(5))
+            .unwrap();
+
+        // Provider 1 would be available sooner but has no free slot.
+        assert_eq!(select_provider(&model, &db).unwrap(), 0);
+    }
+
+    #[test]
+    fn strategy_applies_after_health_filter() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let mut model = two_provider_model();
+        model.strategy = BalanceStrategy::Priority;
+
+        for _ in 0..5 {
+            db.record_invocation(&NewInvocation {
+                model_name: "test",
+                success: true,
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        // Priority ignores usage counts...
+        assert_eq!(select_provider(&model, &db).unwrap(), 0);
+
+        // ...but not a provider that keeps failing.
+        for _ in 0..3 {
+            db.record_invocation(&NewInvocation {
+                model_name: "test",
+                exit_code: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        assert_eq!(select_provider(&model, &db).unwrap(), 1);
     }
 }
@@ -0,0 +1,255 @@
+use super::{ERROR_WINDOW_MINUTES, invocation_count};
+use crate::config::{BalanceStrategy, ModelConfig};
+use crate::state::StateDb;
+
+/// Number of recent successful invocations averaged by [`LeastLatency`].
+const LATENCY_SAMPLE_SIZE: usize = 10;
+
+/// Picks one provider out of a non-empty list of healthy candidates.
+///
+/// Candidates are already filtered for exclusions, cooldowns and error
+/// thresholds; a strategy only decides the order of preference among them.
+pub trait Strategy {
+    fn pick(&self, model: &ModelConfig, state: &StateDb, candidates: &[usize]) -> usize;
+}
+
+/// The strategy implementation for a model's configured `strategy`.
+pub fn for_kind(kind: BalanceStrategy) -> Box<dyn Strategy> {
+    match kind {
+        BalanceStrategy::LeastUsed => Box::new(LeastUsed),
+        BalanceStrategy::RoundRobin => Box::new(RoundRobin),
+        BalanceStrategy::Weighted => Box::new(Weighted),
+        BalanceStrategy::Priority => Box::new(Priority),
+        BalanceStrategy::LeastLatency => Box::new(LeastLatency),
+    }
+}
+
+/// Fewest total invocations, with a penalty for each recent error.
+pub struct LeastUsed;
+
+impl Strategy for LeastUsed {
+    fn pick(&self, model: &ModelConfig, state: &StateDb, candidates: &[usize]) -> usize {
+        min_by_score(candidates, |i| {
+            let recent_errors = state
+                .recent_error_count(&model.name, i, ERROR_WINDOW_MINUTES)
+                .unwrap_or(0);
+            invocation_count(model, state, i) as f64 + recent_errors as f64 * 10.0
+        })
+    }
+}
+
+/// Cycles through providers in config order, starting after the one that
+/// served the previous invocation.
+pub struct RoundRobin;
+
+impl Strategy for RoundRobin {
+    fn pick(&self, model: &ModelConfig, state: &StateDb, candidates: &[usize]) -> usize {
+        let Some(last) = state.last_invoked_provider(&model.name).ok().flatten() else {
+            return candidates[0];
+        };
+        candidates
+            .iter()
+            .copied()
+            .find(|&i| i > last)
+            .unwrap_or(candidates[0])
+    }
+}
+
+/// Spreads invocations in proportion to each provider's `weight` (default 1).
+/// A weight of 0 is only used when nothing else is available.
+pub struct Weighted;
+
+impl Strategy for Weighted {
+    fn pick(&self, model: &ModelConfig, state: &StateDb, candidates: &[usize]) -> usize {
+        min_by_score(candidates, |i| {
+            let weight = model.providers[i].weight.unwrap_or(1);
+            if weight == 0 {
+                return f64::MAX;
+            }
+            (invocation_count(model, state, i) + 1) as f64 / weight as f64
+        })
+    }
+}
+
+/// Always the first healthy provider in config order; later providers are
+/// fallbacks.
+pub struct Priority;
+
+impl Strategy for Priority {
+    fn pick(&self, _model: &ModelConfig, _state: &StateDb, candidates: &[usize]) -> usize {
+        candidates[0]
+    }
+}
+
+/// Lowest average duration over recent successful invocations. Providers
+/// without timing data go first so every provider gets measured.
+pub struct LeastLatency;
+
+impl Strategy for LeastLatency {
+    fn pick(&self, model: &ModelConfig, state: &StateDb, candidates: &[usize]) -> usize {
+        min_by_score(candidates, |i| {
+            state
+                .recent_average_latency_ms(&model.name, i, LATENCY_SAMPLE_SIZE)
+                .ok()
+                .flatten()
+                .unwrap_or(0.0)
+        })
+    }
+}
+
+/// The candidate with the lowest score; ties go to the earliest in config order.
+fn min_by_score(candidates: &[usize], score: impl Fn(usize) -> f64) -> usize {
+    let mut best = candidates[0];
+    let mut best_score = f64::INFINITY;
+    for &i in candidates {
+        let s = score(i);
+        if s < best_score {
+            best = i;
+            best_score = s;
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PromptMode, ProviderConfig};
+    use crate::state::NewInvocation;
+    use std::path::Path;
+
+    fn model(weights: &[Option<u32>]) -> ModelConfig {
+        ModelConfig {
+            name: "m".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: weights
+                .iter()
+                .map(|&weight| ProviderConfig {
+                    command: "x".to_string(),
+                    weight,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn db() -> StateDb {
+        StateDb::open(Path::new(":memory:")).unwrap()
+    }
+
+    /// Pick with `strategy` `n` times, recording a successful invocation
+    /// after each pick, and return the picks.
+    fn simulate(
+        strategy: &dyn Strategy,
+        model: &ModelConfig,
+        db: &StateDb,
+        n: usize,
+    ) -> Vec<usize> {
+        let candidates: Vec<usize> = (0..model.providers.len()).collect();
+        (0..n)
+            .map(|_| {
+                let i = strategy.pick(model, db, &candidates);
+                db.record_invocation(&NewInvocation {
+                    model_name: &model.name,
+                    provider_index: i,
+                    success: true,
+                    ..Default::default()
+                })
+                .unwrap();
+                i
+            })
+            .collect()
+    }
+
+    #[test]
+    fn least_used_balances_counts() {
+        let db = db();
+        let m = model(&[None, None]);
+        db.record_invocation(&NewInvocation {
+            model_name: "m",
+            success: true,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(LeastUsed.pick(&m, &db, &[0, 1]), 1);
+    }
+
+    #[test]
+    fn round_robin_cycles_in_order() {
+        let db = db();
+        let m = model(&[None, None, None]);
+        assert_eq!(simulate(&RoundRobin, &m, &db, 5), vec![0, 1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn round_robin_skips_missing_candidates() {
+        let db = db();
+        let m = model(&[None, None, None]);
+        db.record_invocation(&NewInvocation {
+            model_name: "m",
+            success: true,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(RoundRobin.pick(&m, &db, &[0, 2]), 2);
+        db.record_invocation(&NewInvocation {
+            model_name: "m",
+            provider_index: 2,
+            success: true,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(RoundRobin.pick(&m, &db, &[0, 1]), 0);
+    }
+
+    #[test]
+    fn weighted_follows_weights() {
+        let db = db();
+        let m = model(&[Some(3), Some(1)]);
+        let picks = simulate(&Weighted, &m, &db, 8);
+        assert_eq!(picks.iter().filter(|&&i| i == 0).count(), 6);
+        assert_eq!(picks.iter().filter(|&&i| i == 1).count(), 2);
+    }
+
+    #[test]
+    fn weighted_zero_is_last_resort() {
+        let db = db();
+        let m = model(&[Some(0), None]);
+        assert_eq!(simulate(&Weighted, &m, &db, 3), vec![1, 1, 1]);
+        assert_eq!(Weighted.pick(&m, &db, &[0]), 0);
+    }
+
+    #[test]
+    fn priority_prefers_config_order() {
+        let db = db();
+        let m = model(&[None, None, None]);
+        assert_eq!(simulate(&Priority, &m, &db, 3), vec![0, 0, 0]);
+        assert_eq!(Priority.pick(&m, &db, &[1, 2]), 1);
+    }
+
+    #[test]
+    fn least_latency_prefers_fastest_and_measures_unknown() {
+        let db = db();
+        let m = model(&[None, None, None]);
+        db.record_invocation(&NewInvocation {
+            model_name: "m",
+            success: true,
+            duration_ms: Some(900),
+            ..Default::default()
+        })
+        .unwrap();
+        db.record_invocation(&NewInvocation {
+            model_name: "m",
+            provider_index: 1,
+            success: true,
+            duration_ms: Some(200),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // Provider 2 has never been timed, so it is tried first.
+        assert_eq!(LeastLatency.pick(&m, &db, &[0, 1, 2]), 2);
+        assert_eq!(LeastLatency.pick(&m, &db, &[0, 1]), 1);
+    }
+}
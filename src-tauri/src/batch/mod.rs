@@ -0,0 +1,598 @@
+use crate::config::{AgentConfig, ModelConfig, TemplateContext, load_agents};
+use crate::context;
+use crate::diagnostics::Diagnosis;
+use crate::runner::{self, Attempt, ModelHop, RunOptions};
+use crate::state::StateDb;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// One prompt to run, as written in the manifest.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchEntry {
+    /// Key used to match results to entries when resuming. Defaults to the
+    /// entry's 1-based position in the manifest.
+    pub id: Option<String>,
+    pub agent: Option<String>,
+    /// Model to run; overrides the agent's model when both are given.
+    pub model: Option<String>,
+    pub prompt: Option<String>,
+    /// Read the prompt from this file (relative to the manifest).
+    pub prompt_file: Option<PathBuf>,
+    /// Working directory for the provider (relative to the manifest).
+    pub project: Option<PathBuf>,
+    /// Template variables for the agent, on top of `--var`.
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    /// Globs of files (relative to `project`) attached before the prompt,
+    /// after the agent's own `context`.
+    #[serde(default)]
+    pub context: Vec<String>,
+}
+
+/// The outcome of one entry, written as a line of the results JSONL.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BatchResult {
+    pub id: String,
+    pub success: bool,
+    pub model: Option<String>,
+    pub agent: Option<String>,
+    pub provider_index: Option<usize>,
+    pub command: Option<String>,
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub duration_ms: u64,
+    pub diagnosis: Option<Diagnosis>,
+    #[serde(default)]
+    pub attempts: Vec<Attempt>,
+    /// Set when stdout did not match the agent's `output_format`.
+    pub output_error: Option<String>,
+    /// Models of the agent's `models:` list given up on before `model`.
+    #[serde(default)]
+    pub hops: Vec<ModelHop>,
+    /// Set when the entry could not be run at all (unknown model, missing
+    /// prompt file, ...).
+    pub error: Option<String>,
+}
+
+pub struct BatchOptions<'a> {
+    /// Results JSONL; appended to, and read on start to skip finished entries.
+    pub results_path: &'a Path,
+    pub parallelism: usize,
+    /// Run entries again whose recorded result was a failure.
+    pub retry_failed: bool,
+    /// Template variables for every entry's agent.
+    pub vars: &'a HashMap<String, String>,
+    pub models: &'a HashMap<String, ModelConfig>,
+    pub agents_dir: &'a Path,
+    pub diagnostics_model: Option<&'a ModelConfig>,
+    pub timeout: Option<Duration>,
+    /// State DB shared by all workers; each opens its own connection.
+    /// `None` uses the default location.
+    pub state_path: Option<&'a Path>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub total: usize,
+    /// Entries already in the results file and not re-run.
+    pub skipped: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+/// Parse a manifest: JSON Lines (`.jsonl`, one entry per line) or a YAML
+/// list (`.yaml`/`.yml`). Returns each entry with its resolved id.
+pub fn load_manifest(path: &Path) -> Result<Vec<(String, BatchEntry)>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read manifest {}: {e}", path.display()))?;
+
+    let entries: Vec<BatchEntry> = match path.extension().and_then(|e| e.to_str()) {
+        Some("yaml" | "yml") => serde_yml::from_str(&content)
+            .map_err(|e| format!("Manifest {}: YAML parse error: {e}", path.display()))?,
+        _ => content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(n, line)| {
+                serde_json::from_str(line)
+                    .map_err(|e| format!("Manifest {} line {}: {e}", path.display(), n + 1))
+            })
+            .collect::<Result<_, _>>()?,
+    };
+
+    let mut seen = HashSet::new();
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let id = entry.id.clone().unwrap_or_else(|| (i + 1).to_string());
+            if !seen.insert(id.clone()) {
+                return Err(format!("Manifest {}: duplicate id '{id}'", path.display()));
+            }
+            if entry.agent.is_none() && entry.model.is_none() {
+                return Err(format!("Manifest entry '{id}': needs an agent or a model"));
+            }
+            if entry.prompt.is_some() == entry.prompt_file.is_some() {
+                return Err(format!(
+                    "Manifest entry '{id}': needs exactly one of prompt or prompt_file"
+                ));
+            }
+            Ok((id, entry))
+        })
+        .collect()
+}
+
+/// Ids already in the results file. Lines that do not parse (e.g. one cut
+/// short by an interruption) are reported and skipped, so that entry runs
+/// again.
+pub fn completed_ids(results_path: &Path, include_failed: bool) -> HashSet<String> {
+    let Ok(content) = fs::read_to_string(results_path) else {
+        return HashSet::new();
+    };
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .filter_map(
+            |(i, line)| match serde_json::from_str::<BatchResult>(line) {
+                Ok(result) => Some(result),
+                Err(e) => {
+                    eprintln!(
+                        "Warning: Ignoring line {} of {}: {e}",
+                        i + 1,
+                        results_path.display()
+                    );
+                    None
+                }
+            },
+        )
+        .filter(|r| include_failed || r.success)
+        .map(|r| r.id)
+        .collect()
+}
+
+/// Run every manifest entry not already in the results file, `parallelism`
+/// at a time, appending one result line per entry as it finishes.
+pub fn run_batch(manifest: &Path, opts: &BatchOptions) -> Result<BatchSummary, String> {
+    let entries = load_manifest(manifest)?;
+    let base_dir = manifest.parent().unwrap_or(Path::new("."));
+
+    let done = completed_ids(opts.results_path, !opts.retry_failed);
+    let pending: Vec<&(String, BatchEntry)> = entries
+        .iter()
+        .filter(|(id, _)| !done.contains(id))
+        .collect();
+
+    let agents = if pending.iter().any(|(_, e)| e.agent.is_some()) {
+        load_agents(opts.agents_dir)?
+    } else {
+        HashMap::new()
+    };
+
+    let results = Mutex::new(open_results(opts.results_path)?);
+    let next = AtomicUsize::new(0);
+    let finished = AtomicUsize::new(0);
+    let succeeded = AtomicUsize::new(0);
+    let workers = opts.parallelism.clamp(1, pending.len().max(1));
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                let state = open_state(opts.state_path);
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    let Some((id, entry)) = pending.get(i).copied() else {
+                        break;
+                    };
+
+                    let result = run_entry(id, entry, base_dir, &agents, &state, opts);
+                    let line = serde_json::to_string(&result).unwrap_or_default();
+                    if let Ok(mut file) = results.lock() {
+                        let _ = writeln!(file, "{line}").and_then(|_| file.flush());
+                    }
+
+                    if result.success {
+                        succeeded.fetch_add(1, Ordering::SeqCst);
+                    }
+                    let n = finished.fetch_add(1, Ordering::SeqCst) + 1;
+                    eprintln!("[batch] {n}/{} {id}: {}", pending.len(), describe(&result));
+                }
+            });
+        }
+    });
+
+    let succeeded = succeeded.into_inner();
+    Ok(BatchSummary {
+        total: entries.len(),
+        skipped: entries.len() - pending.len(),
+        succeeded,
+        failed: pending.len() - succeeded,
+    })
+}
+
+fn run_entry(
+    id: &str,
+    entry: &BatchEntry,
+    base_dir: &Path,
+    agents: &HashMap<String, AgentConfig>,
+    state: &StateDb,
+    opts: &BatchOptions,
+) -> BatchResult {
+    let started = Instant::now();
+    let agent = entry.agent.as_ref().and_then(|name| agents.get(name));
+    let mut result = BatchResult {
+        id: id.to_string(),
+        agent: entry.agent.clone(),
+        ..Default::default()
+    };
+
+    let outcome = prepare(entry, base_dir, agents, opts.models, opts.vars).and_then(
+        |(chain, prompt, project)| {
+            let run_opts = RunOptions {
+                working_dir: project.as_deref(),
+                agent_name: entry.agent.as_deref(),
+                run_id: None,
+                cancel: None,
+                diagnostics_model: opts.diagnostics_model,
+                models: opts.models,
+                timeout: opts.timeout,
+                on_output: None,
+                output_format: agent.and_then(AgentConfig::output_format),
+                output_retry: agent.is_some_and(AgentConfig::output_retry),
+            };
+            runner::run_with_fallback(&chain, &prompt, state, &run_opts)
+        },
+    );
+
+    match outcome {
+        Ok(outcome) => {
+            result.success = outcome.success();
+            result.model = Some(outcome.model.clone());
+            result.provider_index = Some(outcome.result.provider_index);
+            result.command = outcome.attempts.last().map(|a| a.command.clone());
+            result.exit_code = Some(outcome.result.exit_code);
+            result.stdout = outcome.result.stdout;
+            result.stderr = outcome.result.stderr;
+            result.diagnosis = outcome.diagnosis;
+            result.attempts = outcome.attempts;
+            result.output_error = outcome.output_error;
+            result.hops = outcome.hops;
+        }
+        Err(e) => result.error = Some(e),
+    }
+    result.duration_ms = started.elapsed().as_millis() as u64;
+    result
+}
+
+/// Resolve an entry's models (its `model`, or the agent's chain), full
+/// prompt and working directory. `vars` sit under the entry's own.
+pub(crate) fn prepare<'a>(
+    entry: &BatchEntry,
+    base_dir: &Path,
+    agents: &HashMap<String, AgentConfig>,
+    models: &'a HashMap<String, ModelConfig>,
+    vars: &HashMap<String, String>,
+) -> Result<(Vec<&'a ModelConfig>, String, Option<PathBuf>), String> {
+    let agent = entry
+        .agent
+        .as_ref()
+        .map(|name| {
+            agents
+                .get(name)
+                .ok_or_else(|| format!("Unknown agent: {name}"))
+        })
+        .transpose()?;
+
+    let names = match (&entry.model, agent) {
+        (Some(model), _) => vec![model.as_str()],
+        (None, Some(agent)) => agent.model_chain(),
+        (None, None) => unreachable!("validated by load_manifest"),
+    };
+    let chain = names
+        .into_iter()
+        .map(|name| {
+            models
+                .get(name)
+                .ok_or_else(|| format!("Unknown model: {name}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let prompt = match (&entry.prompt, &entry.prompt_file) {
+        (Some(prompt), _) => prompt.clone(),
+        (None, Some(path)) => {
+            let path = base_dir.join(path);
+            fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read prompt file {}: {e}", path.display()))?
+        }
+        (None, None) => unreachable!("validated by load_manifest"),
+    };
+    let project = entry.project.as_ref().map(|p| base_dir.join(p));
+    let prompt = match agent {
+        Some(agent) => {
+            let mut vars = vars.clone();
+            vars.extend(entry.vars.clone());
+            agent.render(&TemplateContext {
+                prompt,
+                project_dir: project.clone(),
+                vars,
+            })?
+        }
+        None => prompt,
+    };
+
+    let mut patterns = agent.map(|a| a.context.clone()).unwrap_or_default();
+    patterns.extend(entry.context.iter().cloned());
+    let prompt = context::attach(&prompt, &patterns, project.as_deref().unwrap_or(base_dir))?;
+
+    Ok((chain, prompt, project))
+}
+
+/// Refuse an entry from a remote client whose `project` or `context` reaches
+/// outside `base_dir`: absolute paths, `..`, or a project that is a symlink
+/// out of it.
+pub(crate) fn confine(entry: &BatchEntry, base_dir: &Path) -> Result<(), String> {
+    let escapes = |path: &Path| {
+        path.components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    };
+    for pattern in &entry.context {
+        if escapes(Path::new(pattern)) {
+            return Err(format!(
+                "Context pattern '{pattern}' must be relative, without '..'"
+            ));
+        }
+    }
+    let Some(ref project) = entry.project else {
+        return Ok(());
+    };
+    if escapes(project) {
+        return Err(format!(
+            "Project '{}' must be relative to the project root, without '..'",
+            project.display()
+        ));
+    }
+    let root = base_dir
+        .canonicalize()
+        .map_err(|e| format!("Project root {}: {e}", base_dir.display()))?;
+    match root.join(project).canonicalize() {
+        Ok(path) if !path.starts_with(&root) => Err(format!(
+            "Project '{}' is outside the project root",
+            project.display()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Open the results file for appending, making sure a line cut short by an
+/// interruption does not swallow the next record.
+fn open_results(path: &Path) -> Result<File, String> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open results file {}: {e}", path.display()))?;
+
+    let len = file
+        .metadata()
+        .map_err(|e| format!("Failed to read results file: {e}"))?
+        .len();
+    if len > 0 {
+        let mut last = [0u8];
+        file.seek(SeekFrom::Start(len - 1))
+            .and_then(|_| file.read_exact(&mut last))
+            .map_err(|e| format!("Failed to read results file: {e}"))?;
+        if last[0] != b'\n' {
+            writeln!(file).map_err(|e| format!("Failed to write results file: {e}"))?;
+        }
+    }
+    Ok(file)
+}
+
+pub(crate) fn open_state(path: Option<&Path>) -> StateDb {
+    let state = match path {
+        Some(path) => StateDb::open(path),
+        None => StateDb::open_default(),
+    };
+    state.unwrap_or_else(|e| {
+        eprintln!("Warning: Could not open state DB ({e}), running without state tracking.");
+        StateDb::open(Path::new(":memory:")).unwrap()
+    })
+}
+
+fn describe(result: &BatchResult) -> String {
+    if let Some(ref error) = result.error {
+        return format!("error: {error}");
+    }
+    if let Some(ref error) = result.output_error {
+        return format!("invalid output: {error}");
+    }
+    let status = if result.success { "ok" } else { "failed" };
+    match (&result.diagnosis, result.provider_index) {
+        (Some(d), Some(i)) => format!("{status} on provider {i} ({})", d.category.as_str()),
+        (None, Some(i)) => format!("{status} on provider {i}"),
+        _ => status.to_string(),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_support::sh_models;
+
+    fn run(dir: &Path, manifest: &Path, retry_failed: bool) -> BatchSummary {
+        let models = sh_models(&[("echo", "echo \"$0\"")]);
+        let opts = BatchOptions {
+            results_path: &dir.join("results.jsonl"),
+            parallelism: 3,
+            retry_failed,
+            vars: &HashMap::new(),
+            models: &models,
+            agents_dir: &dir.join("agents"),
+            diagnostics_model: None,
+            timeout: None,
+            state_path: Some(&dir.join("state.db")),
+        };
+        run_batch(manifest, &opts).unwrap()
+    }
+
+    fn results(dir: &Path) -> Vec<BatchResult> {
+        fs::read_to_string(dir.join("results.jsonl"))
+            .unwrap()
+            .lines()
+            .filter_map(|l| serde_json::from_str(l).ok())
+            .collect()
+    }
+
+    #[test]
+    fn runs_jsonl_manifest_and_resumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("batch.jsonl");
+        fs::write(dir.path().join("p.md"), "from file").unwrap();
+        fs::write(
+            &manifest,
+            r#"{"id": "a", "model": "echo", "prompt": "hello"}
+{"id": "b", "model": "echo", "prompt_file": "p.md"}
+
+{"id": "c", "model": "missing", "prompt": "x"}
+"#,
+        )
+        .unwrap();
+
+        let summary = run(dir.path(), &manifest, false);
+        assert_eq!(
+            summary,
+            BatchSummary {
+                total: 3,
+                skipped: 0,
+                succeeded: 2,
+                failed: 1
+            }
+        );
+
+        let mut records = results(dir.path());
+        records.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(records[0].stdout, "hello\n");
+        assert_eq!(records[0].provider_index, Some(0));
+        assert_eq!(records[0].command.as_deref(), Some("sh"));
+        assert_eq!(records[1].stdout, "from file\n");
+        assert!(!records[2].success);
+        assert!(
+            records[2]
+                .error
+                .as_deref()
+                .unwrap()
+                .contains("Unknown model")
+        );
+
+        // Finished entries are skipped; failed ones only with retry_failed.
+        assert_eq!(run(dir.path(), &manifest, false).skipped, 3);
+        let retried = run(dir.path(), &manifest, true);
+        assert_eq!((retried.skipped, retried.failed), (2, 1));
+    }
+
+    #[test]
+    fn resumes_after_truncated_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("batch.jsonl");
+        fs::write(
+            &manifest,
+            "{\"model\": \"echo\", \"prompt\": \"one\"}\n{\"model\": \"echo\", \"prompt\": \"two\"}\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("results.jsonl"),
+            // Line 1 is in an older format, without per-attempt models and durations.
+            "{\"id\": \"1\", \"success\": true, \"stdout\": \"one\\n\", \"stderr\": \"\", \"duration_ms\": 1, \
+             \"attempts\": [{\"provider_index\": 0, \"command\": \"sh\", \"exit_code\": 0, \"error_category\": null}]}\n\
+             {\"id\": \"2\", \"succ",
+        )
+        .unwrap();
+
+        let summary = run(dir.path(), &manifest, false);
+        assert_eq!((summary.skipped, summary.succeeded), (1, 1));
+        let ids: Vec<String> = results(dir.path()).into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec!["1", "2"]);
+    }
+
+    #[test]
+    fn runs_yaml_manifest_with_agents() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("agents")).unwrap();
+        fs::write(
+            dir.path().join("agents/greeter.md"),
+            "---\nmodel: echo\n---\nSay hi.",
+        )
+        .unwrap();
+        let manifest = dir.path().join("batch.yaml");
+        fs::write(
+            &manifest,
+            "- agent: greeter\n  prompt: Bob\n- agent: nobody\n  prompt: x\n",
+        )
+        .unwrap();
+
+        let summary = run(dir.path(), &manifest, false);
+        assert_eq!((summary.succeeded, summary.failed), (1, 1));
+
+        let records = results(dir.path());
+        let greeter = records.iter().find(|r| r.id == "1").unwrap();
+        assert_eq!(greeter.stdout, "Say hi.\n\nBob\n");
+        assert_eq!(greeter.model.as_deref(), Some("echo"));
+        assert_eq!(greeter.agent.as_deref(), Some("greeter"));
+    }
+
+    #[test]
+    fn rejects_invalid_manifests() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("batch.jsonl");
+
+        fs::write(&manifest, "{\"prompt\": \"no model\"}\n").unwrap();
+        assert!(load_manifest(&manifest).is_err());
+
+        fs::write(&manifest, "{\"model\": \"echo\"}\n").unwrap();
+        assert!(load_manifest(&manifest).is_err());
+
+        fs::write(
+            &manifest,
+            "{\"id\": \"x\", \"model\": \"m\", \"prompt\": \"a\"}\n{\"id\": \"x\", \"model\": \"m\", \"prompt\": \"b\"}\n",
+        )
+        .unwrap();
+        let err = load_manifest(&manifest).unwrap_err();
+        assert!(err.contains("duplicate id 'x'"), "{err}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn confines_remote_entries_to_the_base() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("app")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("link")).unwrap();
+        let entry = |project: &str, context: &[&str]| BatchEntry {
+            project: Some(PathBuf::from(project)),
+            context: context.iter().map(|c| c.to_string()).collect(),
+            ..Default::default()
+        };
+
+        assert!(confine(&entry("app", &["src/*.rs"]), dir.path()).is_ok());
+        assert!(confine(&entry("./app", &[]), dir.path()).is_ok());
+        for (project, context) in [
+            ("/tmp", &[][..]),
+            ("app/../..", &[]),
+            ("link", &[]),
+            ("app", &["../secrets/*"]),
+            ("app", &["/etc/passwd"]),
+        ] {
+            let err = confine(&entry(project, context), dir.path()).unwrap_err();
+            assert!(err.contains(project) || err.contains("Context"), "{err}");
+        }
+    }
+}
@@ -1,25 +1,166 @@
+use super::template::{self, TemplateContext};
 use regex::Regex;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::LazyLock;
 
-#[derive(Debug, Clone)]
-#[allow(dead_code)]
+#[derive(Debug, Clone, Default)]
 pub struct AgentConfig {
     pub name: String,
     pub description: String,
     pub model: String,
-    pub output_format: String,
+    /// Fallbacks tried in order after `model` (see [`AgentConfig::model_chain`]).
+    pub models: Vec<String>,
+    /// Required shape of the provider's stdout. `Some(None)` is an explicit
+    /// free-form setting and `None` an unset one, so a child agent can clear
+    /// a format it inherits. Read it through [`AgentConfig::output_format`].
+    pub output_format: Option<Option<OutputFormat>>,
+    /// Re-prompt once when the output does not match `output_format`. `None`
+    /// when unset; read it through [`AgentConfig::output_retry`].
+    pub output_retry: Option<bool>,
+    /// Defaults for `{{name}}` placeholders, overridden by `--var`.
+    pub vars: HashMap<String, String>,
+    /// Globs (relative to the project) of files attached before the
+    /// instructions, like `--context`.
+    pub context: Vec<String>,
+    /// Models the prompt is sent to at once instead of `model`, like `--fanout`.
+    pub fanout: Vec<String>,
+    /// How fan-out results are combined; `None` means [`FanoutMode::All`].
+    pub fanout_mode: Option<FanoutMode>,
+    /// Agent that picks or merges the outputs in [`FanoutMode::Judge`].
+    pub judge: Option<String>,
+    /// Parent agent whose fields and instructions this one builds on.
+    /// Cleared once resolved by [`resolve_inheritance`].
+    pub extends: Option<String>,
+    /// Agents layered between the parent and this one, in order.
+    pub mixins: Vec<String>,
     pub instructions: String,
 }
 
+/// An agent's `output_format` frontmatter value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// `json`: a single JSON value, possibly wrapped in prose or a fence.
+    Json,
+    /// `json-schema: <path>`: JSON that validates against the schema file.
+    JsonSchema(PathBuf),
+    /// `markdown`: non-empty Markdown.
+    Markdown,
+}
+
+impl OutputFormat {
+    /// Parse a frontmatter value. Empty and `text` mean no format.
+    pub fn parse(value: &str) -> Result<Option<Self>, String> {
+        let value = value.trim();
+        if let Some(path) = value.strip_prefix("json-schema:") {
+            let path = path.trim();
+            if path.is_empty() {
+                return Err("json-schema needs a path, e.g. 'json-schema: review.json'".to_string());
+            }
+            return Ok(Some(OutputFormat::JsonSchema(PathBuf::from(path))));
+        }
+        match value {
+            "" | "text" => Ok(None),
+            "json" => Ok(Some(OutputFormat::Json)),
+            "markdown" => Ok(Some(OutputFormat::Markdown)),
+            other => Err(format!(
+                "unknown output_format '{other}' (expected json, json-schema: <path> or markdown)"
+            )),
+        }
+    }
+}
+
+/// How the results of a fan-out are combined.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FanoutMode {
+    /// Every model's output, labelled by model.
+    #[default]
+    All,
+    /// The first successful output; the other models are stopped.
+    First,
+    /// The judge agent's answer, given every successful output.
+    Judge,
+}
+
+impl FanoutMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FanoutMode::All => "all",
+            FanoutMode::First => "first",
+            FanoutMode::Judge => "judge",
+        }
+    }
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim() {
+            "all" => Ok(FanoutMode::All),
+            "first" => Ok(FanoutMode::First),
+            "judge" => Ok(FanoutMode::Judge),
+            other => Err(format!(
+                "unknown fanout mode '{other}' (expected all, first or judge)"
+            )),
+        }
+    }
+}
+
+impl AgentConfig {
+    /// The format the output must match; `None` passes it through as-is.
+    pub fn output_format(&self) -> Option<&OutputFormat> {
+        self.output_format.as_ref().and_then(Option::as_ref)
+    }
+
+    pub fn output_retry(&self) -> bool {
+        self.output_retry.unwrap_or(false)
+    }
+
+    /// Models to run on, in order: `model`, then the `models` fallbacks.
+    /// Never empty; an unset model yields `""`.
+    pub fn model_chain(&self) -> Vec<&str> {
+        let mut chain = vec![self.model.as_str()];
+        for model in &self.models {
+            if !chain.contains(&model.as_str()) {
+                chain.push(model);
+            }
+        }
+        chain
+    }
+
+    /// The full prompt: the rendered instructions with the user's prompt at
+    /// `{{prompt}}`, or after them when they do not place it.
+    pub fn render(&self, ctx: &TemplateContext) -> Result<String, String> {
+        let instructions = template::render(&self.instructions, ctx, &self.vars)
+            .map_err(|e| format!("Agent {}: {e}", self.name))?;
+        Ok(if template::has_prompt_placeholder(&self.instructions) {
+            instructions
+        } else if instructions.is_empty() {
+            ctx.prompt.clone()
+        } else {
+            format!("{instructions}\n\n{}", ctx.prompt)
+        })
+    }
+}
+
 #[derive(Deserialize)]
 struct RawFrontmatter {
     description: Option<String>,
     model: Option<String>,
+    #[serde(default)]
+    models: Vec<String>,
     output_format: Option<String>,
+    output_retry: Option<bool>,
+    #[serde(default)]
+    vars: HashMap<String, String>,
+    #[serde(default)]
+    context: Vec<String>,
+    #[serde(default)]
+    fanout: Vec<String>,
+    fanout_mode: Option<String>,
+    judge: Option<String>,
+    extends: Option<String>,
+    #[serde(default)]
+    mixins: Vec<String>,
 }
 
 static FRONTMATTER_RE: LazyLock<Regex> =
@@ -39,8 +180,30 @@ pub fn parse_agent_file(name: &str, content: &str) -> Result<AgentConfig, String
     Ok(AgentConfig {
         name: name.to_string(),
         description: raw.description.unwrap_or_default(),
-        model: raw.model.unwrap_or_default(),
-        output_format: raw.output_format.unwrap_or_default(),
+        model: raw
+            .model
+            .or_else(|| raw.models.first().cloned())
+            .unwrap_or_default(),
+        models: raw.models,
+        output_format: raw
+            .output_format
+            .as_deref()
+            .map(OutputFormat::parse)
+            .transpose()
+            .map_err(|e| format!("Agent {name}: {e}"))?,
+        output_retry: raw.output_retry,
+        vars: raw.vars,
+        context: raw.context,
+        fanout: raw.fanout,
+        fanout_mode: raw
+            .fanout_mode
+            .as_deref()
+            .map(FanoutMode::parse)
+            .transpose()
+            .map_err(|e| format!("Agent {name}: {e}"))?,
+        judge: raw.judge,
+        extends: raw.extends,
+        mixins: raw.mixins,
         instructions,
     })
 }
@@ -54,7 +217,17 @@ pub fn load_agent_file(path: &Path) -> Result<AgentConfig, String> {
     let content = fs::read_to_string(path)
         .map_err(|e| format!("Failed to read agent file {}: {e}", path.display()))?;
 
-    parse_agent_file(name, &content)
+    let mut agent = parse_agent_file(name, &content)?;
+    // Includes and schema paths are relative to the agent file.
+    let dir = path.parent().unwrap_or(Path::new("."));
+    agent.instructions = template::expand_includes(&agent.instructions, dir)
+        .map_err(|e| format!("Agent {name}: {e}"))?;
+    if let Some(Some(OutputFormat::JsonSchema(ref mut schema))) = agent.output_format
+        && schema.is_relative()
+    {
+        *schema = dir.join(&*schema);
+    }
+    Ok(agent)
 }
 
 pub fn load_agents(agents_dir: &Path) -> Result<HashMap<String, AgentConfig>, String> {
@@ -79,7 +252,97 @@ pub fn load_agents(agents_dir: &Path) -> Result<HashMap<String, AgentConfig>, St
         agents.insert(agent.name.clone(), agent);
     }
 
-    Ok(agents)
+    agents
+        .values()
+        .map(|agent| Ok((agent.name.clone(), resolve_inheritance(agent, &agents)?)))
+        .collect()
+}
+
+/// Flatten `agent`'s `extends` chain and `mixins`, looking names up in
+/// `agents`.
+///
+/// Layers apply in order parent, mixins (as listed), then the agent itself:
+/// later layers win for `description`, `model`/`models`, `output_format`,
+/// `output_retry`, `fanout`, `fanout_mode` and `judge` (an empty
+/// `output_format` clears an inherited one), `vars` are merged key by key,
+/// and `context` and instructions are concatenated in the same order.
+pub fn resolve_inheritance(
+    agent: &AgentConfig,
+    agents: &HashMap<String, AgentConfig>,
+) -> Result<AgentConfig, String> {
+    resolve(agent, agents, &mut Vec::new())
+}
+
+fn resolve(
+    agent: &AgentConfig,
+    agents: &HashMap<String, AgentConfig>,
+    chain: &mut Vec<String>,
+) -> Result<AgentConfig, String> {
+    if agent.extends.is_none() && agent.mixins.is_empty() {
+        return Ok(agent.clone());
+    }
+    if chain.contains(&agent.name) {
+        chain.push(agent.name.clone());
+        return Err(format!("Agent inheritance cycle: {}", chain.join(" -> ")));
+    }
+    chain.push(agent.name.clone());
+
+    let mut layers = Vec::new();
+    for (relation, name) in agent
+        .extends
+        .iter()
+        .map(|name| ("extends", name))
+        .chain(agent.mixins.iter().map(|name| ("mixes in", name)))
+    {
+        let base = agents
+            .get(name)
+            .ok_or_else(|| format!("Agent {} {relation} unknown agent '{name}'", agent.name))?;
+        layers.push(resolve(base, agents, chain)?);
+    }
+    chain.pop();
+
+    let mut merged = AgentConfig {
+        name: agent.name.clone(),
+        ..Default::default()
+    };
+    let mut parts = Vec::new();
+    for layer in layers.iter().chain(std::iter::once(agent)) {
+        if !layer.description.is_empty() {
+            merged.description = layer.description.clone();
+        }
+        // `model` and `models` are one setting: a layer with either replaces both.
+        if !layer.model.is_empty() {
+            merged.model = layer.model.clone();
+            merged.models = layer.models.clone();
+        }
+        if layer.output_format.is_some() {
+            merged.output_format = layer.output_format.clone();
+        }
+        if !layer.fanout.is_empty() {
+            merged.fanout = layer.fanout.clone();
+        }
+        if layer.fanout_mode.is_some() {
+            merged.fanout_mode = layer.fanout_mode;
+        }
+        if layer.judge.is_some() {
+            merged.judge = layer.judge.clone();
+        }
+        if layer.output_retry.is_some() {
+            merged.output_retry = layer.output_retry;
+        }
+        merged.vars.extend(layer.vars.clone());
+        for pattern in &layer.context {
+            if !merged.context.contains(pattern) {
+                merged.context.push(pattern.clone());
+            }
+        }
+        let text = layer.instructions.trim();
+        if !text.is_empty() {
+            parts.push(text);
+        }
+    }
+    merged.instructions = parts.join("\n\n");
+    Ok(merged)
 }
 
 #[cfg(test)]
@@ -96,6 +359,220 @@ mod tests {
         assert!(agent.instructions.contains("# Instructions"));
     }
 
+    #[test]
+    fn parses_output_format() {
+        let agent =
+            parse_agent_file("t", "---\noutput_format: json\noutput_retry: true\n---\n").unwrap();
+        assert_eq!(agent.output_format(), Some(&OutputFormat::Json));
+        assert!(agent.output_retry());
+
+        let agent =
+            parse_agent_file("t", "---\noutput_format: 'json-schema: out.json'\n---\n").unwrap();
+        assert_eq!(
+            agent.output_format(),
+            Some(&OutputFormat::JsonSchema(PathBuf::from("out.json")))
+        );
+        assert!(!agent.output_retry());
+
+        let err = parse_agent_file("t", "---\noutput_format: xml\n---\n").unwrap_err();
+        assert!(err.contains("unknown output_format 'xml'"), "{err}");
+    }
+
+    #[test]
+    fn parses_fanout() {
+        let agent = parse_agent_file(
+            "t",
+            "---\nfanout: [opus, gpt, gemini]\nfanout_mode: judge\njudge: arbiter\n---\n",
+        )
+        .unwrap();
+        assert_eq!(agent.fanout, vec!["opus", "gpt", "gemini"]);
+        assert_eq!(agent.fanout_mode, Some(FanoutMode::Judge));
+        assert_eq!(agent.judge.as_deref(), Some("arbiter"));
+
+        let err = parse_agent_file("t", "---\nfanout_mode: vote\n---\n").unwrap_err();
+        assert!(err.contains("unknown fanout mode 'vote'"), "{err}");
+    }
+
+    #[test]
+    fn resolves_schema_path_against_agent_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reviewer.md");
+        fs::write(
+            &path,
+            "---\noutput_format: 'json-schema: schemas/review.json'\n---\n",
+        )
+        .unwrap();
+        let agent = load_agent_file(&path).unwrap();
+        assert_eq!(
+            agent.output_format(),
+            Some(&OutputFormat::JsonSchema(
+                dir.path().join("schemas/review.json")
+            ))
+        );
+    }
+
+    #[test]
+    fn renders_instructions_around_prompt() {
+        let ctx = TemplateContext {
+            prompt: "the diff".to_string(),
+            ..Default::default()
+        };
+
+        let agent = parse_agent_file("t", "---\nmodel: m\n---\nReview {{target}}.").unwrap();
+        let err = agent.render(&ctx).unwrap_err();
+        assert!(
+            err.starts_with("Agent t: unknown template variable"),
+            "{err}"
+        );
+
+        let agent = parse_agent_file(
+            "t",
+            "---\nvars:\n  target: code\n---\nReview {{target}}:\n{{prompt}}\nThanks.",
+        )
+        .unwrap();
+        assert_eq!(
+            agent.render(&ctx).unwrap(),
+            "Review code:\nthe diff\nThanks."
+        );
+
+        let agent = parse_agent_file(
+            "t",
+            "---\nmodel: m\n---\nKeep {% raw %}{{ .Name }} {{name}}{% endraw %} and ${{ github.sha }}.",
+        )
+        .unwrap();
+        assert_eq!(
+            agent.render(&ctx).unwrap(),
+            "Keep {{ .Name }} {{name}} and ${{ github.sha }}.\n\nthe diff"
+        );
+
+        let agent = parse_agent_file("t", "---\nmodel: m\n---\nBe brief.").unwrap();
+        assert_eq!(agent.render(&ctx).unwrap(), "Be brief.\n\nthe diff");
+        let agent = parse_agent_file("t", "---\nmodel: m\n---\n").unwrap();
+        assert_eq!(agent.render(&ctx).unwrap(), "the diff");
+    }
+
+    #[test]
+    fn load_expands_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("common.md"), "Shared rules.\n").unwrap();
+        let path = dir.path().join("coder.md");
+        fs::write(
+            &path,
+            "---\nmodel: m\n---\n{% include \"common.md\" %}\nCode well.",
+        )
+        .unwrap();
+        assert_eq!(
+            load_agent_file(&path).unwrap().instructions,
+            "Shared rules.\nCode well."
+        );
+    }
+
+    fn write_agents(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, content) in files {
+            fs::write(dir.path().join(format!("{name}.md")), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn resolves_extends_and_mixins() {
+        let dir = write_agents(&[
+            (
+                "base",
+                "---\nmodel: opus\noutput_format: markdown\nvars:\n  tone: calm\n---\nBase rules.\n",
+            ),
+            (
+                "reviewer",
+                "---\nextends: base\ndescription: Reviewer\n---\nReview code.\n",
+            ),
+            (
+                "strict",
+                "---\nvars:\n  tone: strict\ncontext: [STYLE.md]\n---\nNo nits.\n",
+            ),
+            ("json", "---\noutput_format: json\n---\n"),
+            (
+                "security",
+                "---\nextends: reviewer\nmixins: [strict, json]\nmodel: sonnet\n---\nFocus on auth.\n",
+            ),
+        ]);
+        let agents = load_agents(dir.path()).unwrap();
+
+        let reviewer = &agents["reviewer"];
+        assert_eq!(reviewer.model, "opus");
+        assert_eq!(reviewer.description, "Reviewer");
+        assert_eq!(reviewer.instructions, "Base rules.\n\nReview code.");
+
+        let security = &agents["security"];
+        assert_eq!(security.model, "sonnet");
+        assert_eq!(security.description, "Reviewer");
+        assert_eq!(security.output_format(), Some(&OutputFormat::Json));
+        assert_eq!(security.vars["tone"], "strict");
+        assert_eq!(security.context, vec!["STYLE.md"]);
+        assert_eq!(
+            security.instructions,
+            "Base rules.\n\nReview code.\n\nNo nits.\n\nFocus on auth."
+        );
+        assert!(security.extends.is_none() && security.mixins.is_empty());
+
+        // Resolving an already resolved agent is a no-op.
+        let again = resolve_inheritance(security, &agents).unwrap();
+        assert_eq!(again.instructions, security.instructions);
+    }
+
+    #[test]
+    fn child_layers_override_inherited_output_settings() {
+        let dir = write_agents(&[
+            (
+                "base",
+                "---\noutput_format: json\noutput_retry: true\n---\n",
+            ),
+            (
+                "free",
+                "---\nextends: base\noutput_format: text\noutput_retry: false\n---\n",
+            ),
+            ("kept", "---\nextends: base\n---\n"),
+        ]);
+        let agents = load_agents(dir.path()).unwrap();
+
+        assert_eq!(agents["free"].output_format(), None);
+        assert!(!agents["free"].output_retry());
+        assert_eq!(agents["kept"].output_format(), Some(&OutputFormat::Json));
+        assert!(agents["kept"].output_retry());
+    }
+
+    #[test]
+    fn reports_missing_parents_and_cycles() {
+        let dir = write_agents(&[("a", "---\nextends: ghost\n---\n")]);
+        let err = load_agents(dir.path()).unwrap_err();
+        assert_eq!(err, "Agent a extends unknown agent 'ghost'");
+
+        let dir = write_agents(&[
+            ("a", "---\nextends: b\n---\n"),
+            ("b", "---\nmixins: [a]\n---\n"),
+        ]);
+        let err = load_agents(dir.path()).unwrap_err();
+        assert!(err.starts_with("Agent inheritance cycle: "), "{err}");
+        assert!(
+            err.contains("a -> b -> a") || err.contains("b -> a -> b"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn model_chain_puts_model_first() {
+        let agent = parse_agent_file("t", "---\nmodels: [opus, sonnet]\n---\n").unwrap();
+        assert_eq!(agent.model, "opus");
+        assert_eq!(agent.model_chain(), vec!["opus", "sonnet"]);
+
+        let agent =
+            parse_agent_file("t", "---\nmodel: codex\nmodels: [opus, codex]\n---\n").unwrap();
+        assert_eq!(agent.model_chain(), vec!["codex", "opus"]);
+
+        let agent = parse_agent_file("t", "---\ndescription: x\n---\n").unwrap();
+        assert_eq!(agent.model_chain(), vec![""]);
+    }
+
     #[test]
     fn rejects_no_frontmatter() {
         let content = "# Just markdown\n\nNo frontmatter here.\n";
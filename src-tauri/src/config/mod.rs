@@ -1,5 +1,13 @@
 mod agent;
 pub mod model;
+mod template;
 
-pub use agent::{AgentConfig, load_agent_file, load_agents};
-pub use model::{ModelConfig, PromptMode, ProviderConfig, load_models};
+pub use agent::{
+    AgentConfig, FanoutMode, OutputFormat, load_agent_file, load_agents, resolve_inheritance,
+};
+pub use model::{
+    BalanceStrategy, ModelConfig, PromptMode, ProviderConfig, RetryPolicy, load_models,
+};
+pub use template::{
+    TemplateContext, placeholders as template_placeholders, render as render_template,
+};
@@ -1,26 +1,173 @@
+use crate::diagnostics::ErrorCategory;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::Path;
+use std::time::Duration;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ProviderConfig {
     pub command: String,
     pub args: Vec<String>,
+    /// Seconds before the provider process is killed. Overrides the model timeout.
+    pub timeout: Option<u64>,
+    /// Relative share of traffic under the `weighted` strategy (default 1).
+    pub weight: Option<u32>,
+    /// Most invocations allowed to run on this provider at once (unlimited if unset).
+    pub max_concurrent: Option<u32>,
+    /// Variables set for the provider process. Values may reference
+    /// `{env:NAME}` and `{secret:...}` (see `executor::env::resolve`).
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Variables removed from the inherited environment.
+    #[serde(default)]
+    pub env_remove: Vec<String>,
+    /// Start from an empty environment instead of inheriting the runner's.
+    #[serde(default)]
+    pub env_clear: bool,
+    /// Account (`id` or `provider/id`) whose config directory the CLI runs with.
+    pub account: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ModelConfig {
     pub name: String,
     pub prompt_mode: PromptMode,
+    /// Variable that carries the prompt under `prompt_mode = "env"`
+    /// (`AGENT_PROMPT` if unset).
+    pub prompt_env: Option<String>,
     pub providers: Vec<ProviderConfig>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// Seconds before a provider process is killed, unless the provider sets its own.
+    pub timeout: Option<u64>,
+    #[serde(default)]
+    pub strategy: BalanceStrategy,
+    /// Seconds to wait for a free slot when every provider is at its
+    /// `max_concurrent` limit. Fails straight away when unset.
+    pub wait_for_slot: Option<u64>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum PromptMode {
+    /// Written to the provider's stdin.
+    #[default]
     Stdin,
+    /// Appended as the last argument.
     Arg,
+    /// Written to a file in a private temp directory whose path replaces
+    /// `{prompt_file}` in `args` (or is appended when there is none).
+    File,
+    /// Passed in the environment variable named by `prompt_env`.
+    Env,
+    /// Substituted for `{prompt}` wherever it appears in `args`.
+    ArgTemplate,
+}
+
+/// Replaced by the prompt in `args` under `prompt_mode = "arg_template"`.
+pub const PROMPT_PLACEHOLDER: &str = "{prompt}";
+/// Replaced by the prompt file's path in `args` under `prompt_mode = "file"`.
+pub const PROMPT_FILE_PLACEHOLDER: &str = "{prompt_file}";
+/// Default variable for `prompt_mode = "env"`.
+pub const DEFAULT_PROMPT_ENV: &str = "AGENT_PROMPT";
+
+impl PromptMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PromptMode::Stdin => "stdin",
+            PromptMode::Arg => "arg",
+            PromptMode::File => "file",
+            PromptMode::Env => "env",
+            PromptMode::ArgTemplate => "arg_template",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "stdin" => Some(PromptMode::Stdin),
+            "arg" => Some(PromptMode::Arg),
+            "file" => Some(PromptMode::File),
+            "env" => Some(PromptMode::Env),
+            "arg_template" => Some(PromptMode::ArgTemplate),
+            _ => None,
+        }
+    }
+}
+
+/// How the balancer chooses among a model's available providers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    /// Fewest total invocations, penalizing recent errors.
+    #[default]
+    LeastUsed,
+    /// The next provider in config order after the one used last.
+    RoundRobin,
+    /// Invocations proportional to each provider's `weight`.
+    Weighted,
+    /// The first provider in config order; later ones are fallbacks.
+    Priority,
+    /// The lowest average duration over recent successful invocations.
+    LeastLatency,
+}
+
+impl BalanceStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BalanceStrategy::LeastUsed => "least_used",
+            BalanceStrategy::RoundRobin => "round_robin",
+            BalanceStrategy::Weighted => "weighted",
+            BalanceStrategy::Priority => "priority",
+            BalanceStrategy::LeastLatency => "least_latency",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "least_used" => Some(BalanceStrategy::LeastUsed),
+            "round_robin" => Some(BalanceStrategy::RoundRobin),
+            "weighted" => Some(BalanceStrategy::Weighted),
+            "priority" => Some(BalanceStrategy::Priority),
+            "least_latency" => Some(BalanceStrategy::LeastLatency),
+            _ => None,
+        }
+    }
+}
+
+/// Failover policy: how many providers a single invocation may try, and
+/// which diagnosed failures are worth retrying on the next provider.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    pub max_attempts: usize,
+    /// Serialized as `on`, the key used in the model TOML.
+    #[serde(rename = "on")]
+    pub retry_on: Vec<ErrorCategory>,
+}
+
+const DEFAULT_MAX_ATTEMPTS: usize = 3;
+
+const DEFAULT_RETRY_ON: [ErrorCategory; 4] = [
+    ErrorCategory::RateLimit,
+    ErrorCategory::QuotaExhausted,
+    ErrorCategory::AuthExpired,
+    ErrorCategory::NetworkError,
+];
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            retry_on: DEFAULT_RETRY_ON.to_vec(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether a failure in `category` should be retried on another provider.
+    pub fn should_retry(&self, category: ErrorCategory) -> bool {
+        self.retry_on.contains(&category)
+    }
 }
 
 // Raw TOML structures for deserialization
@@ -30,57 +177,174 @@ struct RawModelToml {
     command: Option<String>,
     args: Option<Vec<String>>,
     prompt_mode: Option<String>,
+    prompt_env: Option<String>,
     providers: Option<Vec<RawProvider>>,
+    retry: Option<RawRetry>,
+    timeout: Option<u64>,
+    strategy: Option<String>,
+    wait_for_slot: Option<u64>,
+    env: Option<BTreeMap<String, String>>,
+    env_remove: Option<Vec<String>>,
+    env_clear: Option<bool>,
+    account: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct RawProvider {
     command: String,
     args: Option<Vec<String>>,
+    timeout: Option<u64>,
+    weight: Option<u32>,
+    max_concurrent: Option<u32>,
+    env: Option<BTreeMap<String, String>>,
+    env_remove: Option<Vec<String>>,
+    env_clear: Option<bool>,
+    account: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawRetry {
+    max_attempts: Option<usize>,
+    on: Option<Vec<String>>,
+}
+
+fn parse_retry(name: &str, raw: Option<RawRetry>) -> Result<RetryPolicy, String> {
+    let defaults = RetryPolicy::default();
+    let Some(raw) = raw else {
+        return Ok(defaults);
+    };
+
+    let retry_on = match raw.on {
+        Some(names) => names
+            .iter()
+            .map(|n| {
+                ErrorCategory::parse(n)
+                    .ok_or_else(|| format!("Model {name}: unknown retry category '{n}'"))
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => defaults.retry_on,
+    };
+
+    Ok(RetryPolicy {
+        max_attempts: raw.max_attempts.unwrap_or(defaults.max_attempts),
+        retry_on,
+    })
 }
 
-fn parse_prompt_mode(s: &str) -> PromptMode {
-    match s {
-        "arg" => PromptMode::Arg,
-        _ => PromptMode::Stdin,
+fn provider_to_toml(p: &ProviderConfig) -> String {
+    let args_toml: Vec<String> = p.args.iter().map(|a| format!("\"{}\"", a)).collect();
+    let mut out = format!(
+        "command = \"{}\"\nargs = [{}]\n",
+        p.command,
+        args_toml.join(", ")
+    );
+    if let Some(timeout) = p.timeout {
+        out.push_str(&format!("timeout = {timeout}\n"));
     }
+    if let Some(weight) = p.weight {
+        out.push_str(&format!("weight = {weight}\n"));
+    }
+    if let Some(max) = p.max_concurrent {
+        out.push_str(&format!("max_concurrent = {max}\n"));
+    }
+    if !p.env.is_empty() {
+        let vars: Vec<String> = p
+            .env
+            .iter()
+            .map(|(k, v)| format!("{k} = \"{v}\""))
+            .collect();
+        out.push_str(&format!("env = {{ {} }}\n", vars.join(", ")));
+    }
+    if !p.env_remove.is_empty() {
+        let names: Vec<String> = p.env_remove.iter().map(|n| format!("\"{n}\"")).collect();
+        out.push_str(&format!("env_remove = [{}]\n", names.join(", ")));
+    }
+    if p.env_clear {
+        out.push_str("env_clear = true\n");
+    }
+    if let Some(ref account) = p.account {
+        out.push_str(&format!("account = \"{account}\"\n"));
+    }
+    out
+}
+
+fn retry_to_toml(retry: &RetryPolicy) -> String {
+    if *retry == RetryPolicy::default() {
+        return String::new();
+    }
+    let on: Vec<String> = retry
+        .retry_on
+        .iter()
+        .map(|c| format!("\"{}\"", c.as_str()))
+        .collect();
+    format!(
+        "\n[retry]\nmax_attempts = {}\non = [{}]\n",
+        retry.max_attempts,
+        on.join(", ")
+    )
 }
 
 impl ModelConfig {
     pub fn to_toml(&self) -> String {
-        let mode_str = match self.prompt_mode {
-            PromptMode::Stdin => "stdin",
-            PromptMode::Arg => "arg",
+        // The compact single-provider form only has room for command, args,
+        // env and account; anything else needs a [[providers]] table.
+        let single = match self.providers.as_slice() {
+            [p] if p.timeout.is_none() && p.weight.is_none() && p.max_concurrent.is_none() => {
+                Some(p)
+            }
+            _ => None,
         };
 
-        if self.providers.len() == 1 {
-            let p = &self.providers[0];
-            let args_toml: Vec<String> = p.args.iter().map(|a| format!("\"{}\"", a)).collect();
-            format!(
-                "command = \"{}\"\nargs = [{}]\nprompt_mode = \"{}\"\n",
-                p.command,
-                args_toml.join(", "),
-                mode_str
-            )
-        } else {
-            let mut out = format!("prompt_mode = \"{}\"\n", mode_str);
+        let mut out = String::new();
+        if let Some(p) = single {
+            out.push_str(&provider_to_toml(p));
+        }
+        out.push_str(&format!(
+            "prompt_mode = \"{}\"\n",
+            self.prompt_mode.as_str()
+        ));
+        if let Some(ref var) = self.prompt_env {
+            out.push_str(&format!("prompt_env = \"{var}\"\n"));
+        }
+        if let Some(timeout) = self.timeout {
+            out.push_str(&format!("timeout = {timeout}\n"));
+        }
+        if self.strategy != BalanceStrategy::default() {
+            out.push_str(&format!("strategy = \"{}\"\n", self.strategy.as_str()));
+        }
+        if let Some(wait) = self.wait_for_slot {
+            out.push_str(&format!("wait_for_slot = {wait}\n"));
+        }
+
+        if single.is_none() {
             for p in &self.providers {
-                let args_toml: Vec<String> = p.args.iter().map(|a| format!("\"{}\"", a)).collect();
-                out.push_str(&format!(
-                    "\n[[providers]]\ncommand = \"{}\"\nargs = [{}]\n",
-                    p.command,
-                    args_toml.join(", ")
-                ));
+                out.push_str("\n[[providers]]\n");
+                out.push_str(&provider_to_toml(p));
             }
-            out
         }
+
+        out.push_str(&retry_to_toml(&self.retry));
+        out
+    }
+
+    /// Effective timeout for a provider: its own, else the model's.
+    pub fn provider_timeout(&self, provider_index: usize) -> Option<Duration> {
+        self.providers
+            .get(provider_index)
+            .and_then(|p| p.timeout)
+            .or(self.timeout)
+            .map(Duration::from_secs)
     }
 
     pub fn from_toml(name: &str, content: &str) -> Result<Self, String> {
         let raw: RawModelToml =
             toml::from_str(content).map_err(|e| format!("TOML parse error for {name}: {e}"))?;
 
-        let prompt_mode = parse_prompt_mode(raw.prompt_mode.as_deref().unwrap_or("stdin"));
+        let prompt_mode = match raw.prompt_mode.as_deref() {
+            Some(s) => PromptMode::parse(s)
+                .ok_or_else(|| format!("Model {name}: unknown prompt_mode '{s}'"))?,
+            None => PromptMode::default(),
+        };
 
         let providers = if let Some(providers) = raw.providers {
             // Multi-provider format: [[providers]]
@@ -89,6 +353,13 @@ impl ModelConfig {
                 .map(|p| ProviderConfig {
                     command: p.command,
                     args: p.args.unwrap_or_default(),
+                    timeout: p.timeout,
+                    weight: p.weight,
+                    max_concurrent: p.max_concurrent,
+                    env: p.env.unwrap_or_default(),
+                    env_remove: p.env_remove.unwrap_or_default(),
+                    env_clear: p.env_clear.unwrap_or_default(),
+                    account: p.account,
                 })
                 .collect()
         } else if let Some(command) = raw.command {
@@ -96,6 +367,11 @@ impl ModelConfig {
             vec![ProviderConfig {
                 command,
                 args: raw.args.unwrap_or_default(),
+                env: raw.env.unwrap_or_default(),
+                env_remove: raw.env_remove.unwrap_or_default(),
+                env_clear: raw.env_clear.unwrap_or_default(),
+                account: raw.account,
+                ..Default::default()
             }]
         } else {
             return Err(format!(
@@ -106,11 +382,34 @@ impl ModelConfig {
         if providers.is_empty() {
             return Err(format!("Model {name}: no providers defined"));
         }
+        if prompt_mode == PromptMode::ArgTemplate
+            && let Some(p) = providers
+                .iter()
+                .find(|p| !p.args.iter().any(|a| a.contains(PROMPT_PLACEHOLDER)))
+        {
+            return Err(format!(
+                "Model {name}: prompt_mode = \"arg_template\" but '{}' has no {PROMPT_PLACEHOLDER} in args",
+                p.command
+            ));
+        }
+
+        let retry = parse_retry(name, raw.retry)?;
+
+        let strategy = match raw.strategy.as_deref() {
+            Some(s) => BalanceStrategy::parse(s)
+                .ok_or_else(|| format!("Model {name}: unknown strategy '{s}'"))?,
+            None => BalanceStrategy::default(),
+        };
 
         Ok(ModelConfig {
             name: name.to_string(),
             prompt_mode,
+            prompt_env: raw.prompt_env,
             providers,
+            retry,
+            timeout: raw.timeout,
+            strategy,
+            wait_for_slot: raw.wait_for_slot,
         })
     }
 }
@@ -242,4 +541,247 @@ args = ["exec", "-m", "gpt-5.3-codex"]
         assert_eq!(config1.providers[1].command, config2.providers[1].command);
         assert_eq!(config1.prompt_mode, config2.prompt_mode);
     }
+
+    #[test]
+    fn retry_defaults_when_absent() {
+        let toml = r#"
+command = "claude"
+"#;
+        let config = ModelConfig::from_toml("test", toml).unwrap();
+        assert_eq!(config.retry, RetryPolicy::default());
+        assert!(config.retry.should_retry(ErrorCategory::RateLimit));
+        assert!(!config.retry.should_retry(ErrorCategory::Unknown));
+    }
+
+    #[test]
+    fn parse_retry_policy() {
+        let toml = r#"
+command = "claude"
+
+[retry]
+max_attempts = 5
+on = ["rate_limit", "network_error"]
+"#;
+        let config = ModelConfig::from_toml("test", toml).unwrap();
+        assert_eq!(config.retry.max_attempts, 5);
+        assert!(config.retry.should_retry(ErrorCategory::NetworkError));
+        assert!(!config.retry.should_retry(ErrorCategory::AuthExpired));
+    }
+
+    #[test]
+    fn rejects_unknown_retry_category() {
+        let toml = r#"
+command = "claude"
+
+[retry]
+on = ["cosmic_rays"]
+"#;
+        assert!(ModelConfig::from_toml("test", toml).is_err());
+    }
+
+    #[test]
+    fn roundtrip_retry_policy() {
+        let original = r#"
+prompt_mode = "stdin"
+
+[[providers]]
+command = "a"
+
+[[providers]]
+command = "b"
+
+[retry]
+max_attempts = 1
+on = ["quota_exhausted"]
+"#;
+        let config1 = ModelConfig::from_toml("test", original).unwrap();
+        let config2 = ModelConfig::from_toml("test", &config1.to_toml()).unwrap();
+        assert_eq!(config1.retry, config2.retry);
+
+        let json = serde_json::to_value(&config1.retry).unwrap();
+        assert_eq!(json["on"], serde_json::json!(["quota_exhausted"]));
+    }
+
+    #[test]
+    fn parse_timeouts() {
+        let toml = r#"
+prompt_mode = "stdin"
+timeout = 600
+
+[[providers]]
+command = "a"
+
+[[providers]]
+command = "b"
+timeout = 30
+"#;
+        let config = ModelConfig::from_toml("test", toml).unwrap();
+        assert_eq!(config.provider_timeout(0), Some(Duration::from_secs(600)));
+        assert_eq!(config.provider_timeout(1), Some(Duration::from_secs(30)));
+
+        let config2 = ModelConfig::from_toml("test", &config.to_toml()).unwrap();
+        assert_eq!(config2.timeout, Some(600));
+        assert_eq!(config2.providers[1].timeout, Some(30));
+    }
+
+    #[test]
+    fn roundtrip_single_provider_with_timeout() {
+        let config1 = ModelConfig {
+            name: "test".to_string(),
+            providers: vec![ProviderConfig {
+                command: "claude".to_string(),
+                args: vec!["-p".to_string()],
+                timeout: Some(45),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let config2 = ModelConfig::from_toml("test", &config1.to_toml()).unwrap();
+        assert_eq!(config2.provider_timeout(0), Some(Duration::from_secs(45)));
+    }
+
+    #[test]
+    fn parse_strategy_and_weights() {
+        let toml = r#"
+prompt_mode = "stdin"
+strategy = "weighted"
+
+[[providers]]
+command = "a"
+weight = 3
+
+[[providers]]
+command = "b"
+"#;
+        let config = ModelConfig::from_toml("test", toml).unwrap();
+        assert_eq!(config.strategy, BalanceStrategy::Weighted);
+        assert_eq!(config.providers[0].weight, Some(3));
+        assert_eq!(config.providers[1].weight, None);
+
+        let config2 = ModelConfig::from_toml("test", &config.to_toml()).unwrap();
+        assert_eq!(config2.strategy, BalanceStrategy::Weighted);
+        assert_eq!(config2.providers[0].weight, Some(3));
+    }
+
+    #[test]
+    fn rejects_unknown_strategy() {
+        let toml = r#"
+command = "a"
+strategy = "vibes"
+"#;
+        assert!(ModelConfig::from_toml("test", toml).is_err());
+    }
+
+    #[test]
+    fn parse_concurrency_limits() {
+        let toml = r#"
+command = "a"
+wait_for_slot = 120
+max_concurrent = 9
+"#;
+        // max_concurrent is per provider; at the top level it is ignored.
+        let config = ModelConfig::from_toml("test", toml).unwrap();
+        assert_eq!(config.wait_for_slot, Some(120));
+        assert_eq!(config.providers[0].max_concurrent, None);
+
+        let toml = r#"
+[[providers]]
+command = "a"
+max_concurrent = 2
+"#;
+        let config = ModelConfig::from_toml("test", toml).unwrap();
+        assert_eq!(config.providers[0].max_concurrent, Some(2));
+        assert_eq!(config.wait_for_slot, None);
+
+        let config2 = ModelConfig::from_toml("test", &config.to_toml()).unwrap();
+        assert_eq!(config2.providers[0].max_concurrent, Some(2));
+    }
+
+    #[test]
+    fn parse_prompt_modes() {
+        let toml = r#"
+command = "gemini"
+args = ["--prompt={prompt}", "--yolo"]
+prompt_mode = "arg_template"
+"#;
+        let config = ModelConfig::from_toml("test", toml).unwrap();
+        assert_eq!(config.prompt_mode, PromptMode::ArgTemplate);
+
+        let toml = r#"
+command = "tool"
+prompt_mode = "env"
+prompt_env = "TOOL_PROMPT"
+"#;
+        let config = ModelConfig::from_toml("test", toml).unwrap();
+        assert_eq!(config.prompt_mode, PromptMode::Env);
+        let config2 = ModelConfig::from_toml("test", &config.to_toml()).unwrap();
+        assert_eq!(config2.prompt_mode, PromptMode::Env);
+        assert_eq!(config2.prompt_env.as_deref(), Some("TOOL_PROMPT"));
+
+        let toml = r#"
+command = "tool"
+args = ["--prompt"]
+prompt_mode = "arg_template"
+"#;
+        let err = ModelConfig::from_toml("test", toml).unwrap_err();
+        assert!(err.contains("no {prompt} in args"), "{err}");
+
+        let toml = r#"
+command = "tool"
+prompt_mode = "carrier_pigeon"
+"#;
+        let err = ModelConfig::from_toml("test", toml).unwrap_err();
+        assert!(err.contains("unknown prompt_mode"), "{err}");
+    }
+
+    #[test]
+    fn parse_provider_env() {
+        let toml = r#"
+command = "claude"
+env = { ANTHROPIC_API_KEY = "{secret:account:work}" }
+env_remove = ["CLAUDECODE"]
+"#;
+        let config = ModelConfig::from_toml("test", toml).unwrap();
+        let p = &config.providers[0];
+        assert_eq!(p.env["ANTHROPIC_API_KEY"], "{secret:account:work}");
+        assert_eq!(p.env_remove, vec!["CLAUDECODE"]);
+        assert!(!p.env_clear);
+
+        let toml = r#"
+[[providers]]
+command = "a"
+env_clear = true
+env = { PATH = "{env:PATH}", MODE = "ci" }
+"#;
+        let config = ModelConfig::from_toml("test", toml).unwrap();
+        let config2 = ModelConfig::from_toml("test", &config.to_toml()).unwrap();
+        assert!(config2.providers[0].env_clear);
+        assert_eq!(config2.providers[0].env, config.providers[0].env);
+    }
+
+    #[test]
+    fn parse_provider_accounts() {
+        let toml = r#"
+strategy = "round_robin"
+
+[[providers]]
+command = "claude"
+account = "work"
+
+[[providers]]
+command = "claude"
+account = "claude/personal"
+"#;
+        let config = ModelConfig::from_toml("test", toml).unwrap();
+        assert_eq!(config.providers[0].account.as_deref(), Some("work"));
+        let config2 = ModelConfig::from_toml("test", &config.to_toml()).unwrap();
+        assert_eq!(
+            config2.providers[1].account.as_deref(),
+            Some("claude/personal")
+        );
+
+        let config =
+            ModelConfig::from_toml("test", "command = \"codex\"\naccount = \"ci\"").unwrap();
+        assert_eq!(config.providers[0].account.as_deref(), Some("ci"));
+    }
 }
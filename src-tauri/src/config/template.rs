@@ -0,0 +1,224 @@
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+/// A `{% raw %}...{% endraw %}` block, a `${{...}}` expression (GitHub
+/// Actions syntax, left alone) or a `{{name}}` placeholder.
+static VAR_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?s)\{%\s*raw\s*%\}(?P<raw>.*?)\{%\s*endraw\s*%\}|(?P<dollar>\$)?\{\{\s*(?P<name>[A-Za-z_][\w.-]*)\s*\}\}",
+    )
+    .unwrap()
+});
+
+static INCLUDE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\{%\s*include\s+"([^"]+)"\s*%\}"#).unwrap());
+
+/// Values for the `{{...}}` placeholders in agent instructions.
+#[derive(Debug, Clone, Default)]
+pub struct TemplateContext {
+    /// Substituted for `{{prompt}}`.
+    pub prompt: String,
+    /// Substituted for `{{project_dir}}`; defaults to the current directory.
+    pub project_dir: Option<PathBuf>,
+    /// `--var key=value` overrides; take precedence over the agent's `vars`.
+    pub vars: HashMap<String, String>,
+}
+
+/// The names of the `{{name}}` placeholders in `text`, outside raw blocks
+/// and `${{...}}` expressions.
+pub fn placeholders(text: &str) -> impl Iterator<Item = &str> {
+    VAR_RE
+        .captures_iter(text)
+        .filter(|caps| caps.name("dollar").is_none())
+        .filter_map(|caps| caps.name("name").map(|m| m.as_str()))
+}
+
+/// Whether `text` places the prompt itself with `{{prompt}}`.
+pub fn has_prompt_placeholder(text: &str) -> bool {
+    placeholders(text).any(|name| name == "prompt")
+}
+
+/// Substitute every placeholder in `text` in a single pass, so values (the
+/// prompt in particular) are never expanded themselves.
+///
+/// Lookup order: `prompt`, `env.NAME`, then `ctx.vars`, `defaults` (the
+/// agent's frontmatter `vars`) and finally `project_dir`. Unknown names and
+/// unset environment variables are errors. The contents of `{% raw %}`
+/// blocks and `${{...}}` expressions are kept verbatim.
+pub fn render(
+    text: &str,
+    ctx: &TemplateContext,
+    defaults: &HashMap<String, String>,
+) -> Result<String, String> {
+    let mut error = None;
+    let rendered = VAR_RE.replace_all(text, |caps: &Captures| {
+        if let Some(raw) = caps.name("raw") {
+            return raw.as_str().to_string();
+        }
+        if caps.name("dollar").is_some() {
+            return caps[0].to_string();
+        }
+        match lookup(&caps["name"], ctx, defaults) {
+            Ok(value) => value,
+            Err(e) => {
+                error.get_or_insert(e);
+                String::new()
+            }
+        }
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(rendered.into_owned()),
+    }
+}
+
+fn lookup(
+    name: &str,
+    ctx: &TemplateContext,
+    defaults: &HashMap<String, String>,
+) -> Result<String, String> {
+    if name == "prompt" {
+        return Ok(ctx.prompt.clone());
+    }
+    if let Some(var) = name.strip_prefix("env.") {
+        return std::env::var(var).map_err(|_| {
+            format!("environment variable {var} is not set (used as {{{{{name}}}}})")
+        });
+    }
+    if let Some(value) = ctx.vars.get(name).or_else(|| defaults.get(name)) {
+        return Ok(value.clone());
+    }
+    if name == "project_dir" {
+        return ctx
+            .project_dir
+            .clone()
+            .or_else(|| std::env::current_dir().ok())
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .ok_or_else(|| "project_dir is unknown".to_string());
+    }
+    Err(format!(
+        "unknown template variable '{name}' (set it with --var {name}=...)"
+    ))
+}
+
+/// Replace every `{% include "path" %}` in `text` with the file's contents,
+/// recursively. Paths are relative to the including file's directory.
+pub fn expand_includes(text: &str, dir: &Path) -> Result<String, String> {
+    expand(text, dir, &mut Vec::new())
+}
+
+fn expand(text: &str, dir: &Path, stack: &mut Vec<PathBuf>) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for caps in INCLUDE_RE.captures_iter(text) {
+        let whole = caps.get(0).unwrap();
+        let path = dir.join(&caps[1]);
+        let canonical = path
+            .canonicalize()
+            .map_err(|e| format!("Failed to include {}: {e}", path.display()))?;
+        if stack.contains(&canonical) {
+            return Err(format!("Include cycle at {}", path.display()));
+        }
+
+        let content = fs::read_to_string(&canonical)
+            .map_err(|e| format!("Failed to include {}: {e}", path.display()))?;
+        stack.push(canonical.clone());
+        let included = expand(
+            content.trim_end_matches('\n'),
+            canonical.parent().unwrap_or(dir),
+            stack,
+        )?;
+        stack.pop();
+
+        out.push_str(&text[last..whole.start()]);
+        out.push_str(&included);
+        last = whole.end();
+    }
+    out.push_str(&text[last..]);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_placeholders() {
+        let ctx = TemplateContext {
+            prompt: "Fix {{bug}}".to_string(),
+            project_dir: Some(PathBuf::from("/repo")),
+            vars: HashMap::from([("lang".to_string(), "Rust".to_string())]),
+        };
+        let defaults = HashMap::from([
+            ("lang".to_string(), "Go".to_string()),
+            ("tone".to_string(), "terse".to_string()),
+        ]);
+
+        let out = render(
+            "Work in {{ project_dir }} on {{lang}}, be {{tone}}.\n\n{{prompt}}",
+            &ctx,
+            &defaults,
+        )
+        .unwrap();
+        // The prompt is inserted verbatim, not rendered.
+        assert_eq!(out, "Work in /repo on Rust, be terse.\n\nFix {{bug}}");
+
+        let err = render("{{nope}}", &ctx, &defaults).unwrap_err();
+        assert!(err.contains("unknown template variable 'nope'"), "{err}");
+        let err = render("{{env.AGENT_RUNNER_SURELY_UNSET}}", &ctx, &defaults).unwrap_err();
+        assert!(
+            err.contains("AGENT_RUNNER_SURELY_UNSET is not set"),
+            "{err}"
+        );
+        assert!(render("{{env.PATH}}", &ctx, &defaults).is_ok());
+    }
+
+    #[test]
+    fn keeps_raw_blocks_and_dollar_expressions() {
+        let ctx = TemplateContext {
+            prompt: "Go".to_string(),
+            ..Default::default()
+        };
+        let text = "Quote {% raw %}{{#each items}}{{name}}{{/each}}{% endraw %} and \
+                    `sha: ${{ github.sha }}`.\n{%raw%}{{prompt}}{%endraw%} {{prompt}}";
+
+        let out = render(text, &ctx, &HashMap::new()).unwrap();
+        assert_eq!(
+            out,
+            "Quote {{#each items}}{{name}}{{/each}} and `sha: ${{ github.sha }}`.\n{{prompt}} Go"
+        );
+        assert!(!has_prompt_placeholder(
+            "{% raw %}{{prompt}}{% endraw %} ${{prompt}}"
+        ));
+    }
+
+    #[test]
+    fn expands_nested_includes_and_detects_cycles() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("shared")).unwrap();
+        fs::write(
+            dir.path().join("shared/style.md"),
+            "Style rules.\n{% include \"tone.md\" %}\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("shared/tone.md"), "Be kind.\n").unwrap();
+
+        let out = expand_includes(
+            "Intro.\n{% include \"shared/style.md\" %}\nEnd.",
+            dir.path(),
+        )
+        .unwrap();
+        assert_eq!(out, "Intro.\nStyle rules.\nBe kind.\nEnd.");
+
+        fs::write(dir.path().join("a.md"), "{% include \"b.md\" %}").unwrap();
+        fs::write(dir.path().join("b.md"), "{% include \"a.md\" %}").unwrap();
+        let err = expand_includes("{% include \"a.md\" %}", dir.path()).unwrap_err();
+        assert!(err.contains("Include cycle"), "{err}");
+
+        let err = expand_includes("{% include \"missing.md\" %}", dir.path()).unwrap_err();
+        assert!(err.contains("missing.md"), "{err}");
+    }
+}
@@ -0,0 +1,273 @@
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Files larger than this are listed but not included.
+pub const MAX_FILE_BYTES: u64 = 256 * 1024;
+/// Once this much content is included, remaining files are listed only.
+pub const MAX_TOTAL_BYTES: u64 = 1024 * 1024;
+/// How much of a file is checked for NUL bytes to detect binaries.
+const BINARY_SNIFF_BYTES: usize = 8 * 1024;
+
+/// One file matched by a context pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextFile {
+    /// Path relative to the base directory, with `/` separators.
+    pub path: String,
+    /// `None` when the file was skipped; see `skipped`.
+    pub content: Option<String>,
+    pub skipped: Option<String>,
+}
+
+/// Expand `patterns` (globs relative to `base`) into files, in pattern order
+/// and sorted within each pattern. A file matched twice is included once.
+/// A pattern that matches no file, or a match that resolves outside `base`
+/// (through `..` or a symlink), is an error.
+pub fn gather(patterns: &[String], base: &Path) -> Result<Vec<ContextFile>, String> {
+    let root = base.canonicalize().map_err(|e| {
+        format!(
+            "Failed to resolve context directory {}: {e}",
+            base.display()
+        )
+    })?;
+    let mut seen: Vec<PathBuf> = Vec::new();
+    let mut files = Vec::new();
+    let mut total: u64 = 0;
+
+    for pattern in patterns {
+        // Only the pattern is a glob; the base may contain `[` or `*`.
+        let full = format!(
+            "{}/{pattern}",
+            glob::Pattern::escape(&base.to_string_lossy())
+        );
+        let paths =
+            glob::glob(&full).map_err(|e| format!("Invalid context pattern '{pattern}': {e}"))?;
+
+        let mut matched = false;
+        for path in paths {
+            let path = path.map_err(|e| format!("Failed to read context files: {e}"))?;
+            if !path.is_file() {
+                continue;
+            }
+            matched = true;
+            let path = path
+                .canonicalize()
+                .map_err(|e| format!("Failed to resolve context file {}: {e}", path.display()))?;
+            if !path.starts_with(&root) {
+                return Err(format!(
+                    "Context pattern '{pattern}' matches {}, outside {}",
+                    path.display(),
+                    root.display()
+                ));
+            }
+            if seen.contains(&path) {
+                continue;
+            }
+            seen.push(path.clone());
+
+            let file = read_file(&path, &root, MAX_TOTAL_BYTES.saturating_sub(total))?;
+            total += file.content.as_ref().map_or(0, |c| c.len() as u64);
+            files.push(file);
+        }
+        if !matched {
+            return Err(format!(
+                "Context pattern '{pattern}' matches no files in {}",
+                base.display()
+            ));
+        }
+    }
+    Ok(files)
+}
+
+/// Read `path`, which must be inside `root`.
+fn read_file(path: &Path, root: &Path, budget: u64) -> Result<ContextFile, String> {
+    let relative = path
+        .strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    let skip = |reason: String| ContextFile {
+        path: relative.clone(),
+        content: None,
+        skipped: Some(reason),
+    };
+
+    let size = fs::metadata(path)
+        .map_err(|e| format!("Failed to read context file {}: {e}", path.display()))?
+        .len();
+    if size > MAX_FILE_BYTES {
+        return Ok(skip(format!(
+            "{} KB exceeds the {} KB per-file limit",
+            size.div_ceil(1024),
+            MAX_FILE_BYTES / 1024
+        )));
+    }
+    if size > budget {
+        return Ok(skip(format!(
+            "context is over the {} KB total limit",
+            MAX_TOTAL_BYTES / 1024
+        )));
+    }
+
+    let mut bytes = Vec::with_capacity(size as usize);
+    fs::File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut bytes))
+        .map_err(|e| format!("Failed to read context file {}: {e}", path.display()))?;
+    if bytes.iter().take(BINARY_SNIFF_BYTES).any(|&b| b == 0) {
+        return Ok(skip("binary file".to_string()));
+    }
+    match String::from_utf8(bytes) {
+        Ok(content) => Ok(ContextFile {
+            path: relative,
+            content: Some(content),
+            skipped: None,
+        }),
+        Err(_) => Ok(skip("binary file".to_string())),
+    }
+}
+
+/// The delimited block placed before the instructions.
+pub fn render(files: &[ContextFile]) -> String {
+    let mut out = String::from("<context>\n");
+    for file in files {
+        match (&file.content, &file.skipped) {
+            (Some(content), _) => {
+                out.push_str(&format!(
+                    "<file path=\"{}\">\n{content}",
+                    escape_attr(&file.path)
+                ));
+                if !content.ends_with('\n') {
+                    out.push('\n');
+                }
+                out.push_str("</file>\n");
+            }
+            (None, reason) => out.push_str(&format!(
+                "<file path=\"{}\" skipped=\"{}\"/>\n",
+                escape_attr(&file.path),
+                escape_attr(reason.as_deref().unwrap_or_default())
+            )),
+        }
+    }
+    out.push_str("</context>");
+    out
+}
+
+/// Escape `value` for use inside a double-quoted attribute.
+pub fn escape_attr(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Prefix `prompt` with the context gathered from `patterns`, reporting
+/// skipped files on stderr. Returns `prompt` unchanged when there are none.
+pub fn attach(prompt: &str, patterns: &[String], base: &Path) -> Result<String, String> {
+    if patterns.is_empty() {
+        return Ok(prompt.to_string());
+    }
+    let files = gather(patterns, base)?;
+    for file in &files {
+        if let Some(ref reason) = file.skipped {
+            eprintln!("[context] skipped {}: {reason}", file.path);
+        }
+    }
+    Ok(format!("{}\n\n{prompt}", render(&files)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gathers_and_renders_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src/nested")).unwrap();
+        fs::write(dir.path().join("src/a.rs"), "fn a() {}\n").unwrap();
+        fs::write(dir.path().join("src/nested/b.rs"), "fn b() {}").unwrap();
+        fs::write(dir.path().join("logo.png"), [0x89, b'P', b'N', b'G', 0, 1]).unwrap();
+        fs::write(
+            dir.path().join("huge.txt"),
+            "x".repeat(MAX_FILE_BYTES as usize + 1),
+        )
+        .unwrap();
+
+        let patterns = vec![
+            "src/**/*.rs".to_string(),
+            "src/a.rs".to_string(),
+            "*.png".to_string(),
+            "huge.txt".to_string(),
+        ];
+        let files = gather(&patterns, dir.path()).unwrap();
+        let paths: Vec<&str> = files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec!["src/a.rs", "src/nested/b.rs", "logo.png", "huge.txt"]
+        );
+        assert_eq!(files[2].skipped.as_deref(), Some("binary file"));
+        assert!(
+            files[3]
+                .skipped
+                .as_deref()
+                .unwrap()
+                .contains("per-file limit")
+        );
+
+        let block = render(&files);
+        assert!(block.starts_with("<context>\n<file path=\"src/a.rs\">\nfn a() {}\n</file>\n"));
+        assert!(block.contains("<file path=\"src/nested/b.rs\">\nfn b() {}\n</file>"));
+        assert!(block.contains("<file path=\"logo.png\" skipped=\"binary file\"/>"));
+        assert!(block.ends_with("</context>"));
+
+        let prompt = attach("Do it.", &patterns[..1], dir.path()).unwrap();
+        assert!(prompt.starts_with("<context>\n"));
+        assert!(prompt.ends_with("</context>\n\nDo it."));
+        assert_eq!(attach("Do it.", &[], dir.path()).unwrap(), "Do it.");
+    }
+
+    #[test]
+    fn enforces_total_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let chunk = "y".repeat(MAX_FILE_BYTES as usize);
+        for i in 0..5 {
+            fs::write(dir.path().join(format!("{i}.txt")), &chunk).unwrap();
+        }
+        let files = gather(&["*.txt".to_string()], dir.path()).unwrap();
+        let included = files.iter().filter(|f| f.content.is_some()).count();
+        assert_eq!(included as u64, MAX_TOTAL_BYTES / MAX_FILE_BYTES);
+        assert!(files[4].skipped.as_deref().unwrap().contains("total limit"));
+    }
+
+    #[test]
+    fn rejects_files_outside_base() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("project");
+        fs::create_dir(&base).unwrap();
+        fs::write(dir.path().join("secret.txt"), "hunter2").unwrap();
+        fs::write(base.join("a \"b\" <c>.txt"), "ok").unwrap();
+
+        let err = gather(&["../secret.txt".to_string()], &base).unwrap_err();
+        assert!(err.contains("outside"), "{err}");
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.path().join("secret.txt"), base.join("link.txt"))
+                .unwrap();
+            let err = gather(&["link.txt".to_string()], &base).unwrap_err();
+            assert!(err.contains("outside"), "{err}");
+        }
+
+        let files = gather(&["a*.txt".to_string()], &base).unwrap();
+        assert!(render(&files).contains("<file path=\"a &quot;b&quot; &lt;c&gt;.txt\">"));
+    }
+
+    #[test]
+    fn unmatched_pattern_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = gather(&["*.md".to_string()], dir.path()).unwrap_err();
+        assert!(err.contains("matches no files"), "{err}");
+    }
+}
@@ -1,23 +1,26 @@
 use crate::config::ModelConfig;
 use crate::executor;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::Path;
 
 const MAX_STDERR_LEN: usize = 2000;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Diagnosis {
     pub category: ErrorCategory,
     pub summary: String,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ErrorCategory {
     RateLimit,
     QuotaExhausted,
     AuthExpired,
     CliVersionMismatch,
     NetworkError,
+    Timeout,
     Unknown,
 }
 
@@ -29,9 +32,23 @@ impl ErrorCategory {
             ErrorCategory::AuthExpired => "auth_expired",
             ErrorCategory::CliVersionMismatch => "cli_version_mismatch",
             ErrorCategory::NetworkError => "network_error",
+            ErrorCategory::Timeout => "timeout",
             ErrorCategory::Unknown => "unknown",
         }
     }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "rate_limit" => Some(ErrorCategory::RateLimit),
+            "quota_exhausted" => Some(ErrorCategory::QuotaExhausted),
+            "auth_expired" => Some(ErrorCategory::AuthExpired),
+            "cli_version_mismatch" => Some(ErrorCategory::CliVersionMismatch),
+            "network_error" => Some(ErrorCategory::NetworkError),
+            "timeout" => Some(ErrorCategory::Timeout),
+            "unknown" => Some(ErrorCategory::Unknown),
+            _ => None,
+        }
+    }
 }
 
 pub fn diagnose_error(
@@ -80,14 +97,7 @@ fn parse_diagnosis(output: &str, stderr: &str, exit_code: i32) -> Result<Diagnos
         return Ok(heuristic_diagnosis(stderr, exit_code));
     }
 
-    let category = match lines[0].trim() {
-        "rate_limit" => ErrorCategory::RateLimit,
-        "quota_exhausted" => ErrorCategory::QuotaExhausted,
-        "auth_expired" => ErrorCategory::AuthExpired,
-        "cli_version_mismatch" => ErrorCategory::CliVersionMismatch,
-        "network_error" => ErrorCategory::NetworkError,
-        _ => ErrorCategory::Unknown,
-    };
+    let category = ErrorCategory::parse(lines[0].trim()).unwrap_or(ErrorCategory::Unknown);
 
     let summary = if lines.len() > 1 {
         lines[1..].join("\n")
@@ -98,7 +108,8 @@ fn parse_diagnosis(output: &str, stderr: &str, exit_code: i32) -> Result<Diagnos
     Ok(Diagnosis { category, summary })
 }
 
-fn heuristic_diagnosis(stderr: &str, _exit_code: i32) -> Diagnosis {
+/// Classify an error from stderr keywords alone, without calling a model.
+pub fn heuristic_diagnosis(stderr: &str, _exit_code: i32) -> Diagnosis {
     let lower = stderr.to_lowercase();
 
     let category = if lower.contains("429")
@@ -0,0 +1,317 @@
+use crate::config::ProviderConfig;
+use crate::state::{AccountRecord, AuthMethod};
+use regex::{Captures, Regex};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::LazyLock;
+
+static REF_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\{(env|secret:env|secret:file|secret:account):([^{}]+)\}").unwrap()
+});
+
+/// Shown in place of a secret in captured stderr.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// The variable each CLI reads its config directory from.
+const CONFIG_DIR_VARS: &[(&str, &str)] =
+    &[("claude", "CLAUDE_CONFIG_DIR"), ("codex", "CODEX_HOME")];
+
+/// A provider's environment with every reference in `env` resolved.
+#[derive(Debug, Default)]
+pub struct ProviderEnv {
+    clear: bool,
+    remove: Vec<String>,
+    vars: Vec<(String, String)>,
+    /// Resolved `{secret:...}` values, redacted from stderr.
+    secrets: Vec<String>,
+}
+
+/// Resolve the provider's environment: its `account`'s config directory
+/// variable, then `env` with these references substituted:
+///
+/// - `{env:NAME}`: the runner's own `NAME` (empty if unset)
+/// - `{secret:env:NAME}`: the runner's `NAME`, which must be set
+/// - `{secret:file:PATH}`: the trimmed contents of `PATH` (`~/` allowed)
+/// - `{secret:account:ID}`: the API key of account `ID` (or `provider/ID`),
+///   read from its key variable or, failing that, its key file
+pub fn resolve(
+    provider: &ProviderConfig,
+    accounts: &[AccountRecord],
+) -> Result<ProviderEnv, String> {
+    let mut env = ProviderEnv {
+        clear: provider.env_clear,
+        remove: provider.env_remove.clone(),
+        ..Default::default()
+    };
+    if let Some(ref reference) = provider.account {
+        let account = find_account(reference, accounts)
+            .map_err(|e| format!("Provider '{}': {e}", provider.command))?;
+        let var = config_dir_var(&account.provider).ok_or_else(|| {
+            format!(
+                "Provider '{}': accounts of '{}' cannot be selected per provider",
+                provider.command, account.provider
+            )
+        })?;
+        let dir = account_config_dir(account)?;
+        env.vars
+            .push((var.to_string(), dir.to_string_lossy().into_owned()));
+    }
+    for (key, template) in &provider.env {
+        let mut error = None;
+        let value = REF_RE.replace_all(template, |caps: &Captures| {
+            let value = match &caps[1] {
+                "env" => Ok(std::env::var(&caps[2]).unwrap_or_default()),
+                "secret:env" => {
+                    std::env::var(&caps[2]).map_err(|_| format!("{} is not set", &caps[2]))
+                }
+                "secret:file" => read_secret_file(&caps[2]),
+                _ => account_secret(&caps[2], accounts),
+            };
+            match value {
+                Ok(value) => {
+                    if caps[1].starts_with("secret:") && !value.is_empty() {
+                        env.secrets.push(value.clone());
+                    }
+                    value
+                }
+                Err(e) => {
+                    error.get_or_insert(e);
+                    String::new()
+                }
+            }
+        });
+        if let Some(e) = error {
+            return Err(format!(
+                "Failed to resolve env {key} for '{}': {e}",
+                provider.command
+            ));
+        }
+        env.vars.push((key.clone(), value.into_owned()));
+    }
+    // Longest first, so a secret containing another is replaced whole.
+    env.secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+    env.secrets.dedup();
+    Ok(env)
+}
+
+impl ProviderEnv {
+    /// Apply to `cmd`: clear, then remove, then set.
+    pub fn apply(&self, cmd: &mut Command) {
+        if self.clear {
+            cmd.env_clear();
+        }
+        for key in &self.remove {
+            cmd.env_remove(key);
+        }
+        for (key, value) in &self.vars {
+            cmd.env(key, value);
+        }
+    }
+
+    /// `text` with every resolved secret replaced by [`REDACTED`].
+    pub fn redact(&self, text: &str) -> String {
+        let mut out = text.to_string();
+        for secret in &self.secrets {
+            if out.contains(secret.as_str()) {
+                out = out.replace(secret.as_str(), REDACTED);
+            }
+        }
+        out
+    }
+}
+
+fn expand_home(path: &str) -> Result<PathBuf, String> {
+    match path.strip_prefix("~/") {
+        Some(rest) => Ok(dirs::home_dir()
+            .ok_or_else(|| "Could not determine home directory".to_string())?
+            .join(rest)),
+        None => Ok(PathBuf::from(path)),
+    }
+}
+
+fn read_secret_file(path: &str) -> Result<String, String> {
+    let path = expand_home(path)?;
+    std::fs::read_to_string(&path)
+        .map(|s| s.trim().to_string())
+        .map_err(|e| format!("Failed to read {}: {e}", path.display()))
+}
+
+/// The variable `cli` reads its config directory from, if it has one.
+pub fn config_dir_var(cli: &str) -> Option<&'static str> {
+    CONFIG_DIR_VARS
+        .iter()
+        .find(|(name, _)| *name == cli)
+        .map(|(_, var)| *var)
+}
+
+/// The config directory for `account`, created if missing: its own
+/// `config_dir`, else `<data dir>/oulipoly-agent-runner/accounts/<provider>/<id>`.
+pub fn account_config_dir(account: &AccountRecord) -> Result<PathBuf, String> {
+    let dir = match account.config_dir {
+        Some(ref dir) => expand_home(dir)?,
+        None => dirs::data_dir()
+            .ok_or_else(|| "Could not determine data directory".to_string())?
+            .join("oulipoly-agent-runner")
+            .join("accounts")
+            .join(&account.provider)
+            .join(&account.id),
+    };
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+    Ok(dir)
+}
+
+/// Look up `reference` as an account `id` or `provider/id`.
+fn find_account<'a>(
+    reference: &str,
+    accounts: &'a [AccountRecord],
+) -> Result<&'a AccountRecord, String> {
+    let matches: Vec<&AccountRecord> = accounts
+        .iter()
+        .filter(|a| a.id == reference || format!("{}/{}", a.provider, a.id) == reference)
+        .collect();
+    match matches.as_slice() {
+        [account] => Ok(account),
+        [] => Err(format!("unknown account '{reference}'")),
+        _ => Err(format!(
+            "account '{reference}' is ambiguous; use provider/{reference}"
+        )),
+    }
+}
+
+fn account_secret(reference: &str, accounts: &[AccountRecord]) -> Result<String, String> {
+    let account = find_account(reference, accounts)?;
+    let AuthMethod::ApiKey {
+        env_var,
+        config_path,
+    } = &account.auth_method
+    else {
+        return Err(format!("account '{reference}' does not use an API key"));
+    };
+
+    if let Ok(value) = std::env::var(env_var)
+        && !value.is_empty()
+    {
+        return Ok(value);
+    }
+    match config_path {
+        Some(path) => read_secret_file(path),
+        None => Err(format!("{env_var} is not set for account '{reference}'")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::AuthStatus;
+
+    fn account(id: &str, provider: &str, auth_method: AuthMethod) -> AccountRecord {
+        AccountRecord {
+            id: id.to_string(),
+            provider: provider.to_string(),
+            profile_name: id.to_string(),
+            auth_method,
+            auth_status: AuthStatus::Valid,
+            created_at: String::new(),
+            config_dir: None,
+        }
+    }
+
+    #[test]
+    fn resolves_references_and_redacts_secrets() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("key");
+        std::fs::write(&key_file, "sk-from-file\n").unwrap();
+
+        let accounts = vec![
+            account(
+                "work",
+                "claude",
+                AuthMethod::ApiKey {
+                    env_var: "AGENT_RUNNER_TEST_UNSET_KEY".to_string(),
+                    config_path: Some(key_file.to_string_lossy().into_owned()),
+                },
+            ),
+            account("personal", "claude", AuthMethod::OAuth),
+        ];
+        let provider = ProviderConfig {
+            command: "claude".to_string(),
+            env: [
+                ("API_KEY", "{secret:account:claude/work}"),
+                ("AUTH", "Bearer {secret:file:KEY_FILE}"),
+                ("HOME_DIR", "{env:HOME}"),
+            ]
+            .into_iter()
+            .map(|(k, v)| {
+                (
+                    k.to_string(),
+                    v.replace("KEY_FILE", &key_file.to_string_lossy()),
+                )
+            })
+            .collect(),
+            ..Default::default()
+        };
+
+        let env = resolve(&provider, &accounts).unwrap();
+        assert!(
+            env.vars
+                .contains(&("API_KEY".to_string(), "sk-from-file".to_string()))
+        );
+        assert!(
+            env.vars
+                .contains(&("AUTH".to_string(), "Bearer sk-from-file".to_string()))
+        );
+        assert_eq!(env.secrets, vec!["sk-from-file"]);
+        assert_eq!(
+            env.redact("401: bad key sk-from-file\n"),
+            "401: bad key [REDACTED]\n"
+        );
+
+        let mut bad = provider.clone();
+        bad.env = [("K".to_string(), "{secret:account:personal}".to_string())].into();
+        let err = resolve(&bad, &accounts).unwrap_err();
+        assert!(err.contains("does not use an API key"), "{err}");
+        bad.env = [("K".to_string(), "{secret:account:ghost}".to_string())].into();
+        let err = resolve(&bad, &accounts).unwrap_err();
+        assert!(err.contains("unknown account 'ghost'"), "{err}");
+    }
+
+    #[test]
+    fn binds_provider_to_account_config_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_dir = dir.path().join("claude-work");
+        let mut work = account("work", "claude", AuthMethod::OAuth);
+        work.config_dir = Some(config_dir.to_string_lossy().into_owned());
+        let accounts = vec![
+            work,
+            account("work", "codex", AuthMethod::OAuth),
+            account("bot", "gemini", AuthMethod::OAuth),
+        ];
+
+        let mut provider = ProviderConfig {
+            command: "claude".to_string(),
+            account: Some("claude/work".to_string()),
+            env: [("CLAUDE_CONFIG_DIR".to_string(), "/override".to_string())].into(),
+            ..Default::default()
+        };
+        let env = resolve(&provider, &accounts).unwrap();
+        // The account sets the directory (creating it); explicit env wins.
+        assert_eq!(
+            env.vars[0],
+            (
+                "CLAUDE_CONFIG_DIR".to_string(),
+                config_dir.to_string_lossy().into_owned()
+            )
+        );
+        assert_eq!(env.vars[1].1, "/override");
+        assert!(config_dir.is_dir());
+
+        provider.env.clear();
+        provider.account = Some("work".to_string());
+        let err = resolve(&provider, &accounts).unwrap_err();
+        assert!(err.contains("ambiguous"), "{err}");
+        provider.account = Some("bot".to_string());
+        let err = resolve(&provider, &accounts).unwrap_err();
+        assert!(err.contains("cannot be selected per provider"), "{err}");
+    }
+}
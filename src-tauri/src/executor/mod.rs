@@ -1,9 +1,41 @@
+use crate::config::model::{DEFAULT_PROMPT_ENV, PROMPT_FILE_PLACEHOLDER, PROMPT_PLACEHOLDER};
 use crate::config::{ModelConfig, PromptMode, ProviderConfig};
-use std::io::Write;
+use crate::state::AccountRecord;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub mod env;
 
 const LARGE_PROMPT_THRESHOLD: usize = 100 * 1024; // 100KB
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Bytes of each stream kept in the result when output is being streamed.
+const STREAM_TAIL_BYTES: usize = 64 * 1024;
+
+/// Exit code reported for timed-out invocations, matching coreutils `timeout`.
+pub const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// How often [`ExecOptions::heartbeat`] is called while a provider runs.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Exit code reported for cancelled invocations, as for a job stopped with Ctrl-C.
+pub const CANCELLED_EXIT_CODE: i32 = 130;
+
+/// How a provider process ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionStatus {
+    /// The process exited on its own; see `exit_code`.
+    Completed,
+    /// The process exceeded its timeout and was killed.
+    TimedOut,
+    /// [`ExecOptions::cancel`] was set and the process was killed.
+    Cancelled,
+}
 
 #[allow(dead_code)]
 pub struct ExecutionResult {
@@ -11,6 +43,50 @@ pub struct ExecutionResult {
     pub stderr: String,
     pub exit_code: i32,
     pub provider_index: usize,
+    pub status: ExecutionStatus,
+    pub duration: Duration,
+    /// Total bytes the provider wrote to stdout, even when `stdout` only
+    /// holds the tail of a streamed run.
+    pub stdout_bytes: u64,
+}
+
+impl ExecutionResult {
+    pub fn timed_out(&self) -> bool {
+        self.status == ExecutionStatus::TimedOut
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.status == ExecutionStatus::Cancelled
+    }
+}
+
+/// Which provider pipe a streamed line came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Receives provider output line by line (newline included) as it is produced.
+pub type OutputSink<'a> = &'a (dyn Fn(OutputStream, &str) + Sync);
+
+/// Per-invocation overrides for [`execute_with`].
+#[derive(Clone, Copy, Default)]
+pub struct ExecOptions<'a> {
+    /// Overrides the provider/model timeout when set.
+    pub timeout: Option<Duration>,
+    /// Streams output as it arrives. The result then only keeps the last
+    /// `STREAM_TAIL_BYTES` of each stream.
+    pub on_output: Option<OutputSink<'a>>,
+    /// Accounts that `{secret:account:...}` references in provider `env`
+    /// resolve against.
+    pub accounts: &'a [AccountRecord],
+    /// Kill the provider as soon as this is set.
+    pub cancel: Option<&'a AtomicBool>,
+    /// Called every [`HEARTBEAT_INTERVAL`] while the provider runs, e.g. to
+    /// keep its concurrency lease alive.
+    pub heartbeat: Option<&'a dyn Fn()>,
 }
 
 pub fn execute(
@@ -18,6 +94,22 @@ pub fn execute(
     provider_index: usize,
     prompt: &str,
     working_dir: Option<&Path>,
+) -> Result<ExecutionResult, String> {
+    execute_with(
+        model,
+        provider_index,
+        prompt,
+        working_dir,
+        &ExecOptions::default(),
+    )
+}
+
+pub fn execute_with(
+    model: &ModelConfig,
+    provider_index: usize,
+    prompt: &str,
+    working_dir: Option<&Path>,
+    opts: &ExecOptions,
 ) -> Result<ExecutionResult, String> {
     let provider = model.providers.get(provider_index).ok_or_else(|| {
         format!(
@@ -26,17 +118,20 @@ pub fn execute(
         )
     })?;
 
-    let (result, temp_file) = execute_provider(provider, model.prompt_mode, prompt, working_dir)?;
-    // Clean up temp file if one was created
-    if let Some(path) = temp_file {
-        let _ = std::fs::remove_file(path);
-    }
+    let timeout = opts
+        .timeout
+        .or_else(|| model.provider_timeout(provider_index));
+
+    let result = execute_provider(model, provider, prompt, working_dir, timeout, opts)?;
 
     Ok(ExecutionResult {
         stdout: result.stdout,
         stderr: result.stderr,
         exit_code: result.exit_code,
         provider_index,
+        status: result.status,
+        duration: result.duration,
+        stdout_bytes: result.stdout_bytes,
     })
 }
 
@@ -44,14 +139,20 @@ struct RawResult {
     stdout: String,
     stderr: String,
     exit_code: i32,
+    status: ExecutionStatus,
+    duration: Duration,
+    stdout_bytes: u64,
 }
 
 fn execute_provider(
+    model: &ModelConfig,
     provider: &ProviderConfig,
-    prompt_mode: PromptMode,
     prompt: &str,
     working_dir: Option<&Path>,
-) -> Result<(RawResult, Option<PathBuf>), String> {
+    timeout: Option<Duration>,
+    opts: &ExecOptions,
+) -> Result<RawResult, String> {
+    let env = env::resolve(provider, opts.accounts)?;
     let parts = shell_split(&provider.command);
     if parts.is_empty() {
         return Err("Empty command".to_string());
@@ -61,64 +162,284 @@ fn execute_provider(
     for part in &parts[1..] {
         cmd.arg(part);
     }
-    for arg in &provider.args {
-        cmd.arg(arg);
+
+    let prompt_mode = model.prompt_mode;
+    // Holds the prompt file's private directory until the provider exits.
+    let mut prompt_dir = None;
+    let large = prompt.len() > LARGE_PROMPT_THRESHOLD;
+    if large && prompt_mode == PromptMode::Env {
+        return Err(format!(
+            "Prompt is {} KB, too large for an environment variable; \
+             use prompt_mode = \"file\" or \"stdin\"",
+            prompt.len() / 1024
+        ));
+    }
+    // A single argument is capped at 128 KB on Linux, so a large prompt is
+    // handed over as a private file instead.
+    let in_args = matches!(prompt_mode, PromptMode::Arg | PromptMode::ArgTemplate);
+    let arg_prompt = if large && in_args {
+        let (dir, path) = write_prompt_file(prompt)?;
+        prompt_dir = Some(dir);
+        Cow::Owned(format!("Follow the instructions in {}", path.display()))
+    } else {
+        Cow::Borrowed(prompt)
+    };
+    match prompt_mode {
+        PromptMode::ArgTemplate => {
+            for arg in &provider.args {
+                cmd.arg(arg.replace(PROMPT_PLACEHOLDER, &arg_prompt));
+            }
+        }
+        PromptMode::File => {
+            let (dir, path) = write_prompt_file(prompt)?;
+            let path = path.to_string_lossy().into_owned();
+            for arg in &provider.args {
+                cmd.arg(arg.replace(PROMPT_FILE_PLACEHOLDER, &path));
+            }
+            if !provider
+                .args
+                .iter()
+                .any(|a| a.contains(PROMPT_FILE_PLACEHOLDER))
+            {
+                cmd.arg(&path);
+            }
+            prompt_dir = Some(dir);
+        }
+        _ => {
+            for arg in &provider.args {
+                cmd.arg(arg);
+            }
+        }
     }
 
     if let Some(dir) = working_dir {
         cmd.current_dir(dir);
     }
-
-    let mut temp_path = None;
+    env.apply(&mut cmd);
 
     match prompt_mode {
         PromptMode::Arg => {
-            if prompt.len() > LARGE_PROMPT_THRESHOLD {
-                // Write to temp file, pass reference
-                let dir = working_dir.unwrap_or(Path::new("."));
-                let filename = format!("_agent_prompt_{}.md", uuid::Uuid::new_v4());
-                let path = dir.join(&filename);
-                std::fs::write(&path, prompt)
-                    .map_err(|e| format!("Failed to write temp prompt file: {e}"))?;
-                cmd.arg(format!("Follow the instructions in {filename}"));
-                temp_path = Some(path);
-            } else {
-                cmd.arg(prompt);
-            }
-            cmd.stdin(Stdio::null());
+            cmd.arg(arg_prompt.as_ref());
         }
-        PromptMode::Stdin => {
-            cmd.stdin(Stdio::piped());
+        PromptMode::Env => {
+            let var = model.prompt_env.as_deref().unwrap_or(DEFAULT_PROMPT_ENV);
+            cmd.env(var, prompt);
         }
+        PromptMode::Stdin | PromptMode::File | PromptMode::ArgTemplate => {}
     }
+    cmd.stdin(if prompt_mode == PromptMode::Stdin {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    });
 
     cmd.stdout(Stdio::piped());
     cmd.stderr(Stdio::piped());
 
+    // Run the provider in its own process group so a timeout can take down
+    // everything it spawned, not just the direct child.
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+
+    let start = Instant::now();
     let mut child = cmd
         .spawn()
         .map_err(|e| format!("Failed to spawn '{}': {e}", provider.command))?;
 
-    if prompt_mode == PromptMode::Stdin
-        && let Some(mut stdin) = child.stdin.take()
-    {
-        stdin
-            .write_all(prompt.as_bytes())
-            .map_err(|e| format!("Failed to write to stdin: {e}"))?;
-        // stdin is dropped here, closing the pipe
-    }
+    // stdin, stdout and stderr are all serviced on their own threads so a
+    // provider that stops reading or floods one pipe cannot block the deadline.
+    // Secrets never reach the sink or the captured stderr.
+    let sink = opts.on_output;
+    let redacting = |stream: OutputStream, line: &str| {
+        if let Some(sink) = sink {
+            match stream {
+                OutputStream::Stdout => sink(stream, line),
+                OutputStream::Stderr => sink(stream, &env.redact(line)),
+            }
+        }
+    };
+    let on_output: Option<OutputSink> = sink.map(|_| &redacting as OutputSink);
+    let tail_limit = on_output.map(|_| STREAM_TAIL_BYTES);
+    let stdin = if prompt_mode == PromptMode::Stdin {
+        child.stdin.take()
+    } else {
+        None
+    };
+    let stdout_pipe = child.stdout.take();
+    let stderr_pipe = child.stderr.take();
+
+    let (wait, stdout, stderr) = thread::scope(|scope| {
+        if let Some(mut stdin) = stdin {
+            scope.spawn(move || {
+                // A provider that exits early closes the pipe; that is
+                // reported through its exit status, not as a write error.
+                let _ = stdin.write_all(prompt.as_bytes());
+                // stdin is dropped here, closing the pipe
+            });
+        }
+        let stdout_reader = stdout_pipe.map(|pipe| {
+            scope.spawn(move || read_stream(pipe, OutputStream::Stdout, on_output, tail_limit))
+        });
+        let stderr_reader = stderr_pipe.map(|pipe| {
+            scope.spawn(move || read_stream(pipe, OutputStream::Stderr, on_output, tail_limit))
+        });
 
-    let output = child
-        .wait_with_output()
-        .map_err(|e| format!("Failed to wait for process: {e}"))?;
+        let wait = wait_with_deadline(&mut child, timeout, opts.cancel, opts.heartbeat);
+        let stdout = stdout_reader
+            .and_then(|h| h.join().ok())
+            .unwrap_or_default();
+        let (stderr, _) = stderr_reader
+            .and_then(|h| h.join().ok())
+            .unwrap_or_default();
+        (wait, stdout, stderr)
+    });
+    let (stdout, stdout_bytes) = stdout;
+    let (status, exit_code) = wait?;
+    let mut stderr = env.redact(&stderr);
+
+    if status == ExecutionStatus::TimedOut {
+        let secs = timeout.map(|t| t.as_secs_f64()).unwrap_or_default();
+        if !stderr.is_empty() && !stderr.ends_with('\n') {
+            stderr.push('\n');
+        }
+        stderr.push_str(&format!(
+            "[agent-runner] '{}' timed out after {secs}s and was killed",
+            provider.command
+        ));
+    }
 
     let result = RawResult {
-        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
-        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
-        exit_code: output.status.code().unwrap_or(-1),
+        stdout,
+        stderr,
+        exit_code,
+        status,
+        duration: start.elapsed(),
+        stdout_bytes,
     };
 
-    Ok((result, temp_path))
+    drop(prompt_dir);
+    Ok(result)
+}
+
+/// Write `prompt` to `prompt.md` in a fresh temp directory that only the
+/// current user can read.
+fn write_prompt_file(prompt: &str) -> Result<(tempfile::TempDir, PathBuf), String> {
+    let dir = tempfile::Builder::new()
+        .prefix("agent-runner-")
+        .tempdir()
+        .map_err(|e| format!("Failed to create prompt directory: {e}"))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o700))
+            .map_err(|e| format!("Failed to secure prompt directory: {e}"))?;
+    }
+    let path = dir.path().join("prompt.md");
+    std::fs::write(&path, prompt).map_err(|e| format!("Failed to write prompt file: {e}"))?;
+    Ok((dir, path))
+}
+
+/// Drain a provider pipe, forwarding each line to `sink` when streaming.
+///
+/// Without a `tail_limit` the whole stream is returned; with one, only its
+/// last `tail_limit` bytes are kept. Also returns the total bytes read.
+fn read_stream<R: Read>(
+    pipe: R,
+    stream: OutputStream,
+    sink: Option<OutputSink>,
+    tail_limit: Option<usize>,
+) -> (String, u64) {
+    let mut reader = BufReader::new(pipe);
+    let mut captured: Vec<u8> = Vec::new();
+    let mut line: Vec<u8> = Vec::new();
+    let mut total: u64 = 0;
+
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        if let Some(sink) = sink {
+            sink(stream, &String::from_utf8_lossy(&line));
+        }
+        total += line.len() as u64;
+        captured.extend_from_slice(&line);
+        if let Some(limit) = tail_limit
+            && captured.len() > limit
+        {
+            captured.drain(..captured.len() - limit);
+        }
+    }
+
+    (String::from_utf8_lossy(&captured).into_owned(), total)
+}
+
+/// Wait for `child` to exit, killing its process group once `timeout`
+/// elapses or `cancel` is set, and calling `heartbeat` along the way.
+fn wait_with_deadline(
+    child: &mut Child,
+    timeout: Option<Duration>,
+    cancel: Option<&AtomicBool>,
+    heartbeat: Option<&dyn Fn()>,
+) -> Result<(ExecutionStatus, i32), String> {
+    if timeout.is_none() && cancel.is_none() && heartbeat.is_none() {
+        let status = child
+            .wait()
+            .map_err(|e| format!("Failed to wait for process: {e}"))?;
+        return Ok((ExecutionStatus::Completed, status.code().unwrap_or(-1)));
+    }
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut next_beat = Instant::now() + HEARTBEAT_INTERVAL;
+    loop {
+        if let Some(heartbeat) = heartbeat
+            && Instant::now() >= next_beat
+        {
+            heartbeat();
+            next_beat = Instant::now() + HEARTBEAT_INTERVAL;
+        }
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                return Ok((ExecutionStatus::Completed, status.code().unwrap_or(-1)));
+            }
+            Ok(None) if cancel.is_some_and(|c| c.load(Ordering::SeqCst)) => {
+                kill_process_group(child);
+                let _ = child.wait();
+                return Ok((ExecutionStatus::Cancelled, CANCELLED_EXIT_CODE));
+            }
+            Ok(None) if deadline.is_some_and(|d| Instant::now() >= d) => {
+                kill_process_group(child);
+                let _ = child.wait();
+                return Ok((ExecutionStatus::TimedOut, TIMEOUT_EXIT_CODE));
+            }
+            Ok(None) => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                kill_process_group(child);
+                return Err(format!("Failed to wait for process: {e}"));
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn kill_process_group(child: &mut Child) {
+    // The child leads its own process group (see `execute_provider`), so
+    // signalling the negated pid reaches every process it spawned.
+    if let Ok(pid) = i32::try_from(child.id()) {
+        unsafe {
+            libc::kill(-pid, libc::SIGKILL);
+        }
+    }
+    let _ = child.kill();
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(child: &mut Child) {
+    let _ = child.kill();
 }
 
 fn shell_split(s: &str) -> Vec<String> {
@@ -208,7 +529,9 @@ mod tests {
             providers: vec![ProviderConfig {
                 command: "echo".to_string(),
                 args: vec![],
+                ..Default::default()
             }],
+            ..Default::default()
         };
         let result = execute(&model, 0, "hello world", None).unwrap();
         assert_eq!(result.exit_code, 0);
@@ -224,10 +547,283 @@ mod tests {
             providers: vec![ProviderConfig {
                 command: "cat".to_string(),
                 args: vec![],
+                ..Default::default()
             }],
+            ..Default::default()
         };
         let result = execute(&model, 0, "piped input", None).unwrap();
         assert_eq!(result.exit_code, 0);
         assert_eq!(result.stdout, "piped input");
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn execute_file_env_and_template_modes() {
+        let sh = |mode: PromptMode, script: &str, extra: &[&str]| ModelConfig {
+            name: "test".to_string(),
+            prompt_mode: mode,
+            providers: vec![ProviderConfig {
+                command: "sh".to_string(),
+                args: ["-c", script, "sh"]
+                    .iter()
+                    .chain(extra)
+                    .map(|s| s.to_string())
+                    .collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        // The file lives outside the project and is gone once the run ends.
+        let project = tempfile::tempdir().unwrap();
+        let model = sh(
+            PromptMode::File,
+            r#"cat "$1"; echo; echo "$1""#,
+            &["{prompt_file}"],
+        );
+        let result = execute(&model, 0, "from a file", Some(project.path())).unwrap();
+        let mut lines = result.stdout.lines();
+        assert_eq!(lines.next(), Some("from a file"));
+        let path = PathBuf::from(lines.next().unwrap());
+        assert!(!path.starts_with(project.path()));
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_dir(project.path()).unwrap().count(), 0);
+
+        // Without a placeholder the path is appended.
+        let model = sh(PromptMode::File, r#"cat "$1""#, &[]);
+        assert_eq!(
+            execute(&model, 0, "appended", None).unwrap().stdout,
+            "appended"
+        );
+
+        let mut model = sh(PromptMode::Env, r#"printf %s "$AGENT_PROMPT""#, &[]);
+        assert_eq!(
+            execute(&model, 0, "via env", None).unwrap().stdout,
+            "via env"
+        );
+        model.prompt_env = Some("MY_PROMPT".to_string());
+        model.providers[0].args[1] = r#"printf %s "$MY_PROMPT""#.to_string();
+        assert_eq!(
+            execute(&model, 0, "renamed", None).unwrap().stdout,
+            "renamed"
+        );
+
+        let model = sh(
+            PromptMode::ArgTemplate,
+            r#"printf '%s|%s' "$1" "$2""#,
+            &["--prompt={prompt}", "last"],
+        );
+        assert_eq!(
+            execute(&model, 0, "hi {prompt_file}", None).unwrap().stdout,
+            "--prompt=hi {prompt_file}|last"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn large_prompts_go_through_a_private_file() {
+        let sh = |mode: PromptMode, args: &[&str]| ModelConfig {
+            name: "test".to_string(),
+            prompt_mode: mode,
+            providers: vec![ProviderConfig {
+                command: "sh".to_string(),
+                args: args.iter().map(|s| s.to_string()).collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let prompt = "x".repeat(LARGE_PROMPT_THRESHOLD + 1);
+        let project = tempfile::tempdir().unwrap();
+        let script = r#"path="${1#Follow the instructions in }"; wc -c < "$path"; echo "$path""#;
+
+        for model in [
+            sh(PromptMode::Arg, &["-c", script, "sh"]),
+            sh(PromptMode::ArgTemplate, &["-c", script, "sh", "{prompt}"]),
+        ] {
+            let result = execute(&model, 0, &prompt, Some(project.path())).unwrap();
+            let mut lines = result.stdout.lines();
+            assert_eq!(
+                lines.next().map(str::trim),
+                Some((LARGE_PROMPT_THRESHOLD + 1).to_string().as_str())
+            );
+            let path = PathBuf::from(lines.next().unwrap());
+            assert!(!path.starts_with(project.path()));
+            assert!(!path.exists());
+        }
+        assert_eq!(std::fs::read_dir(project.path()).unwrap().count(), 0);
+
+        let model = sh(PromptMode::Env, &["-c", "true"]);
+        let Err(err) = execute(&model, 0, &prompt, None) else {
+            panic!("a large env prompt was accepted");
+        };
+        assert!(
+            err.contains("too large for an environment variable"),
+            "{err}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn execute_sets_env_and_redacts_secrets() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("key");
+        std::fs::write(&key_file, "hunter22").unwrap();
+        let model = ModelConfig {
+            name: "test".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![ProviderConfig {
+                command: "sh".to_string(),
+                args: vec![
+                    "-c".to_string(),
+                    r#"echo "${HOME:-none} $MODE"; echo "bad key $TOKEN" >&2"#.to_string(),
+                ],
+                env: [
+                    ("MODE".to_string(), "ci".to_string()),
+                    (
+                        "TOKEN".to_string(),
+                        format!("{{secret:file:{}}}", key_file.display()),
+                    ),
+                ]
+                .into(),
+                env_remove: vec!["HOME".to_string()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let result = execute(&model, 0, "", None).unwrap();
+        assert_eq!(result.stdout, "none ci\n");
+        assert_eq!(result.stderr, "bad key [REDACTED]\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn execute_times_out_and_kills_process_group() {
+        let model = ModelConfig {
+            name: "test".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![ProviderConfig {
+                command: "sh".to_string(),
+                // The backgrounded sleep keeps stdout open; it must be killed
+                // with the group or reading output would block past the deadline.
+                args: vec!["-c".to_string(), "sleep 30 & sleep 30".to_string()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let opts = ExecOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let result = execute_with(&model, 0, "ignored", None, &opts).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(result.timed_out());
+        assert_eq!(result.exit_code, TIMEOUT_EXIT_CODE);
+        assert!(result.stderr.contains("timed out"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn execute_stops_when_cancelled() {
+        let model = ModelConfig {
+            name: "test".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![ProviderConfig {
+                command: "sleep".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let cancel = AtomicBool::new(false);
+        let opts = ExecOptions {
+            cancel: Some(&cancel),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let result = thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(200));
+                cancel.store(true, Ordering::SeqCst);
+            });
+            execute_with(&model, 0, "30", None, &opts).unwrap()
+        });
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(result.cancelled());
+        assert_eq!(result.exit_code, CANCELLED_EXIT_CODE);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn execute_uses_provider_timeout() {
+        let model = ModelConfig {
+            name: "test".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![ProviderConfig {
+                command: "sleep".to_string(),
+                args: vec![],
+                timeout: Some(1),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let result = execute(&model, 0, "30", None).unwrap();
+        assert!(result.timed_out());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn streams_output_line_by_line() {
+        use std::sync::Mutex;
+
+        let model = ModelConfig {
+            name: "test".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![ProviderConfig {
+                command: "sh".to_string(),
+                args: vec![
+                    "-c".to_string(),
+                    "echo one; echo two; echo oops >&2".to_string(),
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let seen: Mutex<Vec<(OutputStream, String)>> = Mutex::new(Vec::new());
+        let sink = |stream: OutputStream, line: &str| {
+            seen.lock().unwrap().push((stream, line.to_string()));
+        };
+        let opts = ExecOptions {
+            on_output: Some(&sink),
+            ..Default::default()
+        };
+
+        let result = execute_with(&model, 0, "ignored", None, &opts).unwrap();
+        assert_eq!(result.stdout, "one\ntwo\n");
+        assert_eq!(result.stdout_bytes, 8);
+        assert_eq!(result.stderr, "oops\n");
+
+        let seen = seen.into_inner().unwrap();
+        let stdout_lines: Vec<&str> = seen
+            .iter()
+            .filter(|(s, _)| *s == OutputStream::Stdout)
+            .map(|(_, l)| l.as_str())
+            .collect();
+        assert_eq!(stdout_lines, vec!["one\n", "two\n"]);
+        assert!(seen.contains(&(OutputStream::Stderr, "oops\n".to_string())));
+    }
+
+    #[test]
+    fn streaming_keeps_only_the_tail() {
+        let input = "x".repeat(STREAM_TAIL_BYTES + 10) + "\nlast\n";
+        let sink = |_: OutputStream, _: &str| {};
+        let (tail, total) = read_stream(
+            input.as_bytes(),
+            OutputStream::Stdout,
+            Some(&sink),
+            Some(STREAM_TAIL_BYTES),
+        );
+        assert_eq!(tail.len(), STREAM_TAIL_BYTES);
+        assert_eq!(total, input.len() as u64);
+        assert!(tail.ends_with("\nlast\n"));
+    }
 }
@@ -0,0 +1,427 @@
+//! Fan-out: one prompt sent to several models at once, with the results
+//! labelled, raced or handed to a judge agent.
+
+use crate::batch;
+use crate::config::{AgentConfig, FanoutMode, ModelConfig, TemplateContext};
+use crate::context::escape_attr;
+use crate::runner::{self, RunOptions, RunReport};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+pub struct FanoutOptions<'a> {
+    pub mode: FanoutMode,
+    /// Required in [`FanoutMode::Judge`]; runs on its own models.
+    pub judge: Option<&'a AgentConfig>,
+    /// The agent the prompt came from; its `output_format` applies to
+    /// every model.
+    pub agent: Option<&'a AgentConfig>,
+    pub working_dir: Option<&'a Path>,
+    pub models: &'a HashMap<String, ModelConfig>,
+    pub diagnostics_model: Option<&'a ModelConfig>,
+    pub timeout: Option<Duration>,
+    /// State DB each model's thread opens; `None` uses the default location.
+    pub state_path: Option<&'a Path>,
+}
+
+/// One model's part of a fan-out.
+#[derive(Debug, Clone, Serialize)]
+pub struct Branch {
+    pub model: String,
+    /// `None` when the model could not be run at all; see `error`.
+    pub report: Option<RunReport>,
+    pub error: Option<String>,
+    /// Stopped because another model answered first.
+    pub cancelled: bool,
+}
+
+impl Branch {
+    pub fn succeeded(&self) -> bool {
+        self.report.as_ref().is_some_and(|r| r.success)
+    }
+
+    /// Why the branch did not succeed, in one line.
+    pub fn failure(&self) -> String {
+        if self.cancelled {
+            return "cancelled".to_string();
+        }
+        if let Some(ref error) = self.error {
+            return error.clone();
+        }
+        let Some(ref report) = self.report else {
+            return String::new();
+        };
+        report
+            .output_error
+            .clone()
+            .or_else(|| {
+                report
+                    .diagnosis
+                    .as_ref()
+                    .map(|d| format!("{}: {}", d.category.as_str(), d.summary))
+            })
+            .unwrap_or_else(|| format!("exit {}", report.exit_code))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FanoutReport {
+    pub mode: &'static str,
+    /// `all`: any model succeeded. `first`: one did. `judge`: the judge did.
+    pub success: bool,
+    /// The final answer.
+    pub output: String,
+    /// In the order the models were given.
+    pub branches: Vec<Branch>,
+    pub judge: Option<RunReport>,
+}
+
+/// Run `prompt` on every model in `targets` concurrently, each with its own
+/// provider failover, and combine the results as `opts.mode` says.
+pub fn run(
+    targets: &[&ModelConfig],
+    prompt: &str,
+    opts: &FanoutOptions,
+) -> Result<FanoutReport, String> {
+    if targets.is_empty() {
+        return Err("Fan-out needs at least one model".to_string());
+    }
+    // Resolved up front so a misconfigured judge fails before any model runs.
+    let judge = match (opts.mode, opts.judge) {
+        (FanoutMode::Judge, None) => {
+            return Err("Fan-out mode 'judge' needs a judge agent".to_string());
+        }
+        (FanoutMode::Judge, Some(judge)) => Some((judge, judge_chain(judge, opts.models)?)),
+        _ => None,
+    };
+
+    let cancel = AtomicBool::new(false);
+    let winner = Mutex::new(None);
+    let branches: Vec<Branch> = std::thread::scope(|scope| {
+        let handles: Vec<_> = targets
+            .iter()
+            .enumerate()
+            .map(|(i, model)| {
+                let (cancel, winner) = (&cancel, &winner);
+                scope.spawn(move || {
+                    let branch = run_branch(model, prompt, opts, cancel);
+                    if opts.mode == FanoutMode::First && branch.succeeded() {
+                        winner
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .get_or_insert(i);
+                        cancel.store(true, Ordering::SeqCst);
+                    }
+                    eprintln!("[fanout] {}: {}", branch.model, describe(&branch));
+                    branch
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .zip(targets)
+            .map(|(handle, model)| {
+                handle.join().unwrap_or_else(|_| Branch {
+                    model: model.name.clone(),
+                    report: None,
+                    error: Some("panicked".to_string()),
+                    cancelled: false,
+                })
+            })
+            .collect()
+    });
+
+    let mut report = FanoutReport {
+        mode: opts.mode.as_str(),
+        success: false,
+        output: String::new(),
+        branches,
+        judge: None,
+    };
+    match opts.mode {
+        FanoutMode::All => {
+            report.success = report.branches.iter().any(Branch::succeeded);
+            report.output = label(&report.branches);
+        }
+        FanoutMode::First => {
+            let winner = winner.into_inner().unwrap_or_else(|e| e.into_inner());
+            if let Some(stdout) = winner
+                .and_then(|i| report.branches[i].report.as_ref())
+                .map(|r| r.stdout.clone())
+            {
+                report.success = true;
+                report.output = stdout;
+            }
+        }
+        FanoutMode::Judge => {
+            let answers: Vec<Branch> = report
+                .branches
+                .iter()
+                .filter(|b| b.succeeded())
+                .cloned()
+                .collect();
+            if let (Some((judge, chain)), false) = (judge, answers.is_empty()) {
+                let verdict = run_judge(judge, &chain, prompt, &label(&answers), opts)?;
+                report.success = verdict.success;
+                report.output = verdict.stdout.clone();
+                report.judge = Some(verdict);
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn run_branch(
+    model: &ModelConfig,
+    prompt: &str,
+    opts: &FanoutOptions,
+    cancel: &AtomicBool,
+) -> Branch {
+    let state = batch::open_state(opts.state_path);
+    let agent_name = opts.agent.map(|a| a.name.as_str());
+    let run_opts = RunOptions {
+        working_dir: opts.working_dir,
+        agent_name,
+        run_id: None,
+        cancel: Some(cancel),
+        diagnostics_model: opts.diagnostics_model,
+        models: opts.models,
+        timeout: opts.timeout,
+        on_output: None,
+        output_format: opts.agent.and_then(AgentConfig::output_format),
+        output_retry: opts.agent.is_some_and(AgentConfig::output_retry),
+    };
+    match runner::run(model, prompt, &state, &run_opts) {
+        Ok(outcome) => Branch {
+            model: model.name.clone(),
+            cancelled: outcome.result.cancelled(),
+            report: Some(outcome.report(agent_name)),
+            error: None,
+        },
+        Err(e) => Branch {
+            model: model.name.clone(),
+            report: None,
+            error: Some(e),
+            cancelled: false,
+        },
+    }
+}
+
+fn describe(branch: &Branch) -> String {
+    match branch.report {
+        Some(ref report) if branch.succeeded() => {
+            format!("succeeded in {:.1}s", report.duration_ms as f64 / 1000.0)
+        }
+        _ => format!("failed ({})", branch.failure()),
+    }
+}
+
+/// Each branch's stdout in a `<response model="...">` block; failed
+/// branches as an empty element with the reason.
+pub fn label(branches: &[Branch]) -> String {
+    branches
+        .iter()
+        .map(|branch| match branch.report {
+            Some(ref report) if branch.succeeded() => format!(
+                "<response model=\"{}\">\n{}\n</response>",
+                escape_attr(&branch.model),
+                report.stdout.trim_end()
+            ),
+            _ => format!(
+                "<response model=\"{}\" failed=\"{}\"/>",
+                escape_attr(&branch.model),
+                escape_attr(&branch.failure())
+            ),
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// The judge's models, in fallback order.
+fn judge_chain<'a>(
+    judge: &AgentConfig,
+    models: &'a HashMap<String, ModelConfig>,
+) -> Result<Vec<&'a ModelConfig>, String> {
+    judge
+        .model_chain()
+        .into_iter()
+        .map(|name| {
+            models.get(name).ok_or_else(|| {
+                format!(
+                    "Unknown model '{name}' referenced by agent '{}'",
+                    judge.name
+                )
+            })
+        })
+        .collect()
+}
+
+/// Give the judge the task and the labelled answers: as `{{task}}` and
+/// `{{responses}}`, and together as the prompt.
+fn run_judge(
+    judge: &AgentConfig,
+    chain: &[&ModelConfig],
+    task: &str,
+    responses: &str,
+    opts: &FanoutOptions,
+) -> Result<RunReport, String> {
+    let prompt = judge.render(&TemplateContext {
+        prompt: format!("<task>\n{}\n</task>\n\n{responses}", task.trim_end()),
+        project_dir: opts.working_dir.map(Path::to_path_buf),
+        vars: HashMap::from([
+            ("task".to_string(), task.to_string()),
+            ("responses".to_string(), responses.to_string()),
+        ]),
+    })?;
+
+    eprintln!("[fanout] judging with {}", judge.name);
+    let state = batch::open_state(opts.state_path);
+    let run_opts = RunOptions {
+        working_dir: opts.working_dir,
+        agent_name: Some(&judge.name),
+        run_id: None,
+        cancel: None,
+        diagnostics_model: opts.diagnostics_model,
+        models: opts.models,
+        timeout: opts.timeout,
+        on_output: None,
+        output_format: judge.output_format(),
+        output_retry: judge.output_retry(),
+    };
+    runner::run_with_fallback(chain, &prompt, &state, &run_opts)
+        .map(|outcome| outcome.report(Some(&judge.name)))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_support::sh_models;
+
+    fn models() -> HashMap<String, ModelConfig> {
+        sh_models(&[
+            ("fast", "echo 4"),
+            ("slow", "sleep 30; echo four"),
+            ("down", "echo 'connection refused' >&2; exit 1"),
+            ("judge", "echo \"verdict on: $0\""),
+        ])
+    }
+
+    fn arbiter(model: &str) -> AgentConfig {
+        AgentConfig {
+            name: "arbiter".to_string(),
+            model: model.to_string(),
+            instructions: "Pick the best answer to: {{task}}".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn fanout(
+        mode: FanoutMode,
+        names: &[&str],
+        models: &HashMap<String, ModelConfig>,
+        judge: Option<&AgentConfig>,
+    ) -> Result<FanoutReport, String> {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.db");
+        let opts = FanoutOptions {
+            mode,
+            judge,
+            agent: None,
+            working_dir: None,
+            models,
+            diagnostics_model: None,
+            timeout: None,
+            state_path: Some(&state_path),
+        };
+        let targets: Vec<&ModelConfig> = names.iter().map(|n| &models[*n]).collect();
+        run(&targets, "2+2?", &opts)
+    }
+
+    #[test]
+    fn all_labels_every_answer() {
+        let all = fanout(FanoutMode::All, &["fast", "down"], &models(), None).unwrap();
+        assert!(all.success);
+        assert!(
+            all.output.starts_with(
+                "<response model=\"fast\">\n4\n</response>\n\n<response model=\"down\" failed=\""
+            ),
+            "{}",
+            all.output
+        );
+    }
+
+    #[test]
+    fn first_returns_the_fastest_and_cancels_the_rest() {
+        let start = std::time::Instant::now();
+        let first = fanout(
+            FanoutMode::First,
+            &["slow", "down", "fast"],
+            &models(),
+            None,
+        )
+        .unwrap();
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "slow was not cancelled"
+        );
+        assert!(first.success);
+        assert_eq!(first.output, "4\n");
+        assert!(first.branches[0].cancelled);
+        let slow = first.branches[0].report.as_ref().unwrap();
+        assert!(slow.attempts[0].cancelled);
+        // "down" may fail or be cancelled, depending on which ends first.
+        assert!(!first.branches[1].succeeded());
+    }
+
+    #[test]
+    fn judge_sees_only_successful_answers() {
+        let judge = arbiter("judge");
+        let judged = fanout(
+            FanoutMode::Judge,
+            &["fast", "down"],
+            &models(),
+            Some(&judge),
+        )
+        .unwrap();
+        assert!(judged.success);
+        assert_eq!(
+            judged.output,
+            "verdict on: Pick the best answer to: 2+2?\n\n<task>\n2+2?\n</task>\n\n\
+             <response model=\"fast\">\n4\n</response>\n"
+        );
+        assert_eq!(judged.judge.unwrap().agent.as_deref(), Some("arbiter"));
+
+        let nobody = fanout(FanoutMode::Judge, &["down"], &models(), Some(&judge)).unwrap();
+        assert!(!nobody.success && nobody.judge.is_none());
+    }
+
+    #[test]
+    fn unknown_judge_model_fails_before_any_model_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+        let script = format!("touch {}", marker.display());
+        let models = sh_models(&[("marker", &script)]);
+        let judge = arbiter("missing");
+
+        let err = fanout(FanoutMode::Judge, &["marker"], &models, Some(&judge)).unwrap_err();
+        assert!(err.contains("Unknown model 'missing'"), "{err}");
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn labels_escape_attributes() {
+        let branch = Branch {
+            model: "m".to_string(),
+            report: None,
+            error: Some("bad \"quote\" <x>".to_string()),
+            cancelled: false,
+        };
+        assert_eq!(
+            label(&[branch]),
+            "<response model=\"m\" failed=\"bad &quot;quote&quot; &lt;x&gt;\"/>"
+        );
+    }
+}
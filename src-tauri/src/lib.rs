@@ -1,10 +1,21 @@
 pub mod balancer;
+pub mod batch;
 pub mod config;
+pub mod context;
 pub mod diagnostics;
 pub mod discovery;
 pub mod executor;
+pub mod fanout;
+pub mod mcp;
+pub mod output;
+pub mod pipeline;
+pub mod runner;
+pub mod serve;
 pub mod setup;
 pub mod state;
+pub mod stats;
+#[cfg(test)]
+mod test_support;
 
 use config::{ModelConfig, PromptMode};
 use serde::{Deserialize, Serialize};
@@ -27,6 +38,13 @@ pub struct TestModelResult {
     pub exit_code: i32,
 }
 
+/// One line of provider output streamed to the GUI while a test runs.
+#[derive(Serialize, Clone)]
+pub struct OutputChunk {
+    pub stream: executor::OutputStream,
+    pub line: String,
+}
+
 #[derive(Serialize)]
 pub struct ModelSummary {
     pub name: String,
@@ -347,6 +365,7 @@ fn update_pool(
             model.providers.push(config::ProviderConfig {
                 command: (*cmd).clone(),
                 args: vec![],
+                ..Default::default()
             });
         }
 
@@ -368,6 +387,7 @@ fn update_pool(
 async fn test_model(
     state: tauri::State<'_, AppState>,
     name: String,
+    on_output: Option<Channel<OutputChunk>>,
 ) -> Result<TestModelResult, String> {
     let model = {
         let models = state.models.lock().map_err(|e| e.to_string())?;
@@ -385,8 +405,39 @@ async fn test_model(
 
     let result = tauri::async_runtime::spawn_blocking(move || {
         let db = state::StateDb::open(&db_path).map_err(|e| e.to_string())?;
-        let provider_index = balancer::select_provider(&model, &db);
-        executor::execute(&model, provider_index, "Say hello in one sentence.", None)
+        let reservation = balancer::select_and_reserve(&model, &db, &[])?.ok_or_else(|| {
+            format!(
+                "All providers of {} are at their max_concurrent limit",
+                model.name
+            )
+        })?;
+        // Released when dropped, whether or not the test gets to run.
+        let lease = runner::Lease::new(&db, reservation.lease_id);
+        let renew = || lease.renew();
+        let accounts = db.list_accounts(None)?;
+        let forward = on_output.map(|channel| {
+            move |stream: executor::OutputStream, line: &str| {
+                let _ = channel.send(OutputChunk {
+                    stream,
+                    line: line.to_string(),
+                });
+            }
+        });
+        let opts = executor::ExecOptions {
+            on_output: forward
+                .as_ref()
+                .map(|f| f as &(dyn Fn(executor::OutputStream, &str) + Sync)),
+            accounts: &accounts,
+            heartbeat: Some(&renew),
+            ..Default::default()
+        };
+        executor::execute_with(
+            &model,
+            reservation.provider_index,
+            "Say hello in one sentence.",
+            None,
+            &opts,
+        )
     })
     .await
     .map_err(|e| e.to_string())??;
@@ -443,6 +494,8 @@ pub struct AddAccountInput {
     pub provider: String,
     pub profile_name: String,
     pub auth_method: AuthMethod,
+    #[serde(default)]
+    pub config_dir: Option<String>,
 }
 
 #[tauri::command]
@@ -474,6 +527,7 @@ fn add_account(
         auth_method: account.auth_method,
         auth_status: AuthStatus::Unknown,
         created_at: now,
+        config_dir: account.config_dir,
     };
 
     db.insert_account(&record)?;
@@ -636,8 +690,10 @@ mod tests {
                 .map(|c| ProviderConfig {
                     command: c.to_string(),
                     args: vec![],
+                    ..Default::default()
                 })
                 .collect(),
+            ..Default::default()
         }
     }
 
@@ -680,12 +736,15 @@ mod tests {
                     ProviderConfig {
                         command: "claude".to_string(),
                         args: vec![],
+                        ..Default::default()
                     },
                     ProviderConfig {
                         command: "claude".to_string(),
                         args: vec!["-p".to_string()],
+                        ..Default::default()
                     },
                 ],
+                ..Default::default()
             },
         );
         models.insert("y".into(), make_model("y", &["claude"]));
@@ -708,7 +767,9 @@ mod tests {
                 providers: vec![ProviderConfig {
                     command: "env -u CLAUDECODE claude".to_string(),
                     args: vec![],
+                    ..Default::default()
                 }],
+                ..Default::default()
             },
         );
         // Plain command should also group as "claude"
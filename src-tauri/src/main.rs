@@ -1,23 +1,35 @@
-use agent_runner_lib::balancer;
+use agent_runner_lib::batch::{self, BatchOptions};
 use agent_runner_lib::config::{
-    AgentConfig, ModelConfig, load_agent_file, load_agents, load_models,
+    AgentConfig, FanoutMode, ModelConfig, TemplateContext, load_agent_file, load_agents,
+    load_models, resolve_inheritance,
 };
-use agent_runner_lib::diagnostics;
-use agent_runner_lib::executor;
-use agent_runner_lib::state::StateDb;
-
-use clap::Parser;
+use agent_runner_lib::context;
+use agent_runner_lib::executor::OutputStream;
+use agent_runner_lib::fanout::{self, FanoutOptions};
+use agent_runner_lib::mcp::{self, McpOptions};
+use agent_runner_lib::pipeline::{self, PipelineOptions, StepResult, StepStatus};
+use agent_runner_lib::runner::{self, RunOptions};
+use agent_runner_lib::serve::{ServeOptions, Server};
+use agent_runner_lib::state::{StateDb, StatsFilter};
+use agent_runner_lib::stats;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::collections::HashMap;
-use std::io::{IsTerminal, Read};
+use std::io::{IsTerminal, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(
     name = "oulipoly-agent-runner",
-    about = "LLM agent runner with load balancing"
+    about = "LLM agent runner with load balancing",
+    args_conflicts_with_subcommands = true
 )]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Agent name (from agents directory)
     agent: Option<String>,
 
@@ -29,6 +41,18 @@ struct Cli {
     #[arg(short, long)]
     model: Option<String>,
 
+    /// Run the prompt on several models at once (comma-separated)
+    #[arg(long, value_name = "MODELS", value_delimiter = ',', conflicts_with_all = ["model", "stream"])]
+    fanout: Vec<String>,
+
+    /// How fan-out results are combined: all, first or judge
+    #[arg(long, value_name = "MODE", value_parser = FanoutMode::parse)]
+    fanout_mode: Option<FanoutMode>,
+
+    /// Agent that picks or merges fan-out results (implies --fanout-mode judge)
+    #[arg(long, value_name = "AGENT")]
+    judge: Option<String>,
+
     /// Path to an agent .md file
     #[arg(short = 'a', long = "agent-file")]
     agent_file: Option<PathBuf>,
@@ -48,6 +72,200 @@ struct Cli {
     /// Agents directory
     #[arg(long)]
     agents_dir: Option<PathBuf>,
+
+    /// Kill the provider after this many seconds (overrides model/provider timeouts)
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// Forward provider output to the terminal as it is produced
+    #[arg(long, conflicts_with = "output")]
+    stream: bool,
+
+    /// Set a template variable used in agent instructions (repeatable)
+    #[arg(long = "var", value_name = "KEY=VALUE", value_parser = parse_var)]
+    vars: Vec<(String, String)>,
+
+    /// Attach files matching this glob (relative to --project) before the instructions (repeatable)
+    #[arg(long = "context", value_name = "GLOB")]
+    context: Vec<String>,
+
+    /// Print the provider's output as-is (text) or one JSON object describing the run (json)
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show invocation history and provider health from the state DB
+    Stats(StatsArgs),
+    /// Run every entry of a JSONL/YAML manifest, writing one result per entry
+    Batch(BatchArgs),
+    /// Serve models and agents over HTTP on localhost
+    Serve(ServeArgs),
+    /// Run an MCP server on stdio exposing agents and models as tools
+    Mcp(McpArgs),
+    /// Run the steps of a TOML/YAML pipeline, feeding outputs forward
+    Pipeline(PipelineArgs),
+}
+
+#[derive(Args)]
+struct StatsArgs {
+    /// Only show this model
+    #[arg(short, long)]
+    model: Option<String>,
+
+    /// Start of the range: an age (30m, 24h, 7d, 2w), a date or an RFC 3339 time
+    #[arg(long)]
+    since: Option<String>,
+
+    /// End of the range, in the same formats as --since
+    #[arg(long)]
+    until: Option<String>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = StatsFormat::Table)]
+    format: StatsFormat,
+}
+
+#[derive(Args)]
+struct BatchArgs {
+    /// Manifest file (.jsonl, .yaml or .yml)
+    manifest: PathBuf,
+
+    /// Results JSONL (default: <manifest>.results.jsonl next to the manifest)
+    #[arg(short, long)]
+    results: Option<PathBuf>,
+
+    /// Number of entries to run at once
+    #[arg(short = 'j', long, default_value_t = 4)]
+    parallel: usize,
+
+    /// Set a template variable for every entry's agent (repeatable)
+    #[arg(long = "var", value_name = "KEY=VALUE", value_parser = parse_var)]
+    vars: Vec<(String, String)>,
+
+    /// Re-run entries whose recorded result is a failure
+    #[arg(long)]
+    retry_failed: bool,
+
+    /// Kill each provider after this many seconds (overrides model/provider timeouts)
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// Models directory (default: ~/.config/oulipoly-agent-runner/models/)
+    #[arg(long)]
+    models_dir: Option<PathBuf>,
+
+    /// Agents directory
+    #[arg(long)]
+    agents_dir: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8765")]
+    bind: String,
+
+    /// Bearer token clients must send (default: $AGENT_RUNNER_TOKEN, else a random one)
+    #[arg(long)]
+    token: Option<String>,
+
+    /// Number of requests handled at once
+    #[arg(short = 'j', long, default_value_t = 4)]
+    workers: usize,
+
+    /// Kill each provider after this many seconds (overrides model/provider timeouts)
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// Models directory (default: ~/.config/oulipoly-agent-runner/models/)
+    #[arg(long)]
+    models_dir: Option<PathBuf>,
+
+    /// Agents directory
+    #[arg(long)]
+    agents_dir: Option<PathBuf>,
+
+    /// Also serve OpenAI-compatible endpoints under /openai/v1
+    #[arg(long)]
+    openai: bool,
+
+    /// Template turning chat messages into a prompt (uses {{system}}, {{conversation}}, {{last_user}})
+    #[arg(long, value_name = "FILE", requires = "openai")]
+    chat_template: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct McpArgs {
+    /// Number of tool calls run at once
+    #[arg(short = 'j', long, default_value_t = 4)]
+    workers: usize,
+
+    /// Kill each provider after this many seconds (overrides model/provider timeouts)
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// Models directory (default: ~/.config/oulipoly-agent-runner/models/)
+    #[arg(long)]
+    models_dir: Option<PathBuf>,
+
+    /// Agents directory
+    #[arg(long)]
+    agents_dir: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct PipelineArgs {
+    /// Pipeline file (.toml, .yaml or .yml)
+    pipeline: PathBuf,
+
+    /// Pipeline input, available to steps as {{input}} (default: stdin)
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    input: Vec<String>,
+
+    /// Working directory for steps without their own project
+    #[arg(short = 'p', long = "project")]
+    project: Option<PathBuf>,
+
+    /// Set a template variable for every step (repeatable)
+    #[arg(long = "var", value_name = "KEY=VALUE", value_parser = parse_var)]
+    vars: Vec<(String, String)>,
+
+    /// Kill each provider after this many seconds (overrides model/provider timeouts)
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// Print the last step's output (text) or a JSON report of every step (json)
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Models directory (default: ~/.config/oulipoly-agent-runner/models/)
+    #[arg(long)]
+    models_dir: Option<PathBuf>,
+
+    /// Agents directory
+    #[arg(long)]
+    agents_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum StatsFormat {
+    Table,
+    Json,
+}
+
+fn parse_var(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{s}'"))?;
+    Ok((key.trim().to_string(), value.to_string()))
 }
 
 #[derive(Debug)]
@@ -121,71 +339,390 @@ fn resolve_prompt(cli: &Cli, include_agent_as_prompt: bool) -> Result<String, St
     Ok(input)
 }
 
-fn resolve_models_dir(cli: &Cli) -> PathBuf {
-    if let Some(ref dir) = cli.models_dir {
-        return dir.clone();
-    }
+fn resolve_models_dir(dir: Option<&PathBuf>) -> PathBuf {
+    dir.cloned().unwrap_or_else(|| default_config_dir("models"))
+}
+
+fn resolve_agents_dir(dir: Option<&PathBuf>) -> PathBuf {
+    dir.cloned().unwrap_or_else(|| default_config_dir("agents"))
+}
+
+fn default_config_dir(name: &str) -> PathBuf {
     dirs::config_dir()
-        .map(|d| d.join("oulipoly-agent-runner").join("models"))
-        .unwrap_or_else(|| PathBuf::from("models"))
+        .map(|d| d.join("oulipoly-agent-runner").join(name))
+        .unwrap_or_else(|| PathBuf::from(name))
+}
+
+fn run_stats(args: &StatsArgs) -> Result<i32, String> {
+    let state = StateDb::open_default()?;
+    let now = chrono::Utc::now();
+    let filter = StatsFilter {
+        model_name: args.model.clone(),
+        since: args
+            .since
+            .as_deref()
+            .map(|s| stats::parse_time_bound(s, now))
+            .transpose()?,
+        until: args
+            .until
+            .as_deref()
+            .map(|s| stats::parse_time_bound(s, now))
+            .transpose()?,
+    };
+
+    let report = stats::collect(&state, &filter)?;
+    match args.format {
+        StatsFormat::Table => print!("{}", stats::render_table(&report)),
+        StatsFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&report)
+                .map_err(|e| format!("Failed to serialize stats: {e}"))?
+        ),
+    }
+    Ok(0)
+}
+
+fn run_batch(args: &BatchArgs) -> Result<i32, String> {
+    let models = load_models(&resolve_models_dir(args.models_dir.as_ref()))?;
+    let agents_dir = resolve_agents_dir(args.agents_dir.as_ref());
+    let results_path = args
+        .results
+        .clone()
+        .unwrap_or_else(|| args.manifest.with_extension("results.jsonl"));
+
+    let app_config = load_app_config();
+    let opts = BatchOptions {
+        results_path: &results_path,
+        parallelism: args.parallel,
+        retry_failed: args.retry_failed,
+        vars: &args.vars.iter().cloned().collect(),
+        models: &models,
+        agents_dir: &agents_dir,
+        diagnostics_model: app_config
+            .diagnostics_model
+            .as_ref()
+            .and_then(|name| models.get(name)),
+        timeout: args.timeout.map(Duration::from_secs),
+        state_path: None,
+    };
+
+    let summary = batch::run_batch(&args.manifest, &opts)?;
+    eprintln!(
+        "[batch] {} entries: {} succeeded, {} failed, {} already done. Results in {}",
+        summary.total,
+        summary.succeeded,
+        summary.failed,
+        summary.skipped,
+        results_path.display()
+    );
+    Ok(if summary.failed == 0 { 0 } else { 1 })
+}
+
+fn run_serve(args: &ServeArgs) -> Result<i32, String> {
+    let models = load_models(&resolve_models_dir(args.models_dir.as_ref()))?;
+    let agents = load_agents(&resolve_agents_dir(args.agents_dir.as_ref()))?;
+    let token = args
+        .token
+        .clone()
+        .or_else(|| std::env::var("AGENT_RUNNER_TOKEN").ok())
+        .filter(|t| !t.is_empty());
+    let token = match token {
+        Some(token) => token,
+        None => {
+            let token = uuid::Uuid::new_v4().simple().to_string();
+            eprintln!("[serve] token: {token}");
+            token
+        }
+    };
+    let base_dir =
+        std::env::current_dir().map_err(|e| format!("Failed to read current directory: {e}"))?;
+    let chat_template = match args.chat_template {
+        Some(ref path) => Some(
+            std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {}: {e}", path.display()))?,
+        ),
+        None => None,
+    };
+
+    let app_config = load_app_config();
+    let opts = ServeOptions {
+        token: &token,
+        workers: args.workers,
+        models: &models,
+        agents: &agents,
+        diagnostics_model: app_config
+            .diagnostics_model
+            .as_ref()
+            .and_then(|name| models.get(name)),
+        timeout: args.timeout.map(Duration::from_secs),
+        base_dir: &base_dir,
+        state_path: None,
+        openai: args.openai,
+        chat_template: chat_template.as_deref(),
+    };
+
+    let server = Server::bind(&args.bind)?;
+    eprintln!(
+        "[serve] listening on http://{} with {} workers ({} models, {} agents)",
+        server.addr(),
+        args.workers.max(1),
+        models.len(),
+        agents.len()
+    );
+    server.run(&opts);
+    Ok(0)
+}
+
+fn run_mcp(args: &McpArgs) -> Result<i32, String> {
+    let models = load_models(&resolve_models_dir(args.models_dir.as_ref()))?;
+    let agents = load_agents(&resolve_agents_dir(args.agents_dir.as_ref()))?;
+    let base_dir =
+        std::env::current_dir().map_err(|e| format!("Failed to read current directory: {e}"))?;
+
+    let app_config = load_app_config();
+    let opts = McpOptions {
+        models: &models,
+        agents: &agents,
+        diagnostics_model: app_config
+            .diagnostics_model
+            .as_ref()
+            .and_then(|name| models.get(name)),
+        timeout: args.timeout.map(Duration::from_secs),
+        workers: args.workers,
+        base_dir: &base_dir,
+        state_path: None,
+    };
+
+    // Stdout carries the protocol; everything else goes to stderr.
+    mcp::serve(&opts, std::io::stdin().lock(), std::io::stdout())?;
+    Ok(0)
+}
+
+fn run_pipeline(args: &PipelineArgs) -> Result<i32, String> {
+    let models = load_models(&resolve_models_dir(args.models_dir.as_ref()))?;
+    let agents = load_agents(&resolve_agents_dir(args.agents_dir.as_ref()))?;
+    let pipeline = pipeline::load(&args.pipeline, &agents, &models)?;
+
+    let input = if !args.input.is_empty() {
+        args.input.join(" ")
+    } else if std::io::stdin().is_terminal() {
+        String::new()
+    } else {
+        let mut input = String::new();
+        std::io::stdin()
+            .read_to_string(&mut input)
+            .map_err(|e| format!("Failed to read stdin: {e}"))?;
+        input
+    };
+    let project = match args.project {
+        Some(ref dir) => Some(
+            std::env::current_dir()
+                .map_err(|e| format!("Failed to read current directory: {e}"))?
+                .join(dir),
+        ),
+        None => None,
+    };
+
+    let app_config = load_app_config();
+    let opts = PipelineOptions {
+        vars: &args.vars.iter().cloned().collect(),
+        models: &models,
+        agents: &agents,
+        project: project.as_deref(),
+        diagnostics_model: app_config
+            .diagnostics_model
+            .as_ref()
+            .and_then(|name| models.get(name)),
+        timeout: args.timeout.map(Duration::from_secs),
+        state_path: None,
+    };
+
+    let total = pipeline.steps.len();
+    let report = pipeline::run(&pipeline, &input, &opts, &|step| {
+        let n = pipeline
+            .steps
+            .iter()
+            .position(|s| s.id == step.id)
+            .unwrap_or(0)
+            + 1;
+        eprintln!(
+            "[pipeline] {n}/{total} {}: {}",
+            step.id,
+            describe_step(step)
+        );
+    });
+    eprintln!("[pipeline] run {}", report.run_id);
+
+    let failed = report
+        .steps
+        .iter()
+        .rfind(|s| s.status == StepStatus::Failed);
+    let exit_code = match failed {
+        Some(step) if !report.success => step
+            .report
+            .as_ref()
+            .map_or(1, |r| if r.exit_code == 0 { 1 } else { r.exit_code }),
+        _ => 0,
+    };
+
+    if args.output == OutputFormat::Json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report)
+                .map_err(|e| format!("Failed to serialize result: {e}"))?
+        );
+    } else if report.success {
+        if let Some(step) = report.last_run() {
+            print!("{}", step.stdout());
+        }
+    } else if let Some(step) = failed
+        && let Some(ref run) = step.report
+    {
+        eprintln!("{}", run.stderr);
+    }
+    Ok(exit_code)
+}
+
+fn describe_step(step: &StepResult) -> String {
+    if let Some(ref error) = step.error {
+        return format!("error: {error}");
+    }
+    match (&step.report, step.status) {
+        (Some(report), StepStatus::Failed) => match report.output_error {
+            Some(ref error) => format!("failed ({error})"),
+            None => format!("failed (exit {}) on {}", report.exit_code, report.model),
+        },
+        (Some(report), _) => format!("succeeded on {}", report.model),
+        (None, status) => status.as_str().to_string(),
+    }
 }
 
 fn run(cli: Cli) -> Result<i32, String> {
-    let models_dir = resolve_models_dir(&cli);
+    match cli.command {
+        Some(Command::Stats(ref args)) => return run_stats(args),
+        Some(Command::Batch(ref args)) => return run_batch(args),
+        Some(Command::Serve(ref args)) => return run_serve(args),
+        Some(Command::Mcp(ref args)) => return run_mcp(args),
+        Some(Command::Pipeline(ref args)) => return run_pipeline(args),
+        None => {}
+    }
+
+    let models_dir = resolve_models_dir(cli.models_dir.as_ref());
     let models = load_models(&models_dir)?;
 
     let working_dir = cli.project.clone();
 
-    // Direct model execution (--model)
-    if let Some(ref model_name) = cli.model {
-        let model = models
-            .get(model_name)
-            .ok_or_else(|| format!("Unknown model: {model_name}"))?;
-
-        let prompt = if let Some(ref agent_path) = cli.agent_file {
-            let agent = load_agent_file(agent_path)?;
+    // Direct model execution (--model, --fanout)
+    if cli.model.is_some() || !cli.fanout.is_empty() {
+        let (prompt, agent) = if let Some(ref agent_path) = cli.agent_file {
+            let agent = load_agent_file_resolved(agent_path, &cli)?;
             let raw_prompt = resolve_prompt(&cli, true)?;
-            format!("{}\n\n{}", agent.instructions, raw_prompt)
+            (
+                agent.render(&template_context(&cli, raw_prompt))?,
+                Some(agent),
+            )
         } else {
-            resolve_prompt(&cli, true)?
+            (resolve_prompt(&cli, true)?, None)
         };
-
-        return run_with_balancing(model, &prompt, &models, working_dir.as_deref());
+        let prompt = attach_context(&cli, agent.as_ref(), &prompt)?;
+
+        let Some(ref model_name) = cli.model else {
+            return run_fanout(
+                &cli.fanout,
+                &prompt,
+                agent.as_ref(),
+                &models,
+                working_dir.as_deref(),
+                &cli,
+            );
+        };
+        let model = models
+            .get(model_name)
+            .ok_or_else(|| format!("Unknown model: {model_name}"))?;
+        return run_with_balancing(
+            &[model],
+            &prompt,
+            agent.as_ref(),
+            &models,
+            working_dir.as_deref(),
+            &cli,
+        );
     }
 
     // Agent-based execution
     let agent = resolve_agent(&cli)?;
 
-    let model = models.get(&agent.model).ok_or_else(|| {
-        format!(
-            "Unknown model '{}' referenced by agent '{}'",
-            agent.model, agent.name
-        )
-    })?;
+    if !agent.fanout.is_empty() {
+        let raw_prompt = resolve_prompt(&cli, false)?;
+        let full_prompt = agent.render(&template_context(&cli, raw_prompt))?;
+        let full_prompt = attach_context(&cli, Some(&agent), &full_prompt)?;
+        return run_fanout(
+            &agent.fanout,
+            &full_prompt,
+            Some(&agent),
+            &models,
+            working_dir.as_deref(),
+            &cli,
+        );
+    }
+
+    let chain = agent
+        .model_chain()
+        .into_iter()
+        .map(|name| {
+            models.get(name).ok_or_else(|| {
+                format!(
+                    "Unknown model '{name}' referenced by agent '{}'",
+                    agent.name
+                )
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
     let raw_prompt = resolve_prompt(&cli, false)?;
-    let full_prompt = if agent.instructions.is_empty() {
-        raw_prompt
-    } else {
-        format!("{}\n\n{}", agent.instructions, raw_prompt)
+    let full_prompt = agent.render(&template_context(&cli, raw_prompt))?;
+    let full_prompt = attach_context(&cli, Some(&agent), &full_prompt)?;
+
+    run_with_balancing(
+        &chain,
+        &full_prompt,
+        Some(&agent),
+        &models,
+        working_dir.as_deref(),
+        &cli,
+    )
+}
+
+/// Prefix the prompt with the files from the agent's `context:` and `--context`.
+fn attach_context(cli: &Cli, agent: Option<&AgentConfig>, prompt: &str) -> Result<String, String> {
+    let mut patterns = agent.map(|a| a.context.clone()).unwrap_or_default();
+    patterns.extend(cli.context.iter().cloned());
+    let base = match cli.project {
+        Some(ref dir) => dir.clone(),
+        None => {
+            std::env::current_dir().map_err(|e| format!("Failed to read current directory: {e}"))?
+        }
     };
+    context::attach(prompt, &patterns, &base)
+}
 
-    run_with_balancing(model, &full_prompt, &models, working_dir.as_deref())
+fn template_context(cli: &Cli, prompt: String) -> TemplateContext {
+    TemplateContext {
+        prompt,
+        project_dir: cli.project.clone(),
+        vars: cli.vars.iter().cloned().collect(),
+    }
 }
 
 fn resolve_agent(cli: &Cli) -> Result<AgentConfig, String> {
     // --agent-file takes priority
     if let Some(ref path) = cli.agent_file {
-        return load_agent_file(path);
+        return load_agent_file_resolved(path, cli);
     }
 
     // Named agent from agents directory
     if let Some(ref name) = cli.agent {
-        let agents_dir = cli.agents_dir.clone().unwrap_or_else(|| {
-            dirs::config_dir()
-                .map(|d| d.join("oulipoly-agent-runner").join("agents"))
-                .unwrap_or_else(|| PathBuf::from("agents"))
-        });
+        let agents_dir = resolve_agents_dir(cli.agents_dir.as_ref());
         let agents = load_agents(&agents_dir)?;
         return agents
             .get(name)
@@ -196,76 +733,169 @@ fn resolve_agent(cli: &Cli) -> Result<AgentConfig, String> {
     Err("No agent specified. Use a positional argument or --agent-file.".to_string())
 }
 
+/// Load an agent file from any path; its `extends`/`mixins` refer to agents
+/// in the agents directory.
+fn load_agent_file_resolved(path: &Path, cli: &Cli) -> Result<AgentConfig, String> {
+    let agent = load_agent_file(path)?;
+    if agent.extends.is_none() && agent.mixins.is_empty() {
+        return Ok(agent);
+    }
+    let agents = load_agents(&resolve_agents_dir(cli.agents_dir.as_ref()))?;
+    resolve_inheritance(&agent, &agents)
+}
+
+/// Run `prompt` on each of `model_names` concurrently and print the combined
+/// result. `--fanout-mode`/`--judge` override the agent's settings.
+fn run_fanout(
+    model_names: &[String],
+    prompt: &str,
+    agent: Option<&AgentConfig>,
+    models: &HashMap<String, ModelConfig>,
+    working_dir: Option<&Path>,
+    cli: &Cli,
+) -> Result<i32, String> {
+    let targets = model_names
+        .iter()
+        .map(|name| {
+            models
+                .get(name)
+                .ok_or_else(|| format!("Unknown model: {name}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let judge_name = cli
+        .judge
+        .as_ref()
+        .or_else(|| agent.and_then(|a| a.judge.as_ref()));
+    let judge = match judge_name {
+        Some(name) => {
+            let agents = load_agents(&resolve_agents_dir(cli.agents_dir.as_ref()))?;
+            let judge = agents
+                .get(name)
+                .cloned()
+                .ok_or_else(|| format!("Unknown agent: {name}"))?;
+            Some(judge)
+        }
+        None => None,
+    };
+    let mode = cli
+        .fanout_mode
+        .or_else(|| agent.and_then(|a| a.fanout_mode))
+        .unwrap_or(if judge.is_some() {
+            FanoutMode::Judge
+        } else {
+            FanoutMode::All
+        });
+
+    let app_config = load_app_config();
+    let opts = FanoutOptions {
+        mode,
+        judge: judge.as_ref(),
+        agent,
+        working_dir,
+        models,
+        diagnostics_model: app_config
+            .diagnostics_model
+            .as_ref()
+            .and_then(|name| models.get(name)),
+        timeout: cli.timeout.map(Duration::from_secs),
+        state_path: None,
+    };
+    let report = fanout::run(&targets, prompt, &opts)?;
+
+    if cli.output == OutputFormat::Json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report)
+                .map_err(|e| format!("Failed to serialize result: {e}"))?
+        );
+    } else if report.success || mode == FanoutMode::All {
+        print!("{}", report.output);
+        if !report.output.ends_with('\n') {
+            println!();
+        }
+    } else if let Some(ref judge) = report.judge {
+        eprintln!("{}", judge.stderr);
+    }
+    Ok(if report.success { 0 } else { 1 })
+}
+
 fn run_with_balancing(
-    model: &ModelConfig,
+    models: &[&ModelConfig],
     prompt: &str,
+    agent: Option<&AgentConfig>,
     all_models: &HashMap<String, ModelConfig>,
     working_dir: Option<&Path>,
+    cli: &Cli,
 ) -> Result<i32, String> {
     let state = StateDb::open_default().unwrap_or_else(|e| {
         eprintln!("Warning: Could not open state DB ({e}), running without state tracking.");
         StateDb::open(std::path::Path::new(":memory:")).unwrap()
     });
 
-    let provider_index = balancer::select_provider(model, &state);
-    let result = executor::execute(model, provider_index, prompt, working_dir)?;
-
-    let success = result.exit_code == 0;
-
-    // Run diagnostics on failure
-    let error_category = if !success {
-        run_diagnostics(&result.stderr, result.exit_code, all_models, working_dir)
-    } else {
-        None
+    let app_config = load_app_config();
+    let passthrough = |stream: OutputStream, line: &str| match stream {
+        OutputStream::Stdout => {
+            let mut out = std::io::stdout().lock();
+            let _ = out.write_all(line.as_bytes());
+            let _ = out.flush();
+        }
+        OutputStream::Stderr => {
+            let _ = std::io::stderr().write_all(line.as_bytes());
+        }
+    };
+    let agent_name = agent.map(|a| a.name.as_str());
+    let opts = RunOptions {
+        working_dir,
+        agent_name,
+        run_id: None,
+        cancel: None,
+        diagnostics_model: app_config
+            .diagnostics_model
+            .as_ref()
+            .and_then(|name| all_models.get(name)),
+        models: all_models,
+        timeout: cli.timeout.map(Duration::from_secs),
+        on_output: if cli.stream { Some(&passthrough) } else { None },
+        output_format: agent.and_then(AgentConfig::output_format),
+        output_retry: agent.is_some_and(AgentConfig::output_retry),
     };
 
-    state
-        .record_invocation(
-            &model.name,
-            provider_index,
-            success,
-            result.exit_code,
-            error_category.as_deref(),
-            if success { None } else { Some(&result.stderr) },
-        )
-        .unwrap_or_else(|e| eprintln!("Warning: Failed to record invocation: {e}"));
-
-    if success {
-        print!("{}", result.stdout);
-    } else {
-        eprintln!("{}", result.stderr);
-        if let Some(ref cat) = error_category {
-            eprintln!("[diagnostics: {cat}]");
-        }
+    let outcome = runner::run_with_fallback(models, prompt, &state, &opts)?;
+
+    if cli.output == OutputFormat::Json {
+        let exit_code = outcome.exit_code();
+        let report = outcome.report(agent_name);
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report)
+                .map_err(|e| format!("Failed to serialize result: {e}"))?
+        );
+        return Ok(exit_code);
     }
 
-    Ok(result.exit_code)
-}
-
-fn run_diagnostics(
-    stderr: &str,
-    exit_code: i32,
-    models: &HashMap<String, ModelConfig>,
-    working_dir: Option<&Path>,
-) -> Option<String> {
-    let app_config = load_app_config();
-    let diag_model_name = app_config.diagnostics_model?;
-    let diag_model = models.get(&diag_model_name)?;
-
-    match diagnostics::diagnose_error(stderr, exit_code, diag_model, models, working_dir) {
-        Ok(diagnosis) => {
-            eprintln!(
-                "[diagnostics] {}: {}",
-                diagnosis.category.as_str(),
-                diagnosis.summary
-            );
-            Some(diagnosis.category.as_str().to_string())
+    // In streaming mode the output has already been forwarded.
+    if outcome.success() {
+        if !cli.stream {
+            print!("{}", outcome.result.stdout);
         }
-        Err(e) => {
-            eprintln!("[diagnostics] Failed to diagnose: {e}");
-            None
+    } else if let Some(ref error) = outcome.output_error {
+        if !cli.stream {
+            eprint!("{}", outcome.result.stdout);
+        }
+        eprintln!("[output: {error}]");
+    } else {
+        if !cli.stream {
+            eprintln!("{}", outcome.result.stderr);
+        }
+        // Heuristic diagnoses stay silent; only a diagnostics model's verdict
+        // is worth reporting.
+        if let (Some(diagnosis), Some(_)) = (&outcome.diagnosis, opts.diagnostics_model) {
+            eprintln!("[diagnostics: {}]", diagnosis.category.as_str());
         }
     }
+
+    Ok(outcome.exit_code())
 }
 
 fn main() -> ExitCode {
@@ -0,0 +1,484 @@
+//! A Model Context Protocol server on stdio: each agent is a tool, and each
+//! model an `ask_<model>` tool, so one CLI agent can delegate to others.
+
+use crate::batch::{self, BatchEntry};
+use crate::config::{AgentConfig, ModelConfig};
+use crate::runner::{self, RunOptions, RunReport};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Sender};
+use std::time::Duration;
+
+/// Protocol versions this server speaks, newest first.
+const PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+
+// JSON-RPC error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Longest tool name clients accept.
+const MAX_TOOL_NAME: usize = 64;
+
+/// What the server needs besides its input and output.
+pub struct McpOptions<'a> {
+    pub models: &'a HashMap<String, ModelConfig>,
+    pub agents: &'a HashMap<String, AgentConfig>,
+    pub diagnostics_model: Option<&'a ModelConfig>,
+    pub timeout: Option<Duration>,
+    /// Tool calls run at once.
+    pub workers: usize,
+    /// Base for relative `project` paths and `context` globs; tool calls
+    /// cannot reach outside it.
+    pub base_dir: &'a Path,
+    /// State DB each tool call opens; `None` uses the default location.
+    pub state_path: Option<&'a Path>,
+}
+
+/// What a tool runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target<'a> {
+    Agent(&'a str),
+    Model(&'a str),
+}
+
+#[derive(Debug)]
+struct Tool<'a> {
+    name: String,
+    description: String,
+    target: Target<'a>,
+}
+
+/// Arguments of a `tools/call`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ToolArgs {
+    prompt: String,
+    project: Option<PathBuf>,
+    #[serde(default)]
+    vars: HashMap<String, String>,
+    #[serde(default)]
+    context: Vec<String>,
+}
+
+/// Answer newline-delimited JSON-RPC messages from `input` on `output` until
+/// `input` ends. Tool calls run on `opts.workers` threads; further calls
+/// wait their turn.
+pub fn serve(
+    opts: &McpOptions,
+    input: impl BufRead,
+    output: impl Write + Send,
+) -> Result<(), String> {
+    let tools = tools(opts);
+    let output = Mutex::new(output);
+    let send = |message: Value| {
+        let mut output = output.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writeln!(output, "{message}");
+        let _ = output.flush();
+    };
+    let (calls, queue) = mpsc::channel::<(Value, Value)>();
+    let queue = Mutex::new(queue);
+
+    std::thread::scope(|scope| {
+        for _ in 0..opts.workers.max(1) {
+            scope.spawn(|| {
+                loop {
+                    let call = queue.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                    let Ok((id, params)) = call else {
+                        break;
+                    };
+                    send(call_tool(id, &params, &tools, opts));
+                }
+            });
+        }
+        let read = dispatch(input, &send, &tools, &calls);
+        // Dropping the sender lets the workers finish the queue and exit.
+        drop(calls);
+        read
+    })
+}
+
+/// Answer each message from `input`, queueing tool calls on `calls`.
+fn dispatch(
+    input: impl BufRead,
+    send: &dyn Fn(Value),
+    tools: &[Tool],
+    calls: &Sender<(Value, Value)>,
+) -> Result<(), String> {
+    for line in input.lines() {
+        let line = line.map_err(|e| format!("Failed to read MCP input: {e}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message: Value = match serde_json::from_str(&line) {
+            Ok(message) => message,
+            Err(e) => {
+                send(error_response(Value::Null, PARSE_ERROR, &e.to_string()));
+                continue;
+            }
+        };
+        let Some(method) = message["method"].as_str() else {
+            // Responses to requests we never send are ignored.
+            if !message.is_object() || message.get("result").is_none() {
+                let id = message.get("id").cloned().unwrap_or(Value::Null);
+                send(error_response(id, INVALID_REQUEST, "expected a request"));
+            }
+            continue;
+        };
+        // Notifications (no id) need no answer.
+        let Some(id) = message.get("id").cloned() else {
+            continue;
+        };
+
+        let params = &message["params"];
+        match method {
+            "tools/call" => {
+                let _ = calls.send((id, params.clone()));
+            }
+            "initialize" => send(result_response(id, initialize(params))),
+            "ping" => send(result_response(id, json!({}))),
+            "tools/list" => {
+                let list: Vec<Value> = tools.iter().map(describe).collect();
+                send(result_response(id, json!({ "tools": list })));
+            }
+            other => send(error_response(
+                id,
+                METHOD_NOT_FOUND,
+                &format!("Method not found: {other}"),
+            )),
+        }
+    }
+    Ok(())
+}
+
+fn initialize(params: &Value) -> Value {
+    let requested = params["protocolVersion"].as_str().unwrap_or_default();
+    let version = PROTOCOL_VERSIONS
+        .iter()
+        .find(|v| **v == requested)
+        .unwrap_or(&PROTOCOL_VERSIONS[0]);
+    json!({
+        "protocolVersion": version,
+        "capabilities": { "tools": {} },
+        "serverInfo": { "name": "oulipoly-agent-runner", "version": env!("CARGO_PKG_VERSION") },
+    })
+}
+
+/// Agents, then models, each sorted by name. A name that collides with an
+/// earlier tool once sanitized is skipped with a warning.
+fn tools<'a>(opts: &McpOptions<'a>) -> Vec<Tool<'a>> {
+    let mut agents: Vec<&AgentConfig> = opts.agents.values().collect();
+    agents.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut models: Vec<&String> = opts.models.keys().collect();
+    models.sort();
+
+    let candidates = agents
+        .into_iter()
+        .map(|agent| {
+            let description = match agent.description.trim() {
+                "" => format!("Run the {} agent on a prompt.", agent.name),
+                description => description.to_string(),
+            };
+            (
+                tool_name(&agent.name),
+                description,
+                Target::Agent(agent.name.as_str()),
+            )
+        })
+        .chain(models.into_iter().map(|name| {
+            (
+                tool_name(&format!("ask_{name}")),
+                format!("Ask the {name} model and return its reply."),
+                Target::Model(name.as_str()),
+            )
+        }));
+
+    let mut tools: Vec<Tool> = Vec::new();
+    for (name, description, target) in candidates {
+        if tools.iter().any(|t| t.name == name) {
+            let (kind, source) = match target {
+                Target::Agent(source) => ("agent", source),
+                Target::Model(source) => ("model", source),
+            };
+            eprintln!("[mcp] skipping {kind} '{source}': tool name '{name}' is already taken");
+            continue;
+        }
+        tools.push(Tool {
+            name,
+            description,
+            target,
+        });
+    }
+    tools
+}
+
+/// `name` with characters clients reject replaced by `_`.
+fn tool_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(MAX_TOOL_NAME)
+        .collect()
+}
+
+/// A `tools/list` entry.
+fn describe(tool: &Tool) -> Value {
+    let mut properties = json!({
+        "prompt": { "type": "string", "description": "The task or question" },
+        "project": {
+            "type": "string",
+            "description": "Working directory for the run, relative to the server's",
+        },
+        "context": {
+            "type": "array",
+            "items": { "type": "string" },
+            "description": "Globs (relative to project) of files to attach before the prompt",
+        },
+    });
+    if let Target::Agent(_) = tool.target {
+        properties["vars"] = json!({
+            "type": "object",
+            "additionalProperties": { "type": "string" },
+            "description": "Values for the agent's {{name}} placeholders",
+        });
+    }
+    json!({
+        "name": tool.name,
+        "description": tool.description,
+        "inputSchema": {
+            "type": "object",
+            "properties": properties,
+            "required": ["prompt"],
+            "additionalProperties": false,
+        },
+    })
+}
+
+/// Answer a `tools/call`. A run that fails is a tool result with `isError`,
+/// not a protocol error, so the calling agent sees why.
+fn call_tool(id: Value, params: &Value, tools: &[Tool], opts: &McpOptions) -> Value {
+    let name = params["name"].as_str().unwrap_or_default();
+    let Some(tool) = tools.iter().find(|t| t.name == name) else {
+        return error_response(id, INVALID_PARAMS, &format!("Unknown tool: {name}"));
+    };
+    let arguments = params.get("arguments").cloned().unwrap_or(json!({}));
+    let result = serde_json::from_value::<ToolArgs>(arguments)
+        .map_err(|e| format!("Invalid arguments: {e}"))
+        .and_then(|args| run_tool(tool.target, &args, opts));
+
+    let (text, is_error) = match result {
+        Ok(report) if report.success => (report.stdout.trim_end().to_string(), false),
+        Ok(report) => (failure(&report), true),
+        Err(e) => (e, true),
+    };
+    result_response(
+        id,
+        json!({ "content": [{ "type": "text", "text": text }], "isError": is_error }),
+    )
+}
+
+/// Run a tool through the balancer, as the CLI would.
+fn run_tool(target: Target, args: &ToolArgs, opts: &McpOptions) -> Result<RunReport, String> {
+    let (agent, model) = match target {
+        Target::Agent(name) => (Some(name), None),
+        Target::Model(name) => (None, Some(name)),
+    };
+    let entry = BatchEntry {
+        agent: agent.map(String::from),
+        model: model.map(String::from),
+        prompt: Some(args.prompt.clone()),
+        project: args.project.clone(),
+        vars: args.vars.clone(),
+        context: args.context.clone(),
+        ..Default::default()
+    };
+    batch::confine(&entry, opts.base_dir)?;
+    let (chain, prompt, project) = batch::prepare(
+        &entry,
+        opts.base_dir,
+        opts.agents,
+        opts.models,
+        &HashMap::new(),
+    )?;
+
+    let agent_config = agent.and_then(|name| opts.agents.get(name));
+    let run_opts = RunOptions {
+        working_dir: project.as_deref(),
+        agent_name: agent,
+        run_id: None,
+        cancel: None,
+        diagnostics_model: opts.diagnostics_model,
+        models: opts.models,
+        timeout: opts.timeout,
+        on_output: None,
+        output_format: agent_config.and_then(AgentConfig::output_format),
+        output_retry: agent_config.is_some_and(AgentConfig::output_retry),
+    };
+    let state = batch::open_state(opts.state_path);
+    runner::run_with_fallback(&chain, &prompt, &state, &run_opts)
+        .map(|outcome| outcome.report(agent))
+}
+
+/// Why a run failed, followed by the provider's stderr.
+fn failure(report: &RunReport) -> String {
+    let summary = report
+        .output_error
+        .clone()
+        .or_else(|| report.diagnosis.as_ref().map(|d| d.summary.clone()))
+        .unwrap_or_else(|| format!("{} exited with code {}", report.command, report.exit_code));
+    match report.stderr.trim() {
+        "" => summary,
+        stderr => format!("{summary}\n\n{stderr}"),
+    }
+}
+
+fn result_response(id: Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_support::sh_models;
+
+    #[test]
+    fn lists_and_calls_tools() {
+        let dir = tempfile::tempdir().unwrap();
+        let models = sh_models(&[("echo", "echo \"$0\""), ("gpt.5", "echo boom >&2; exit 3")]);
+        let agents = HashMap::from([(
+            "reviewer".to_string(),
+            AgentConfig {
+                name: "reviewer".to_string(),
+                description: "Reviews code".to_string(),
+                model: "echo".to_string(),
+                instructions: "Review {{file}}: {{prompt}}".to_string(),
+                ..Default::default()
+            },
+        )]);
+        let opts = McpOptions {
+            models: &models,
+            agents: &agents,
+            diagnostics_model: None,
+            timeout: None,
+            workers: 2,
+            base_dir: dir.path(),
+            state_path: Some(&dir.path().join("state.db")),
+        };
+
+        let input = [
+            json!({"jsonrpc": "2.0", "id": 1, "method": "initialize",
+                   "params": {"protocolVersion": "2025-03-26", "capabilities": {}}}),
+            json!({"jsonrpc": "2.0", "method": "notifications/initialized"}),
+            json!({"jsonrpc": "2.0", "id": 2, "method": "tools/list"}),
+            json!({"jsonrpc": "2.0", "id": 3, "method": "tools/call", "params": {
+                "name": "reviewer", "arguments": {"prompt": "be brief", "vars": {"file": "a.rs"}}}}),
+            json!({"jsonrpc": "2.0", "id": 4, "method": "tools/call", "params": {
+                "name": "ask_gpt_5", "arguments": {"prompt": "hi"}}}),
+            json!({"jsonrpc": "2.0", "id": 5, "method": "tools/call", "params": {
+                "name": "ask_echo", "arguments": {"text": "hi"}}}),
+            json!({"jsonrpc": "2.0", "id": 6, "method": "tools/call", "params": {"name": "nope"}}),
+            json!({"jsonrpc": "2.0", "id": 7, "method": "resources/list"}),
+            json!({"jsonrpc": "2.0", "id": 8, "method": "tools/call", "params": {
+                "name": "ask_echo", "arguments": {"prompt": "hi", "project": "/etc"}}}),
+        ]
+        .map(|m| m.to_string())
+        .join("\n");
+
+        let mut output = Vec::new();
+        serve(&opts, input.as_bytes(), &mut output).unwrap();
+        let responses: HashMap<i64, Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .map(|r| (r["id"].as_i64().unwrap(), r))
+            .collect();
+        assert_eq!(responses.len(), 8, "the notification gets no answer");
+
+        assert_eq!(responses[&1]["result"]["protocolVersion"], "2025-03-26");
+        let names: Vec<&str> = responses[&2]["result"]["tools"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["reviewer", "ask_echo", "ask_gpt_5"]);
+
+        let reviewed = &responses[&3]["result"];
+        assert_eq!(reviewed["isError"], false);
+        assert_eq!(reviewed["content"][0]["text"], "Review a.rs: be brief");
+
+        let failed = &responses[&4]["result"];
+        assert_eq!(failed["isError"], true);
+        assert!(
+            failed["content"][0]["text"]
+                .as_str()
+                .unwrap()
+                .contains("boom"),
+            "{failed}"
+        );
+        assert_eq!(responses[&5]["result"]["isError"], true);
+        assert_eq!(responses[&6]["error"]["code"], INVALID_PARAMS);
+        assert_eq!(responses[&7]["error"]["code"], METHOD_NOT_FOUND);
+        let outside = &responses[&8]["result"];
+        assert_eq!(outside["isError"], true);
+        assert!(
+            outside["content"][0]["text"]
+                .as_str()
+                .unwrap()
+                .contains("without '..'"),
+            "{outside}"
+        );
+    }
+
+    #[test]
+    fn queues_tool_calls_beyond_the_worker_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        // Fails if another call holds the lock directory.
+        let script = format!(
+            "mkdir {0} || exit 7; sleep 0.3; rmdir {0}; echo ok",
+            dir.path().join("lock").display()
+        );
+        let models = sh_models(&[("solo", &script)]);
+        let agents = HashMap::new();
+        let opts = McpOptions {
+            models: &models,
+            agents: &agents,
+            diagnostics_model: None,
+            timeout: None,
+            workers: 1,
+            base_dir: dir.path(),
+            state_path: Some(&dir.path().join("state.db")),
+        };
+        let input = (1..=3)
+            .map(|id| {
+                json!({"jsonrpc": "2.0", "id": id, "method": "tools/call", "params": {
+                    "name": "ask_solo", "arguments": {"prompt": "hi"}}})
+                .to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let mut output = Vec::new();
+        serve(&opts, input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.lines().count(), 3);
+        for line in output.lines() {
+            let response: Value = serde_json::from_str(line).unwrap();
+            assert_eq!(response["result"]["isError"], false, "{response}");
+        }
+    }
+}
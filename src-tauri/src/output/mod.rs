@@ -0,0 +1,176 @@
+pub mod schema;
+
+use crate::config::OutputFormat;
+use regex::Regex;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::sync::LazyLock;
+
+static FENCE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)```[ \t]*([\w-]*)[ \t]*\n(.*?)\n?```").unwrap());
+
+/// Check a provider's stdout against `format`, returning the payload to hand
+/// back: the extracted JSON for `json`/`json-schema`, the unwrapped text for
+/// `markdown`.
+pub fn validate(format: &OutputFormat, stdout: &str) -> Result<String, String> {
+    match format {
+        OutputFormat::Json => extract_json(stdout)
+            .map(|(_, payload)| format!("{payload}\n"))
+            .ok_or_else(|| "output is not valid JSON".to_string()),
+        OutputFormat::JsonSchema(path) => {
+            let schema = load_schema(path)?;
+            let (value, payload) =
+                extract_json(stdout).ok_or_else(|| "output is not valid JSON".to_string())?;
+            schema::validate(&schema, &value)
+                .map_err(|e| format!("output does not match {}: {e}", path.display()))?;
+            Ok(format!("{payload}\n"))
+        }
+        OutputFormat::Markdown => {
+            let text = unwrap_fence(stdout, &["markdown", "md"]).trim();
+            if text.is_empty() {
+                Err("output is empty".to_string())
+            } else {
+                Ok(format!("{text}\n"))
+            }
+        }
+    }
+}
+
+/// The prompt for a second attempt after `error`, restating the format.
+pub fn reprompt(prompt: &str, format: &OutputFormat, error: &str) -> Result<String, String> {
+    let requirement = match format {
+        OutputFormat::Json => "Respond with a single JSON value and nothing else.".to_string(),
+        OutputFormat::JsonSchema(path) => {
+            let schema = load_schema(path)?;
+            format!(
+                "Respond with a single JSON value matching this JSON schema and nothing else:\n\n{}",
+                serde_json::to_string_pretty(&schema).unwrap_or_default()
+            )
+        }
+        OutputFormat::Markdown => "Respond with a non-empty Markdown document.".to_string(),
+    };
+    Ok(format!(
+        "{prompt}\n\nYour previous response could not be used ({error}). {requirement}"
+    ))
+}
+
+/// Find the JSON payload in `text`: the whole output, the first fenced block
+/// that parses, or the span from the first `{`/`[` to the last `}`/`]`.
+pub fn extract_json(text: &str) -> Option<(Value, &str)> {
+    let parse = |s: &str| serde_json::from_str::<Value>(s).ok();
+
+    let trimmed = text.trim();
+    if let Some(value) = parse(trimmed) {
+        return Some((value, trimmed));
+    }
+
+    for caps in FENCE_RE.captures_iter(text) {
+        let body = caps.get(2).unwrap().as_str().trim();
+        if let Some(value) = parse(body) {
+            return Some((value, body));
+        }
+    }
+
+    for (open, close) in [('{', '}'), ('[', ']')] {
+        if let (Some(start), Some(end)) = (text.find(open), text.rfind(close))
+            && start < end
+        {
+            let span = &text[start..=end];
+            if let Some(value) = parse(span) {
+                return Some((value, span));
+            }
+        }
+    }
+    None
+}
+
+/// Strip a fence around the whole output when its language is one of `langs`
+/// (or unset).
+fn unwrap_fence<'t>(text: &'t str, langs: &[&str]) -> &'t str {
+    let trimmed = text.trim();
+    if let Some(caps) = FENCE_RE.captures(trimmed) {
+        let whole = caps.get(0).unwrap();
+        let lang = &caps[1];
+        if whole.start() == 0
+            && whole.end() == trimmed.len()
+            && (lang.is_empty() || langs.contains(&lang))
+        {
+            return caps.get(2).unwrap().as_str();
+        }
+    }
+    trimmed
+}
+
+fn load_schema(path: &Path) -> Result<Value, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read JSON schema {}: {e}", path.display()))?;
+    serde_json::from_str(&content)
+        .map_err(|e| format!("Invalid JSON schema {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_json_from_wrapped_output() {
+        let (value, payload) = extract_json("  {\"a\": 1}\n").unwrap();
+        assert_eq!(value["a"], 1);
+        assert_eq!(payload, "{\"a\": 1}");
+
+        let fenced = "Here you go:\n\n```json\n{\"a\": [1, 2]}\n```\n\nAnything else?";
+        assert_eq!(extract_json(fenced).unwrap().1, "{\"a\": [1, 2]}");
+
+        let chatty = "Sure! The result is {\"ok\": true} as requested.";
+        assert_eq!(extract_json(chatty).unwrap().1, "{\"ok\": true}");
+
+        assert_eq!(
+            extract_json("Items: [1, 2, 3].")
+                .unwrap()
+                .0
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+        assert!(extract_json("no json {here").is_none());
+    }
+
+    #[test]
+    fn validates_against_schema_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("schema.json");
+        fs::write(
+            &path,
+            r#"{"type": "object", "required": ["verdict"], "properties": {"verdict": {"type": "string"}}}"#,
+        )
+        .unwrap();
+        let format = OutputFormat::JsonSchema(path);
+
+        assert_eq!(
+            validate(&format, "```\n{\"verdict\": \"ok\"}\n```").unwrap(),
+            "{\"verdict\": \"ok\"}\n"
+        );
+        let err = validate(&format, "{\"verdict\": 1}").unwrap_err();
+        assert!(err.contains("$.verdict: expected string"), "{err}");
+        assert!(validate(&format, "not json").is_err());
+
+        let retry = reprompt("Review it.", &format, &err).unwrap();
+        assert!(retry.starts_with("Review it.\n\nYour previous response could not be used"));
+        assert!(retry.contains("\"required\""));
+    }
+
+    #[test]
+    fn validates_markdown() {
+        assert_eq!(
+            validate(&OutputFormat::Markdown, "```markdown\n# Title\n```").unwrap(),
+            "# Title\n"
+        );
+        assert_eq!(
+            validate(&OutputFormat::Markdown, "```rust\nfn x() {}\n```").unwrap(),
+            "```rust\nfn x() {}\n```\n"
+        );
+        assert!(validate(&OutputFormat::Markdown, "  \n").is_err());
+    }
+}
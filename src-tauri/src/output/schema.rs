@@ -0,0 +1,233 @@
+use serde_json::Value;
+
+/// Check `value` against a JSON Schema, returning the first violation.
+///
+/// Covers the keywords agents use to describe their output: `type`, `enum`,
+/// `const`, `properties`, `required`, `additionalProperties`, `items`,
+/// `minItems`/`maxItems`, `minLength`/`maxLength`, `minimum`/`maximum`,
+/// `anyOf`, `oneOf` and `allOf`. Unknown keywords are ignored.
+pub fn validate(schema: &Value, value: &Value) -> Result<(), String> {
+    check(schema, value, "$")
+}
+
+fn check(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return Err(format!("{path}: not allowed")),
+        Value::Object(schema) => schema,
+        _ => return Err(format!("{path}: schema must be an object or boolean")),
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let types: Vec<&str> = match expected {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|t| has_type(value, t)) {
+            return Err(format!(
+                "{path}: expected {}, got {}",
+                types.join(" or "),
+                type_name(value)
+            ));
+        }
+    }
+
+    if let Some(Value::Array(options)) = schema.get("enum")
+        && !options.contains(value)
+    {
+        return Err(format!(
+            "{path}: {value} is not one of {}",
+            Value::Array(options.clone())
+        ));
+    }
+    if let Some(expected) = schema.get("const")
+        && expected != value
+    {
+        return Err(format!("{path}: expected {expected}"));
+    }
+
+    if let Value::Object(map) = value {
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for key in required.iter().filter_map(Value::as_str) {
+                if !map.contains_key(key) {
+                    return Err(format!("{path}: missing required property '{key}'"));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (key, item) in map {
+            let item_path = format!("{path}.{key}");
+            match properties.and_then(|p| p.get(key)) {
+                Some(sub) => check(sub, item, &item_path)?,
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        return Err(format!("{path}: unexpected property '{key}'"));
+                    }
+                    Some(sub @ Value::Object(_)) => check(sub, item, &item_path)?,
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    if let Value::Array(items) = value {
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64)
+            && (items.len() as u64) < min
+        {
+            return Err(format!("{path}: expected at least {min} items"));
+        }
+        if let Some(max) = schema.get("maxItems").and_then(Value::as_u64)
+            && (items.len() as u64) > max
+        {
+            return Err(format!("{path}: expected at most {max} items"));
+        }
+        if let Some(sub) = schema.get("items") {
+            for (i, item) in items.iter().enumerate() {
+                check(sub, item, &format!("{path}[{i}]"))?;
+            }
+        }
+    }
+
+    if let Value::String(s) = value {
+        let len = s.chars().count() as u64;
+        if let Some(min) = schema.get("minLength").and_then(Value::as_u64)
+            && len < min
+        {
+            return Err(format!("{path}: expected at least {min} characters"));
+        }
+        if let Some(max) = schema.get("maxLength").and_then(Value::as_u64)
+            && len > max
+        {
+            return Err(format!("{path}: expected at most {max} characters"));
+        }
+    }
+
+    if let Some(n) = value.as_f64() {
+        if let Some(min) = schema.get("minimum").and_then(Value::as_f64)
+            && n < min
+        {
+            return Err(format!("{path}: {n} is less than {min}"));
+        }
+        if let Some(max) = schema.get("maximum").and_then(Value::as_f64)
+            && n > max
+        {
+            return Err(format!("{path}: {n} is greater than {max}"));
+        }
+    }
+
+    if let Some(Value::Array(all)) = schema.get("allOf") {
+        for sub in all {
+            check(sub, value, path)?;
+        }
+    }
+    if let Some(Value::Array(any)) = schema.get("anyOf")
+        && !any.iter().any(|sub| check(sub, value, path).is_ok())
+    {
+        return Err(format!("{path}: does not match any of the allowed schemas"));
+    }
+    if let Some(Value::Array(one)) = schema.get("oneOf") {
+        let matches = one
+            .iter()
+            .filter(|sub| check(sub, value, path).is_ok())
+            .count();
+        if matches != 1 {
+            return Err(format!(
+                "{path}: expected to match exactly one schema, matched {matches}"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn has_type(value: &Value, t: &str) -> bool {
+    match t {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        _ => false,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn validates_objects() {
+        let schema = json!({
+            "type": "object",
+            "required": ["verdict", "issues"],
+            "additionalProperties": false,
+            "properties": {
+                "verdict": {"enum": ["pass", "fail"]},
+                "issues": {"type": "array", "items": {"type": "string", "minLength": 1}},
+                "score": {"type": "integer", "minimum": 0, "maximum": 10}
+            }
+        });
+
+        assert!(validate(&schema, &json!({"verdict": "pass", "issues": []})).is_ok());
+        assert!(
+            validate(
+                &schema,
+                &json!({"verdict": "fail", "issues": ["x"], "score": 3})
+            )
+            .is_ok()
+        );
+
+        let err = validate(&schema, &json!({"verdict": "pass"})).unwrap_err();
+        assert!(err.contains("missing required property 'issues'"), "{err}");
+        let err = validate(&schema, &json!({"verdict": "meh", "issues": []})).unwrap_err();
+        assert!(err.starts_with("$.verdict"), "{err}");
+        let err = validate(&schema, &json!({"verdict": "pass", "issues": [""]})).unwrap_err();
+        assert!(err.starts_with("$.issues[0]"), "{err}");
+        let err = validate(&schema, &json!({"verdict": "pass", "issues": [], "x": 1})).unwrap_err();
+        assert!(err.contains("unexpected property 'x'"), "{err}");
+        let err = validate(
+            &schema,
+            &json!({"verdict": "pass", "issues": [], "score": 11}),
+        )
+        .unwrap_err();
+        assert!(err.contains("greater than"), "{err}");
+    }
+
+    #[test]
+    fn validates_types_and_combinators() {
+        assert!(validate(&json!({"type": ["string", "null"]}), &json!(null)).is_ok());
+        assert!(validate(&json!({"type": "integer"}), &json!(1.5)).is_err());
+        assert!(
+            validate(
+                &json!({"anyOf": [{"type": "string"}, {"type": "number"}]}),
+                &json!(2)
+            )
+            .is_ok()
+        );
+        assert!(
+            validate(
+                &json!({"oneOf": [{"type": "number"}, {"type": "integer"}]}),
+                &json!(2)
+            )
+            .is_err()
+        );
+        assert!(validate(&json!(false), &json!(1)).is_err());
+    }
+}
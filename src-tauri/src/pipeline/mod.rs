@@ -0,0 +1,651 @@
+//! Multi-step pipelines: each step runs an agent or model on a prompt built
+//! from the pipeline's input and earlier steps' outputs.
+
+use crate::batch::{self, BatchEntry};
+use crate::config::{
+    AgentConfig, ModelConfig, TemplateContext, render_template, template_placeholders,
+};
+use crate::output;
+use crate::runner::{self, RunOptions, RunReport};
+use crate::state::StateDb;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+static STEP_ID_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[A-Za-z_][\w-]*$").unwrap());
+
+/// A step's input when it does not give one.
+const DEFAULT_INPUT: &str = "{{input}}";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pipeline {
+    /// Template variables for every step, overridden by `--var`.
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    pub steps: Vec<Step>,
+    /// Base for relative step `project` paths: the pipeline file's directory.
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    pub id: String,
+    pub agent: Option<String>,
+    /// Model to run; overrides the agent's model when both are given.
+    pub model: Option<String>,
+    /// Template for the step's prompt (default `{{input}}`).
+    pub input: Option<String>,
+    /// Working directory (relative to the pipeline file).
+    pub project: Option<PathBuf>,
+    /// Template variables for the agent; values are templates themselves.
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    #[serde(default)]
+    pub context: Vec<String>,
+    /// Run only when every condition holds; otherwise the step is skipped.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub when: Vec<Condition>,
+    /// Go on to the next step when this one fails.
+    #[serde(default)]
+    pub continue_on_error: bool,
+}
+
+/// A test on an earlier step's result. Every field given must match; a
+/// skipped step matches nothing.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    pub step: String,
+    pub success: Option<bool>,
+    pub exit_code: Option<i32>,
+    /// Dotted path into the JSON in the step's output, e.g. `review.verdict`
+    /// or `issues.0`. Without `equals` the field must be present and not
+    /// `null` or `false`.
+    pub json: Option<String>,
+    pub equals: Option<Value>,
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Condition>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Condition),
+        Many(Vec<Condition>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(condition) => vec![condition],
+        OneOrMany::Many(conditions) => conditions,
+    })
+}
+
+pub struct PipelineOptions<'a> {
+    /// Overrides the pipeline's `vars`.
+    pub vars: &'a HashMap<String, String>,
+    pub models: &'a HashMap<String, ModelConfig>,
+    pub agents: &'a HashMap<String, AgentConfig>,
+    /// Working directory for steps without a `project`.
+    pub project: Option<&'a Path>,
+    pub diagnostics_model: Option<&'a ModelConfig>,
+    pub timeout: Option<Duration>,
+    /// `None` uses the default state DB location.
+    pub state_path: Option<&'a Path>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Succeeded,
+    Failed,
+    Skipped,
+}
+
+impl StepStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StepStatus::Succeeded => "succeeded",
+            StepStatus::Failed => "failed",
+            StepStatus::Skipped => "skipped",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StepResult {
+    pub id: String,
+    pub status: StepStatus,
+    /// `None` when the step was skipped or could not be started.
+    pub report: Option<RunReport>,
+    /// Why the step could not be started (e.g. a template error).
+    pub error: Option<String>,
+    /// The JSON value found in the step's stdout, if any.
+    #[serde(skip)]
+    json: Option<Value>,
+}
+
+impl StepResult {
+    pub fn stdout(&self) -> &str {
+        self.report.as_ref().map_or("", |r| r.stdout.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PipelineReport {
+    /// Recorded with every invocation of the run in the state DB.
+    pub run_id: String,
+    /// Every step ran, or failed with `continue_on_error`.
+    pub success: bool,
+    /// The steps that ran or were skipped, in order. Steps after a failure
+    /// that stopped the pipeline are absent.
+    pub steps: Vec<StepResult>,
+}
+
+impl PipelineReport {
+    /// The last step that was not skipped.
+    pub fn last_run(&self) -> Option<&StepResult> {
+        self.steps
+            .iter()
+            .rev()
+            .find(|s| s.status != StepStatus::Skipped)
+    }
+}
+
+/// Load a pipeline from a `.toml`, `.yaml` or `.yml` file and validate it
+/// against the configured agents and models.
+pub fn load(
+    path: &Path,
+    agents: &HashMap<String, AgentConfig>,
+    models: &HashMap<String, ModelConfig>,
+) -> Result<Pipeline, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read pipeline {}: {e}", path.display()))?;
+    let mut pipeline: Pipeline = match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str(&content)
+            .map_err(|e| format!("Pipeline {}: TOML parse error: {e}", path.display()))?,
+        Some("yaml" | "yml") => serde_yml::from_str(&content)
+            .map_err(|e| format!("Pipeline {}: YAML parse error: {e}", path.display()))?,
+        _ => {
+            return Err(format!(
+                "Pipeline {}: expected a .toml, .yaml or .yml file",
+                path.display()
+            ));
+        }
+    };
+    pipeline.base_dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+    validate(&pipeline, agents, models).map_err(|e| format!("Pipeline {}: {e}", path.display()))?;
+    Ok(pipeline)
+}
+
+/// Check ids, that every agent and model a step names exists, and that
+/// conditions and templates only refer to earlier steps.
+pub fn validate(
+    pipeline: &Pipeline,
+    agents: &HashMap<String, AgentConfig>,
+    models: &HashMap<String, ModelConfig>,
+) -> Result<(), String> {
+    if pipeline.steps.is_empty() {
+        return Err("has no steps".to_string());
+    }
+    let mut earlier = HashSet::new();
+    for step in &pipeline.steps {
+        let id = &step.id;
+        if !STEP_ID_RE.is_match(id) {
+            return Err(format!(
+                "step id '{id}' must be letters, digits, '_' or '-', not starting with a digit"
+            ));
+        }
+        if earlier.contains(id.as_str()) {
+            return Err(format!("duplicate step id '{id}'"));
+        }
+        if step.agent.is_none() && step.model.is_none() {
+            return Err(format!("step '{id}' needs an agent or a model"));
+        }
+        let agent = step
+            .agent
+            .as_ref()
+            .map(|name| {
+                agents
+                    .get(name)
+                    .ok_or_else(|| format!("step '{id}': unknown agent '{name}'"))
+            })
+            .transpose()?;
+        let names = match (&step.model, agent) {
+            (Some(model), _) => vec![model.as_str()],
+            (None, Some(agent)) => agent.model_chain(),
+            (None, None) => Vec::new(),
+        };
+        if let Some(name) = names.into_iter().find(|name| !models.contains_key(*name)) {
+            return Err(format!("step '{id}': unknown model '{name}'"));
+        }
+        for condition in &step.when {
+            if !earlier.contains(condition.step.as_str()) {
+                return Err(format!(
+                    "step '{id}': condition refers to '{}', which is not an earlier step",
+                    condition.step
+                ));
+            }
+            if condition.equals.is_some() && condition.json.is_none() {
+                return Err(format!("step '{id}': 'equals' needs 'json'"));
+            }
+        }
+        let templates = step.input.iter().chain(step.vars.values());
+        for template in templates {
+            let refs = template_placeholders(template)
+                .filter_map(|name| name.strip_prefix("steps."))
+                .map(|rest| rest.split('.').next().unwrap_or(rest));
+            for step_ref in refs {
+                if !earlier.contains(step_ref) {
+                    return Err(format!(
+                        "step '{id}' uses the output of '{step_ref}', which is not an earlier step"
+                    ));
+                }
+            }
+        }
+        earlier.insert(id.as_str());
+    }
+    Ok(())
+}
+
+/// Run the steps in order under a fresh run id. A failed step stops the
+/// pipeline unless it has `continue_on_error`.
+pub fn run(
+    pipeline: &Pipeline,
+    input: &str,
+    opts: &PipelineOptions,
+    on_step: &dyn Fn(&StepResult),
+) -> PipelineReport {
+    let run_id = uuid::Uuid::new_v4().to_string();
+    let state = batch::open_state(opts.state_path);
+    let mut steps: Vec<StepResult> = Vec::new();
+    let mut success = true;
+
+    for step in &pipeline.steps {
+        let result = if step.when.iter().all(|c| holds(c, &steps)) {
+            run_step(step, pipeline, input, &steps, &run_id, &state, opts)
+        } else {
+            StepResult {
+                id: step.id.clone(),
+                status: StepStatus::Skipped,
+                report: None,
+                error: None,
+                json: None,
+            }
+        };
+        on_step(&result);
+        let stop = result.status == StepStatus::Failed && !step.continue_on_error;
+        steps.push(result);
+        if stop {
+            success = false;
+            break;
+        }
+    }
+
+    PipelineReport {
+        run_id,
+        success,
+        steps,
+    }
+}
+
+fn run_step(
+    step: &Step,
+    pipeline: &Pipeline,
+    input: &str,
+    earlier: &[StepResult],
+    run_id: &str,
+    state: &StateDb,
+    opts: &PipelineOptions,
+) -> StepResult {
+    let report =
+        prepare_step(step, pipeline, input, earlier, opts).and_then(|(chain, prompt, project)| {
+            let agent = step.agent.as_ref().and_then(|name| opts.agents.get(name));
+            let run_opts = RunOptions {
+                working_dir: project.as_deref(),
+                agent_name: step.agent.as_deref(),
+                run_id: Some(run_id),
+                cancel: None,
+                diagnostics_model: opts.diagnostics_model,
+                models: opts.models,
+                timeout: opts.timeout,
+                on_output: None,
+                output_format: agent.and_then(AgentConfig::output_format),
+                output_retry: agent.is_some_and(AgentConfig::output_retry),
+            };
+            runner::run_with_fallback(&chain, &prompt, state, &run_opts)
+                .map(|outcome| outcome.report(step.agent.as_deref()))
+        });
+
+    match report {
+        Ok(report) => StepResult {
+            id: step.id.clone(),
+            status: if report.success {
+                StepStatus::Succeeded
+            } else {
+                StepStatus::Failed
+            },
+            json: output::extract_json(&report.stdout).map(|(value, _)| value),
+            report: Some(report),
+            error: None,
+        },
+        Err(e) => StepResult {
+            id: step.id.clone(),
+            status: StepStatus::Failed,
+            report: None,
+            error: Some(e),
+            json: None,
+        },
+    }
+}
+
+/// Render the step's templates and resolve it like a batch entry.
+fn prepare_step<'a>(
+    step: &Step,
+    pipeline: &Pipeline,
+    input: &str,
+    earlier: &[StepResult],
+    opts: &PipelineOptions<'a>,
+) -> Result<(Vec<&'a ModelConfig>, String, Option<PathBuf>), String> {
+    let mut vars = pipeline.vars.clone();
+    vars.extend(opts.vars.clone());
+    vars.insert("input".to_string(), input.to_string());
+    for result in earlier {
+        step_vars(result, &mut vars);
+    }
+    let ctx = TemplateContext {
+        prompt: input.to_string(),
+        project_dir: None,
+        vars,
+    };
+    let render = |template: &str| {
+        render_template(template, &ctx, &HashMap::new())
+            .map_err(|e| format!("Step '{}': {e}", step.id))
+    };
+
+    let prompt = render(step.input.as_deref().unwrap_or(DEFAULT_INPUT))?;
+    let step_vars = step
+        .vars
+        .iter()
+        .map(|(key, template)| Ok((key.clone(), render(template)?)))
+        .collect::<Result<HashMap<_, _>, String>>()?;
+    let entry = BatchEntry {
+        agent: step.agent.clone(),
+        model: step.model.clone(),
+        prompt: Some(prompt),
+        project: step
+            .project
+            .clone()
+            .or_else(|| opts.project.map(Path::to_path_buf)),
+        vars: step_vars,
+        context: step.context.clone(),
+        ..Default::default()
+    };
+    // The agent sees the pipeline's vars too, under its own.
+    batch::prepare(
+        &entry,
+        &pipeline.base_dir,
+        opts.agents,
+        opts.models,
+        &ctx.vars,
+    )
+}
+
+/// Expose an earlier step as `steps.<id>.output`, `.status`, `.success`,
+/// `.exit_code` and `.json.<path>`.
+fn step_vars(result: &StepResult, vars: &mut HashMap<String, String>) {
+    let prefix = format!("steps.{}", result.id);
+    let exit_code = result
+        .report
+        .as_ref()
+        .map(|r| r.exit_code.to_string())
+        .unwrap_or_default();
+    vars.insert(
+        format!("{prefix}.output"),
+        result.stdout().trim_end().to_string(),
+    );
+    vars.insert(
+        format!("{prefix}.status"),
+        result.status.as_str().to_string(),
+    );
+    vars.insert(
+        format!("{prefix}.success"),
+        (result.status == StepStatus::Succeeded).to_string(),
+    );
+    vars.insert(format!("{prefix}.exit_code"), exit_code);
+    if let Some(ref json) = result.json {
+        json_vars(&format!("{prefix}.json"), json, vars);
+    }
+}
+
+fn json_vars(name: &str, value: &Value, vars: &mut HashMap<String, String>) {
+    let text = match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    vars.insert(name.to_string(), text);
+    match value {
+        Value::Object(fields) => {
+            for (key, field) in fields {
+                json_vars(&format!("{name}.{key}"), field, vars);
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                json_vars(&format!("{name}.{i}"), item, vars);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn holds(condition: &Condition, earlier: &[StepResult]) -> bool {
+    let Some(result) = earlier.iter().find(|r| r.id == condition.step) else {
+        return false;
+    };
+    if result.status == StepStatus::Skipped {
+        return false;
+    }
+    if let Some(success) = condition.success
+        && success != (result.status == StepStatus::Succeeded)
+    {
+        return false;
+    }
+    if let Some(exit_code) = condition.exit_code
+        && result.report.as_ref().map(|r| r.exit_code) != Some(exit_code)
+    {
+        return false;
+    }
+    match condition.json {
+        Some(ref path) => {
+            let field = result.json.as_ref().and_then(|json| json_path(json, path));
+            match (&condition.equals, field) {
+                (Some(expected), Some(field)) => field == expected,
+                (None, Some(field)) => !matches!(field, Value::Null | Value::Bool(false)),
+                (_, None) => false,
+            }
+        }
+        None => true,
+    }
+}
+
+fn json_path<'v>(value: &'v Value, path: &str) -> Option<&'v Value> {
+    path.split('.').try_fold(value, |value, key| match value {
+        Value::Object(fields) => fields.get(key),
+        Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+        _ => None,
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_support::sh_models;
+
+    fn parse(
+        toml: &str,
+        agents: &HashMap<String, AgentConfig>,
+        models: &HashMap<String, ModelConfig>,
+    ) -> Pipeline {
+        let pipeline: Pipeline = toml::from_str(toml).unwrap();
+        validate(&pipeline, agents, models).unwrap();
+        pipeline
+    }
+
+    #[test]
+    fn chains_steps_and_links_invocations() {
+        let dir = tempfile::tempdir().unwrap();
+        let models = sh_models(&[
+            ("echo", "echo \"$0\""),
+            ("judge", r#"echo '{"verdict": "approve", "score": 9}'"#),
+            ("broken", "echo nope >&2; exit 2"),
+        ]);
+        let agents = HashMap::from([(
+            "planner".to_string(),
+            AgentConfig {
+                name: "planner".to_string(),
+                model: "echo".to_string(),
+                instructions: "Plan ({{style}}): {{prompt}}".to_string(),
+                ..Default::default()
+            },
+        )]);
+        let pipeline = parse(
+            r#"
+            vars = { style = "terse" }
+
+            [[steps]]
+            id = "plan"
+            agent = "planner"
+
+            [[steps]]
+            id = "review"
+            model = "judge"
+            input = "Review: {{steps.plan.output}}"
+
+            [[steps]]
+            id = "fix"
+            model = "echo"
+            input = "Fix it"
+            when = { step = "review", json = "verdict", equals = "changes_requested" }
+
+            [[steps]]
+            id = "ship"
+            model = "echo"
+            input = "Ship {{input}} scored {{steps.review.json.score}}; fix {{steps.fix.status}}"
+            when = [{ step = "review", success = true }, { step = "review", json = "score" }]
+
+            [[steps]]
+            id = "flaky"
+            model = "broken"
+            continue_on_error = true
+
+            [[steps]]
+            id = "after"
+            model = "echo"
+            input = "{{steps.flaky.status}} {{steps.flaky.exit_code}}"
+
+            [[steps]]
+            id = "fail"
+            model = "broken"
+
+            [[steps]]
+            id = "never"
+            model = "echo"
+            "#,
+            &agents,
+            &models,
+        );
+        let state_path = dir.path().join("state.db");
+        let opts = PipelineOptions {
+            vars: &HashMap::new(),
+            models: &models,
+            agents: &agents,
+            project: None,
+            diagnostics_model: None,
+            timeout: None,
+            state_path: Some(&state_path),
+        };
+
+        let report = run(&pipeline, "v2", &opts, &|_| {});
+        let statuses: Vec<(&str, StepStatus)> = report
+            .steps
+            .iter()
+            .map(|s| (s.id.as_str(), s.status))
+            .collect();
+        use StepStatus::*;
+        assert_eq!(
+            statuses,
+            vec![
+                ("plan", Succeeded),
+                ("review", Succeeded),
+                ("fix", Skipped),
+                ("ship", Succeeded),
+                ("flaky", Failed),
+                ("after", Succeeded),
+                ("fail", Failed),
+            ]
+        );
+        assert!(!report.success);
+        assert_eq!(report.steps[0].stdout(), "Plan (terse): v2\n");
+        assert_eq!(report.steps[3].stdout(), "Ship v2 scored 9; fix skipped\n");
+        assert_eq!(report.steps[5].stdout(), "failed 2\n");
+        assert_eq!(report.last_run().unwrap().id, "fail");
+
+        let state = StateDb::open(&state_path).unwrap();
+        let invocations = state.run_invocations(&report.run_id).unwrap();
+        let models: Vec<&str> = invocations.iter().map(|i| i.model_name.as_str()).collect();
+        assert_eq!(
+            models,
+            vec!["echo", "judge", "echo", "broken", "echo", "broken"]
+        );
+        assert_eq!(invocations[0].agent_name.as_deref(), Some("planner"));
+    }
+
+    #[test]
+    fn rejects_invalid_pipelines() {
+        let models = sh_models(&[("m", "true")]);
+        let agents = HashMap::from([(
+            "writer".to_string(),
+            AgentConfig {
+                name: "writer".to_string(),
+                model: "gone".to_string(),
+                ..Default::default()
+            },
+        )]);
+        let invalid = |toml: &str| {
+            validate(&toml::from_str::<Pipeline>(toml).unwrap(), &agents, &models).unwrap_err()
+        };
+
+        let err =
+            invalid("[[steps]]\nid = \"a\"\nmodel = \"m\"\n[[steps]]\nid = \"a\"\nmodel = \"m\"");
+        assert!(err.contains("duplicate step id 'a'"), "{err}");
+        let err = invalid("[[steps]]\nid = \"a\"");
+        assert!(err.contains("needs an agent or a model"), "{err}");
+        let err = invalid(
+            "[[steps]]\nid = \"a\"\nmodel = \"m\"\ninput = \"{{steps.b.output}}\"\n[[steps]]\nid = \"b\"\nmodel = \"m\"",
+        );
+        assert!(err.contains("not an earlier step"), "{err}");
+        let err = invalid("[[steps]]\nid = \"a\"\nmodel = \"m\"\nwhen = { step = \"a\" }");
+        assert!(err.contains("not an earlier step"), "{err}");
+        let err = invalid("[[steps]]\nid = \"a\"\nmodel = \"nope\"");
+        assert!(err.contains("step 'a': unknown model 'nope'"), "{err}");
+        let err = invalid("[[steps]]\nid = \"a\"\nagent = \"nobody\"");
+        assert!(err.contains("step 'a': unknown agent 'nobody'"), "{err}");
+        let err = invalid("[[steps]]\nid = \"a\"\nagent = \"writer\"");
+        assert!(err.contains("step 'a': unknown model 'gone'"), "{err}");
+        assert!(
+            validate(
+                &toml::from_str("[[steps]]\nid = \"a\"\nagent = \"writer\"\nmodel = \"m\"")
+                    .unwrap(),
+                &agents,
+                &models
+            )
+            .is_ok()
+        );
+    }
+}
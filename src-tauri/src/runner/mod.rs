@@ -0,0 +1,986 @@
+use crate::balancer::{self, cooldown};
+use crate::config::{ModelConfig, OutputFormat};
+use crate::diagnostics::{self, Diagnosis, ErrorCategory};
+use crate::executor::{self, ExecOptions, ExecutionResult, ExecutionStatus, OutputSink};
+use crate::output;
+use crate::state::{NewInvocation, StateDb};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a run waiting for a free provider slot checks again.
+const SLOT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Everything a run needs besides the model and prompt.
+pub struct RunOptions<'a> {
+    pub working_dir: Option<&'a Path>,
+    /// Recorded with each invocation when the prompt came from an agent.
+    pub agent_name: Option<&'a str>,
+    /// Recorded with each invocation to link the steps of a pipeline run.
+    pub run_id: Option<&'a str>,
+    /// Kills the running provider once set (see [`ExecOptions::cancel`]).
+    pub cancel: Option<&'a AtomicBool>,
+    /// Model used to classify failures. Falls back to keyword heuristics when unset.
+    pub diagnostics_model: Option<&'a ModelConfig>,
+    pub models: &'a HashMap<String, ModelConfig>,
+    /// Overrides every provider/model timeout when set.
+    pub timeout: Option<Duration>,
+    /// Streams provider output as it arrives (see [`ExecOptions::on_output`]).
+    pub on_output: Option<OutputSink<'a>>,
+    /// Required shape of a successful run's stdout (an agent's `output_format`).
+    pub output_format: Option<&'a OutputFormat>,
+    /// Re-prompt once, quoting the validation error, when stdout does not
+    /// match `output_format`.
+    pub output_retry: bool,
+}
+
+/// One provider attempt within a run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attempt {
+    /// Empty in results written before agents had fallback models.
+    #[serde(default)]
+    pub model: String,
+    pub provider_index: usize,
+    pub command: String,
+    pub exit_code: i32,
+    pub error_category: Option<ErrorCategory>,
+    /// Missing from results written before attempts were timed.
+    #[serde(default)]
+    pub duration_ms: u64,
+    /// Stopped through [`RunOptions::cancel`] rather than failing.
+    #[serde(default)]
+    pub cancelled: bool,
+}
+
+/// A fall-through from one model of an agent's list to the next.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelHop {
+    pub from: String,
+    pub to: String,
+    /// `cooldown`, `saturated`, or the error category that ended the run.
+    pub reason: String,
+}
+
+/// The final result of a run plus every attempt that led to it.
+pub struct RunOutcome {
+    /// The model that produced `result`.
+    pub model: String,
+    pub result: ExecutionResult,
+    /// Diagnosis of the final attempt, if it failed.
+    pub diagnosis: Option<Diagnosis>,
+    pub attempts: Vec<Attempt>,
+    /// Why the provider's stdout did not match the requested output format.
+    /// When `None`, `result.stdout` holds the validated payload.
+    pub output_error: Option<String>,
+    /// Models given up on before `model`, in order.
+    pub hops: Vec<ModelHop>,
+}
+
+/// A run's outcome as a single machine-readable record.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunReport {
+    /// The model that produced the result (the last one tried).
+    pub model: String,
+    pub agent: Option<String>,
+    pub success: bool,
+    pub provider_index: usize,
+    pub command: String,
+    pub exit_code: i32,
+    pub timed_out: bool,
+    pub stdout: String,
+    pub stderr: String,
+    /// Time spent in providers, summed over all attempts.
+    pub duration_ms: u64,
+    pub diagnosis: Option<Diagnosis>,
+    pub attempts: Vec<Attempt>,
+    pub output_error: Option<String>,
+    pub hops: Vec<ModelHop>,
+}
+
+impl RunOutcome {
+    pub fn success(&self) -> bool {
+        self.provider_succeeded() && self.output_error.is_none()
+    }
+
+    /// The provider's exit code, or 1 when it exited cleanly but its output
+    /// did not match the requested format.
+    pub fn exit_code(&self) -> i32 {
+        match self.result.exit_code {
+            0 if self.output_error.is_some() => 1,
+            code => code,
+        }
+    }
+
+    fn provider_succeeded(&self) -> bool {
+        self.result.exit_code == 0 && !self.result.timed_out()
+    }
+
+    pub fn report(self, agent_name: Option<&str>) -> RunReport {
+        RunReport {
+            agent: agent_name.map(String::from),
+            success: self.success(),
+            provider_index: self.result.provider_index,
+            command: self
+                .attempts
+                .last()
+                .map(|a| a.command.clone())
+                .unwrap_or_default(),
+            exit_code: self.result.exit_code,
+            timed_out: self.result.timed_out(),
+            stdout: self.result.stdout,
+            stderr: self.result.stderr,
+            duration_ms: self.attempts.iter().map(|a| a.duration_ms).sum(),
+            diagnosis: self.diagnosis,
+            attempts: self.attempts,
+            output_error: self.output_error,
+            model: self.model,
+            hops: self.hops,
+        }
+    }
+}
+
+/// Run `prompt` on each of `models` in turn until one is usable, as for an
+/// agent's `models:` list.
+///
+/// A model is skipped when every provider is cooling down (or at its
+/// `max_concurrent` limit with no `wait_for_slot`), and abandoned when its
+/// run ends in `rate_limit` or `quota_exhausted`. The last model always runs.
+/// Each hop is recorded in `state`.
+pub fn run_with_fallback(
+    models: &[&ModelConfig],
+    prompt: &str,
+    state: &StateDb,
+    opts: &RunOptions,
+) -> Result<RunOutcome, String> {
+    let mut hops = Vec::new();
+    let mut attempts = Vec::new();
+
+    for (i, model) in models.iter().enumerate() {
+        let next = models.get(i + 1);
+        if let Some(next) = next
+            && let Some(reason) = unavailable(model, state)
+        {
+            hops.push(hop(model, next, reason, state, opts));
+            continue;
+        }
+
+        let mut outcome = run(model, prompt, state, opts)?;
+        attempts.append(&mut outcome.attempts);
+        let capacity = outcome
+            .diagnosis
+            .as_ref()
+            .map(|d| d.category)
+            .filter(|c| matches!(c, ErrorCategory::RateLimit | ErrorCategory::QuotaExhausted));
+
+        match (next, capacity) {
+            (Some(next), Some(category)) if !outcome.success() && !is_cancelled(opts) => {
+                hops.push(hop(model, next, category.as_str(), state, opts));
+            }
+            _ => {
+                outcome.attempts = attempts;
+                outcome.hops = hops;
+                return Ok(outcome);
+            }
+        }
+    }
+    Err("No model to run".to_string())
+}
+
+fn unavailable(model: &ModelConfig, state: &StateDb) -> Option<&'static str> {
+    if balancer::all_cooling_down(model, state) {
+        Some("cooldown")
+    } else if model.wait_for_slot.is_none() && balancer::all_saturated(model, state) {
+        Some("saturated")
+    } else {
+        None
+    }
+}
+
+fn hop(
+    from: &ModelConfig,
+    to: &ModelConfig,
+    reason: &str,
+    state: &StateDb,
+    opts: &RunOptions,
+) -> ModelHop {
+    eprintln!(
+        "[fallback] {} unavailable ({reason}), trying {}",
+        from.name, to.name
+    );
+    state
+        .record_model_hop(&from.name, &to.name, reason, opts.agent_name)
+        .unwrap_or_else(|e| eprintln!("Warning: {e}"));
+    ModelHop {
+        from: from.name.clone(),
+        to: to.name.clone(),
+        reason: reason.to_string(),
+    }
+}
+
+/// Run `prompt` on `model`, failing over to the next-best provider when a
+/// failure is diagnosed as one of the categories in the model's retry policy.
+///
+/// Every attempt is recorded in `state`. The outcome of the last attempt is
+/// returned whether or not it succeeded. With an `output_format`, a
+/// successful run's stdout is validated and replaced by the extracted payload.
+pub fn run(
+    model: &ModelConfig,
+    prompt: &str,
+    state: &StateDb,
+    opts: &RunOptions,
+) -> Result<RunOutcome, String> {
+    let mut outcome = run_providers(model, prompt, state, opts)?;
+    let Some(format) = opts.output_format else {
+        return Ok(outcome);
+    };
+
+    let Err(error) = check_output(&mut outcome, format) else {
+        return Ok(outcome);
+    };
+    if !opts.output_retry || is_cancelled(opts) {
+        outcome.output_error = Some(error);
+        return Ok(outcome);
+    }
+
+    eprintln!("[output] {error}, re-prompting {}", model.name);
+    let retry_prompt = output::reprompt(prompt, format, &error)?;
+    let earlier = std::mem::take(&mut outcome.attempts);
+    let mut outcome = run_providers(model, &retry_prompt, state, opts)?;
+    outcome.attempts.splice(0..0, earlier);
+    if let Err(error) = check_output(&mut outcome, format) {
+        outcome.output_error = Some(error);
+    }
+    Ok(outcome)
+}
+
+/// Validate a successful run's stdout, replacing it with the payload.
+/// Failed runs are left alone; there is nothing to validate.
+fn check_output(outcome: &mut RunOutcome, format: &OutputFormat) -> Result<(), String> {
+    if outcome.provider_succeeded() {
+        outcome.result.stdout = output::validate(format, &outcome.result.stdout)?;
+    }
+    Ok(())
+}
+
+fn run_providers(
+    model: &ModelConfig,
+    prompt: &str,
+    state: &StateDb,
+    opts: &RunOptions,
+) -> Result<RunOutcome, String> {
+    let max_attempts = model.retry.max_attempts.max(1);
+    let mut tried: Vec<usize> = Vec::new();
+    let mut attempts: Vec<Attempt> = Vec::new();
+    let Some(mut pick) = first_pick(model, state, opts)? else {
+        return Ok(cancelled_outcome(model));
+    };
+    let accounts = state.list_accounts(None)?;
+    let working_dir = opts
+        .working_dir
+        .map(Path::to_path_buf)
+        .or_else(|| std::env::current_dir().ok())
+        .map(|dir| dir.to_string_lossy().into_owned());
+    // Logs one attempt in `state`.
+    let record = |pick: &Pick, result: &ExecutionResult, category: Option<ErrorCategory>| {
+        let success = result.exit_code == 0 && !result.timed_out() && !result.cancelled();
+        state
+            .record_invocation(&NewInvocation {
+                model_name: &model.name,
+                provider_index: pick.provider_index,
+                success,
+                exit_code: result.exit_code,
+                error_category: category.map(|c| c.as_str()),
+                stderr_snippet: if success { None } else { Some(&result.stderr) },
+                duration_ms: Some(result.duration.as_millis() as u64),
+                prompt_bytes: Some(prompt.len() as u64),
+                stdout_bytes: Some(result.stdout_bytes),
+                working_dir: working_dir.as_deref(),
+                agent_name: opts.agent_name,
+                run_id: opts.run_id,
+                reserved: pick.reserved,
+                cancelled: result.cancelled(),
+            })
+            .unwrap_or_else(|e| eprintln!("Warning: Failed to record invocation: {e}"));
+    };
+
+    loop {
+        let provider_index = pick.provider_index;
+        let renew = || {
+            if let Some(ref lease) = pick.lease {
+                lease.renew();
+            }
+        };
+        let exec_opts = ExecOptions {
+            timeout: opts.timeout,
+            on_output: opts.on_output,
+            accounts: &accounts,
+            cancel: opts.cancel,
+            heartbeat: Some(&renew),
+        };
+        let result =
+            executor::execute_with(model, provider_index, prompt, opts.working_dir, &exec_opts)?;
+        // Free the slot before anything else; failover may need it.
+        drop(pick.lease.take());
+        if result.cancelled() {
+            // Not the provider's doing: logged, but nothing to diagnose,
+            // count against it or retry.
+            record(&pick, &result, None);
+            attempts.push(attempt(model, &result, None));
+            return Ok(RunOutcome {
+                model: model.name.clone(),
+                result,
+                diagnosis: None,
+                attempts,
+                output_error: None,
+                hops: Vec::new(),
+            });
+        }
+        let success = result.exit_code == 0 && !result.timed_out();
+
+        let diagnosis = if success {
+            None
+        } else if result.timed_out() {
+            Some(Diagnosis {
+                category: ErrorCategory::Timeout,
+                summary: format!(
+                    "Provider timed out after {:.1}s",
+                    result.duration.as_secs_f64()
+                ),
+            })
+        } else {
+            Some(diagnose(&result.stderr, result.exit_code, opts))
+        };
+        let category = diagnosis.as_ref().map(|d| d.category);
+
+        record_cooldown(model, provider_index, category, &result.stderr, state);
+        record(&pick, &result, category);
+        tried.push(provider_index);
+        attempts.push(attempt(model, &result, category));
+
+        let retryable = category.is_some_and(|c| model.retry.should_retry(c));
+        let next = if success || !retryable || tried.len() >= max_attempts || is_cancelled(opts) {
+            None
+        } else {
+            pick_provider(model, state, &tried)
+        };
+
+        let Some(next) = next else {
+            return Ok(RunOutcome {
+                model: model.name.clone(),
+                result,
+                diagnosis,
+                attempts,
+                output_error: None,
+                hops: Vec::new(),
+            });
+        };
+
+        eprintln!(
+            "[failover] provider {} of {} failed ({}), retrying on provider {}",
+            provider_index,
+            model.name,
+            category.map(|c| c.as_str()).unwrap_or("unknown"),
+            next.provider_index
+        );
+        pick = next;
+    }
+}
+
+fn attempt(
+    model: &ModelConfig,
+    result: &ExecutionResult,
+    category: Option<ErrorCategory>,
+) -> Attempt {
+    Attempt {
+        model: model.name.clone(),
+        provider_index: result.provider_index,
+        command: model.providers[result.provider_index].command.clone(),
+        exit_code: result.exit_code,
+        error_category: category,
+        duration_ms: result.duration.as_millis() as u64,
+        cancelled: result.cancelled(),
+    }
+}
+
+fn is_cancelled(opts: &RunOptions) -> bool {
+    opts.cancel.is_some_and(|c| c.load(Ordering::SeqCst))
+}
+
+/// The outcome of a run cancelled before any provider started.
+fn cancelled_outcome(model: &ModelConfig) -> RunOutcome {
+    RunOutcome {
+        model: model.name.clone(),
+        result: ExecutionResult {
+            stdout: String::new(),
+            stderr: String::new(),
+            exit_code: executor::CANCELLED_EXIT_CODE,
+            provider_index: 0,
+            status: ExecutionStatus::Cancelled,
+            duration: Duration::ZERO,
+            stdout_bytes: 0,
+        },
+        diagnosis: None,
+        attempts: Vec::new(),
+        output_error: None,
+        hops: Vec::new(),
+    }
+}
+
+/// The provider chosen for one attempt.
+struct Pick<'a> {
+    provider_index: usize,
+    /// The call was already counted by the reservation.
+    reserved: bool,
+    lease: Option<Lease<'a>>,
+}
+
+/// An in-flight slot on a provider, released when dropped.
+pub(crate) struct Lease<'a> {
+    state: &'a StateDb,
+    id: i64,
+}
+
+impl<'a> Lease<'a> {
+    /// Take ownership of a lease from [`balancer::select_and_reserve`].
+    pub(crate) fn new(state: &'a StateDb, id: i64) -> Self {
+        Lease { state, id }
+    }
+
+    pub(crate) fn renew(&self) {
+        balancer::renew_lease(self.state, self.id).unwrap_or_else(|e| eprintln!("Warning: {e}"));
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        self.state
+            .release_lease(self.id)
+            .unwrap_or_else(|e| eprintln!("Warning: {e}"));
+    }
+}
+
+/// Pick the first provider, waiting up to the model's `wait_for_slot` when
+/// every provider is at its `max_concurrent` limit. `None` when the run was
+/// cancelled first.
+fn first_pick<'a>(
+    model: &ModelConfig,
+    state: &'a StateDb,
+    opts: &RunOptions,
+) -> Result<Option<Pick<'a>>, String> {
+    if model.providers.is_empty() {
+        return Err(format!("Model {} has no providers", model.name));
+    }
+
+    let deadline = model
+        .wait_for_slot
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let mut announced = false;
+    loop {
+        if is_cancelled(opts) {
+            return Ok(None);
+        }
+        if let Some(pick) = pick_provider(model, state, &[]) {
+            return Ok(Some(pick));
+        }
+        match deadline {
+            Some(deadline) if Instant::now() < deadline => {
+                if !announced {
+                    eprintln!(
+                        "[concurrency] all providers of {} are busy, waiting for a free slot",
+                        model.name
+                    );
+                    announced = true;
+                }
+                thread::sleep(SLOT_POLL_INTERVAL);
+            }
+            _ => {
+                return Err(format!(
+                    "All providers of {} are at their max_concurrent limit",
+                    model.name
+                ));
+            }
+        }
+    }
+}
+
+/// Select and reserve a provider not in `tried`. If the state DB cannot take
+/// the reservation the run goes ahead unreserved rather than failing.
+fn pick_provider<'a>(model: &ModelConfig, state: &'a StateDb, tried: &[usize]) -> Option<Pick<'a>> {
+    match balancer::select_and_reserve(model, state, tried) {
+        Ok(reservation) => reservation.map(|r| Pick {
+            provider_index: r.provider_index,
+            reserved: true,
+            lease: Some(Lease::new(state, r.lease_id)),
+        }),
+        Err(e) => {
+            eprintln!("Warning: Failed to reserve provider: {e}");
+            let picked = if tried.is_empty() {
+                balancer::select_provider(model, state)
+            } else {
+                balancer::select_provider_excluding(model, state, tried)
+            };
+            picked.map(|provider_index| Pick {
+                provider_index,
+                reserved: false,
+                lease: None,
+            })
+        }
+    }
+}
+
+/// Start a cooldown after a rate-limit/quota failure, or end one after a success.
+fn record_cooldown(
+    model: &ModelConfig,
+    provider_index: usize,
+    category: Option<ErrorCategory>,
+    stderr: &str,
+    state: &StateDb,
+) {
+    let result = match category {
+        None => state.clear_cooldown(&model.name, provider_index),
+        Some(category) => {
+            cooldown::record_failure(&model.name, provider_index, category, stderr, state).map(
+                |until| {
+                    if let Some(until) = until {
+                        eprintln!(
+                            "[cooldown] provider {} of {} cooling down until {}",
+                            provider_index,
+                            model.name,
+                            until.to_rfc3339()
+                        );
+                    }
+                },
+            )
+        }
+    };
+    result.unwrap_or_else(|e| eprintln!("Warning: Failed to update cooldown: {e}"));
+}
+
+/// Classify a failure with the diagnostics model if one is configured,
+/// otherwise (or if it fails) with keyword heuristics. Only the diagnostics
+/// model's verdict is reported on stderr; heuristics stay silent.
+fn diagnose(stderr: &str, exit_code: i32, opts: &RunOptions) -> Diagnosis {
+    let Some(diag_model) = opts.diagnostics_model else {
+        return diagnostics::heuristic_diagnosis(stderr, exit_code);
+    };
+    match diagnostics::diagnose_error(stderr, exit_code, diag_model, opts.models, opts.working_dir)
+    {
+        Ok(diagnosis) => {
+            eprintln!(
+                "[diagnostics] {}: {}",
+                diagnosis.category.as_str(),
+                diagnosis.summary
+            );
+            diagnosis
+        }
+        Err(e) => {
+            eprintln!("[diagnostics] Failed to diagnose: {e}");
+            diagnostics::heuristic_diagnosis(stderr, exit_code)
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::config::{PromptMode, ProviderConfig, RetryPolicy};
+    use crate::test_support::sh_provider;
+
+    fn run_model(model: &ModelConfig, db: &StateDb) -> RunOutcome {
+        let models = HashMap::new();
+        let opts = RunOptions {
+            working_dir: None,
+            agent_name: None,
+            run_id: None,
+            cancel: None,
+            diagnostics_model: None,
+            models: &models,
+            timeout: None,
+            on_output: None,
+            output_format: None,
+            output_retry: false,
+        };
+        run(model, "prompt", db, &opts).unwrap()
+    }
+
+    #[test]
+    fn fails_over_on_rate_limit() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let model = ModelConfig {
+            name: "m".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![
+                sh_provider("echo '429 Too Many Requests' >&2; exit 1"),
+                sh_provider("echo ok"),
+            ],
+            ..Default::default()
+        };
+
+        let outcome = run_model(&model, &db);
+        assert!(outcome.success());
+        assert_eq!(outcome.attempts.len(), 2);
+        assert_eq!(
+            outcome.attempts[0].error_category,
+            Some(ErrorCategory::RateLimit)
+        );
+        assert_eq!(outcome.result.provider_index, 1);
+        assert_eq!(db.get_provider("m", 0).unwrap().unwrap().error_count, 1);
+        assert_eq!(
+            db.get_provider("m", 1).unwrap().unwrap().invocation_count,
+            1
+        );
+    }
+
+    #[test]
+    fn report_serializes_final_attempt_and_history() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let model = ModelConfig {
+            name: "m".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![
+                sh_provider("echo 'rate limit' >&2; exit 1"),
+                sh_provider("echo 'quota exceeded' >&2; exit 2"),
+            ],
+            ..Default::default()
+        };
+
+        let report = run_model(&model, &db).report(Some("coder"));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["model"], "m");
+        assert_eq!(json["agent"], "coder");
+        assert_eq!(json["success"], false);
+        assert_eq!(json["provider_index"], 1);
+        assert_eq!(json["command"], "sh");
+        assert_eq!(json["exit_code"], 2);
+        assert_eq!(json["stderr"], "quota exceeded\n");
+        assert_eq!(json["diagnosis"]["category"], "quota_exhausted");
+        assert!(json["diagnosis"]["summary"].is_string());
+        assert_eq!(json["attempts"].as_array().unwrap().len(), 2);
+        assert_eq!(json["attempts"][0]["error_category"], "rate_limit");
+    }
+
+    #[test]
+    fn does_not_retry_unlisted_category() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let model = ModelConfig {
+            name: "m".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![
+                sh_provider("echo 'something weird' >&2; exit 3"),
+                sh_provider("echo ok"),
+            ],
+            ..Default::default()
+        };
+
+        let outcome = run_model(&model, &db);
+        assert!(!outcome.success());
+        assert_eq!(outcome.attempts.len(), 1);
+        assert_eq!(outcome.result.exit_code, 3);
+    }
+
+    #[test]
+    fn respects_max_attempts() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let failing = "echo 'rate limit' >&2; exit 1";
+        let model = ModelConfig {
+            name: "m".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![
+                sh_provider(failing),
+                sh_provider(failing),
+                sh_provider(failing),
+            ],
+            retry: RetryPolicy {
+                max_attempts: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let outcome = run_model(&model, &db);
+        assert!(!outcome.success());
+        assert_eq!(outcome.attempts.len(), 2);
+    }
+
+    #[test]
+    fn timeout_is_recorded_as_its_own_category() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let model = ModelConfig {
+            name: "m".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![sh_provider("sleep 30")],
+            timeout: Some(1),
+            ..Default::default()
+        };
+
+        let outcome = run_model(&model, &db);
+        assert!(!outcome.success());
+        assert!(outcome.result.timed_out());
+        assert_eq!(
+            outcome.attempts[0].error_category,
+            Some(ErrorCategory::Timeout)
+        );
+        let provider = db.get_provider("m", 0).unwrap().unwrap();
+        assert_eq!(provider.error_count, 1);
+    }
+
+    #[test]
+    fn rate_limited_provider_enters_cooldown() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let model = ModelConfig {
+            name: "m".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![
+                sh_provider("echo 'rate limit, retry after 600s' >&2; exit 1"),
+                sh_provider("echo ok"),
+            ],
+            ..Default::default()
+        };
+
+        run_model(&model, &db);
+        assert!(cooldown::is_cooling_down("m", 0, &db));
+        assert!(!cooldown::is_cooling_down("m", 1, &db));
+
+        // The next run goes straight to the healthy provider.
+        let outcome = run_model(&model, &db);
+        assert_eq!(outcome.attempts.len(), 1);
+        assert_eq!(outcome.result.provider_index, 1);
+    }
+
+    #[test]
+    fn releases_lease_after_run() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let model = ModelConfig {
+            name: "m".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![sh_provider("echo ok")],
+            ..Default::default()
+        };
+
+        assert!(run_model(&model, &db).success());
+        assert_eq!(db.in_flight_count("m", 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn cancelled_runs_are_logged_but_not_counted_as_errors() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let model = ModelConfig {
+            name: "m".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![
+                ProviderConfig {
+                    max_concurrent: Some(1),
+                    ..sh_provider("sleep 30")
+                },
+                sh_provider("sleep 30"),
+            ],
+            wait_for_slot: Some(30),
+            ..Default::default()
+        };
+        let models = HashMap::new();
+        let cancel = AtomicBool::new(false);
+        let opts = RunOptions {
+            working_dir: None,
+            agent_name: None,
+            run_id: Some("r"),
+            cancel: Some(&cancel),
+            diagnostics_model: None,
+            models: &models,
+            timeout: None,
+            on_output: None,
+            output_format: None,
+            output_retry: false,
+        };
+
+        let start = Instant::now();
+        let outcome = thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(200));
+                cancel.store(true, Ordering::SeqCst);
+            });
+            run(&model, "prompt", &db, &opts).unwrap()
+        });
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(outcome.result.cancelled());
+        assert_eq!(outcome.attempts.len(), 1, "no failover after cancelling");
+        assert!(outcome.attempts[0].cancelled);
+        let index = outcome.attempts[0].provider_index;
+        let provider = db.get_provider("m", index).unwrap().unwrap();
+        assert_eq!((provider.invocation_count, provider.error_count), (1, 0));
+        let log = db.run_invocations("r").unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].error_category.as_deref(), Some("cancelled"));
+
+        // Already cancelled: no provider starts, even while waiting for a slot.
+        for i in 0..2 {
+            db.acquire_lease("m", i, chrono::Duration::minutes(5))
+                .unwrap();
+        }
+        let model = ModelConfig {
+            providers: vec![ProviderConfig {
+                max_concurrent: Some(1),
+                ..sh_provider("sleep 30")
+            }],
+            ..model
+        };
+        let start = Instant::now();
+        let outcome = run(&model, "prompt", &db, &opts).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(outcome.result.cancelled() && outcome.attempts.is_empty());
+    }
+
+    #[test]
+    fn saturated_model_fails_without_waiting() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let model = ModelConfig {
+            name: "m".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![ProviderConfig {
+                max_concurrent: Some(1),
+                ..sh_provider("echo ok")
+            }],
+            ..Default::default()
+        };
+        db.acquire_lease("m", 0, chrono::Duration::minutes(5))
+            .unwrap();
+
+        let models = HashMap::new();
+        let opts = RunOptions {
+            working_dir: None,
+            agent_name: None,
+            run_id: None,
+            cancel: None,
+            diagnostics_model: None,
+            models: &models,
+            timeout: None,
+            on_output: None,
+            output_format: None,
+            output_retry: false,
+        };
+        let err = run(&model, "prompt", &db, &opts).err().unwrap();
+        assert!(err.contains("max_concurrent"), "{err}");
+    }
+
+    fn run_with_format(model: &ModelConfig, db: &StateDb, retry: bool) -> RunOutcome {
+        let models = HashMap::new();
+        let opts = RunOptions {
+            working_dir: None,
+            agent_name: None,
+            run_id: None,
+            cancel: None,
+            diagnostics_model: None,
+            models: &models,
+            timeout: None,
+            on_output: None,
+            output_format: Some(&OutputFormat::Json),
+            output_retry: retry,
+        };
+        run(model, "prompt", db, &opts).unwrap()
+    }
+
+    #[test]
+    fn validates_output_and_reprompts_once() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let model = ModelConfig {
+            name: "m".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![sh_provider(
+                r#"case "$0" in *"previous response"*) printf 'Done:\n```json\n{"ok": true}\n```\n' ;; *) echo 'not json' ;; esac"#,
+            )],
+            ..Default::default()
+        };
+
+        let outcome = run_with_format(&model, &db, false);
+        assert!(!outcome.success());
+        assert_eq!(outcome.exit_code(), 1);
+        assert_eq!(
+            outcome.output_error.as_deref(),
+            Some("output is not valid JSON")
+        );
+
+        let outcome = run_with_format(&model, &db, true);
+        assert!(outcome.success(), "{:?}", outcome.output_error);
+        assert_eq!(outcome.result.stdout, "{\"ok\": true}\n");
+        assert_eq!(outcome.attempts.len(), 2);
+    }
+
+    #[test]
+    fn falls_through_model_list_on_capacity_errors() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let limited = ModelConfig {
+            name: "opus".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![sh_provider("echo '429 rate limit' >&2; exit 1")],
+            ..Default::default()
+        };
+        let fallback = ModelConfig {
+            name: "sonnet".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![sh_provider("echo ok")],
+            ..Default::default()
+        };
+        let models = HashMap::new();
+        let opts = RunOptions {
+            working_dir: None,
+            agent_name: Some("coder"),
+            run_id: None,
+            cancel: None,
+            diagnostics_model: None,
+            models: &models,
+            timeout: None,
+            on_output: None,
+            output_format: None,
+            output_retry: false,
+        };
+
+        let outcome = run_with_fallback(&[&limited, &fallback], "prompt", &db, &opts).unwrap();
+        assert!(outcome.success());
+        assert_eq!(outcome.model, "sonnet");
+        assert_eq!(outcome.attempts.len(), 2);
+        assert_eq!(outcome.attempts[0].model, "opus");
+        assert_eq!(outcome.hops[0].reason, "rate_limit");
+
+        // The rate limit put opus into cooldown, so the next run skips it.
+        let outcome = run_with_fallback(&[&limited, &fallback], "prompt", &db, &opts).unwrap();
+        assert_eq!(outcome.attempts.len(), 1);
+        assert_eq!(outcome.hops[0].reason, "cooldown");
+
+        let hops = db.list_model_hops(None).unwrap();
+        assert_eq!(hops.len(), 2);
+        assert_eq!(
+            (hops[0].from_model.as_str(), hops[0].to_model.as_str()),
+            ("opus", "sonnet")
+        );
+        assert_eq!(hops[1].agent_name.as_deref(), Some("coder"));
+
+        // The last model runs even when it is unavailable.
+        let outcome = run_with_fallback(&[&limited], "prompt", &db, &opts).unwrap();
+        assert!(!outcome.success());
+        assert!(outcome.hops.is_empty());
+    }
+
+    #[test]
+    fn waits_for_a_slot_to_free_up() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        let model = ModelConfig {
+            name: "m".to_string(),
+            prompt_mode: PromptMode::Arg,
+            providers: vec![ProviderConfig {
+                max_concurrent: Some(1),
+                ..sh_provider("echo ok")
+            }],
+            wait_for_slot: Some(10),
+            ..Default::default()
+        };
+        // Stands in for a crashed run whose lease is about to expire.
+        db.acquire_lease("m", 0, chrono::Duration::seconds(1))
+            .unwrap();
+
+        let started = Instant::now();
+        assert!(run_model(&model, &db).success());
+        assert!(started.elapsed() >= Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,651 @@
+use crate::batch::{self, BatchEntry};
+use crate::config::{AgentConfig, ModelConfig, PromptMode};
+use crate::executor::{OutputSink, OutputStream};
+use crate::runner::{self, RunOptions, RunReport};
+use crate::state::StateDb;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tiny_http::{HTTPVersion, Header, Method, Request, Response};
+
+pub mod openai;
+
+/// Request bodies larger than this are rejected.
+const MAX_BODY_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Wait between failed accepts, doubling up to the max while they keep failing.
+const RECV_BACKOFF_MIN: Duration = Duration::from_millis(50);
+const RECV_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// What a server needs besides its socket.
+pub struct ServeOptions<'a> {
+    /// Required as `Authorization: Bearer <token>` on every request.
+    pub token: &'a str,
+    /// Requests handled at once; each worker runs one prompt at a time.
+    pub workers: usize,
+    pub models: &'a HashMap<String, ModelConfig>,
+    pub agents: &'a HashMap<String, AgentConfig>,
+    pub diagnostics_model: Option<&'a ModelConfig>,
+    pub timeout: Option<Duration>,
+    /// Base for relative `project` paths and `context` globs; requests
+    /// cannot reach outside it.
+    pub base_dir: &'a Path,
+    /// State DB each worker opens; `None` uses the default location.
+    pub state_path: Option<&'a Path>,
+    /// Also serve the OpenAI-compatible endpoints under `/openai/v1`.
+    pub openai: bool,
+    /// How chat messages become a prompt (default
+    /// [`openai::DEFAULT_CHAT_TEMPLATE`]).
+    pub chat_template: Option<&'a str>,
+}
+
+/// Body of `POST /v1/run`: one prompt for an agent or a model.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunRequest {
+    pub agent: Option<String>,
+    /// Model to run; overrides the agent's model when both are given.
+    pub model: Option<String>,
+    pub prompt: String,
+    pub project: Option<PathBuf>,
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    #[serde(default)]
+    pub context: Vec<String>,
+    /// Answer with server-sent events instead of a single JSON object.
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelInfo {
+    pub name: String,
+    pub prompt_mode: PromptMode,
+    pub provider_count: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AgentInfo {
+    pub name: String,
+    pub description: String,
+    /// The agent's models, in fallback order.
+    pub models: Vec<String>,
+}
+
+/// An HTTP server bound to its address, not yet accepting requests.
+pub struct Server {
+    http: tiny_http::Server,
+    stopping: AtomicBool,
+}
+
+impl Server {
+    pub fn bind(addr: &str) -> Result<Self, String> {
+        let http = tiny_http::Server::http(addr)
+            .map_err(|e| format!("Failed to listen on {addr}: {e}"))?;
+        Ok(Server {
+            http,
+            stopping: AtomicBool::new(false),
+        })
+    }
+
+    /// The bound address, e.g. `127.0.0.1:8765`.
+    pub fn addr(&self) -> String {
+        self.http.server_addr().to_string()
+    }
+
+    /// Handle requests on `opts.workers` threads until [`Server::stop`].
+    pub fn run(&self, opts: &ServeOptions) {
+        std::thread::scope(|scope| {
+            for _ in 0..opts.workers.max(1) {
+                scope.spawn(|| {
+                    let state = batch::open_state(opts.state_path);
+                    let mut backoff = RECV_BACKOFF_MIN;
+                    loop {
+                        match self.http.recv() {
+                            Ok(request) => {
+                                backoff = RECV_BACKOFF_MIN;
+                                handle(request, &state, opts);
+                            }
+                            Err(_) if self.stopping.load(Ordering::SeqCst) => break,
+                            Err(e) => {
+                                // e.g. out of file descriptors; retrying at
+                                // once would only spin.
+                                eprintln!("[serve] {e}");
+                                std::thread::sleep(backoff);
+                                backoff = (backoff * 2).min(RECV_BACKOFF_MAX);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    /// Make `run` return once in-flight requests finish.
+    pub fn stop(&self, workers: usize) {
+        self.stopping.store(true, Ordering::SeqCst);
+        for _ in 0..workers.max(1) {
+            self.http.unblock();
+        }
+    }
+}
+
+fn handle(mut request: Request, state: &StateDb, opts: &ServeOptions) {
+    if !authorized(&request, opts.token) {
+        let response = json_response(401, &error_body("missing or invalid bearer token"))
+            .with_header(header("WWW-Authenticate", "Bearer"));
+        let _ = request.respond(response);
+        return;
+    }
+
+    let path = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let response = match (request.method(), path.as_str()) {
+        (Method::Get, "/v1/models") => json_response(200, &list_models(opts.models)),
+        (Method::Get, "/v1/agents") => json_response(200, &list_agents(opts.agents)),
+        (Method::Post, "/v1/run") => {
+            match read_run_request(&mut request)
+                .and_then(|run| prepare(&run, opts).map(|prepared| (run, prepared)))
+            {
+                Ok((run, prepared)) if run.stream => {
+                    return stream_run(request, &run, &prepared, state, opts);
+                }
+                Ok((run, prepared)) => match execute(&run, &prepared, state, opts, None) {
+                    Ok(report) => json_response(200, &report),
+                    Err(e) => json_response(500, &error_body(&e)),
+                },
+                Err(e) => json_response(400, &error_body(&e)),
+            }
+        }
+        (Method::Get, "/openai/v1/models") if opts.openai => {
+            json_response(200, &openai::list_models(opts))
+        }
+        (Method::Post, "/openai/v1/chat/completions") if opts.openai => {
+            return openai::chat_completions(request, state, opts);
+        }
+        (_, "/v1/models" | "/v1/agents" | "/v1/run") => {
+            json_response(405, &error_body("method not allowed"))
+        }
+        _ => json_response(404, &error_body("not found")),
+    };
+    let _ = request.respond(response);
+}
+
+fn authorized(request: &Request, token: &str) -> bool {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.trim().as_bytes(), token.as_bytes()))
+}
+
+/// Compare without stopping at the first difference, so response timing
+/// does not reveal how much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn read_run_request(request: &mut Request) -> Result<RunRequest, String> {
+    let run: RunRequest = read_json(request)?;
+    if run.agent.is_none() && run.model.is_none() {
+        return Err("request needs an agent or a model".to_string());
+    }
+    Ok(run)
+}
+
+fn read_json<T: DeserializeOwned>(request: &mut Request) -> Result<T, String> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_BYTES + 1)
+        .read_to_string(&mut body)
+        .map_err(|e| format!("Failed to read request body: {e}"))?;
+    if body.len() as u64 > MAX_BODY_BYTES {
+        return Err(format!(
+            "request body exceeds {} MB",
+            MAX_BODY_BYTES / 1024 / 1024
+        ));
+    }
+    serde_json::from_str(&body).map_err(|e| format!("Invalid request body: {e}"))
+}
+
+/// A request resolved to its models, full prompt and working directory.
+struct Prepared<'a> {
+    chain: Vec<&'a ModelConfig>,
+    prompt: String,
+    project: Option<PathBuf>,
+}
+
+fn prepare<'a>(run: &RunRequest, opts: &ServeOptions<'a>) -> Result<Prepared<'a>, String> {
+    let entry = BatchEntry {
+        agent: run.agent.clone(),
+        model: run.model.clone(),
+        prompt: Some(run.prompt.clone()),
+        project: run.project.clone(),
+        vars: run.vars.clone(),
+        context: run.context.clone(),
+        ..Default::default()
+    };
+    batch::confine(&entry, opts.base_dir)?;
+    let (chain, prompt, project) = batch::prepare(
+        &entry,
+        opts.base_dir,
+        opts.agents,
+        opts.models,
+        &HashMap::new(),
+    )?;
+    Ok(Prepared {
+        chain,
+        prompt,
+        project,
+    })
+}
+
+/// Run a prepared request through the balancer, as the CLI would.
+fn execute(
+    run: &RunRequest,
+    prepared: &Prepared,
+    state: &StateDb,
+    opts: &ServeOptions,
+    on_output: Option<OutputSink>,
+) -> Result<RunReport, String> {
+    let agent = run.agent.as_ref().and_then(|name| opts.agents.get(name));
+    let run_opts = RunOptions {
+        working_dir: prepared.project.as_deref(),
+        agent_name: run.agent.as_deref(),
+        run_id: None,
+        cancel: None,
+        diagnostics_model: opts.diagnostics_model,
+        models: opts.models,
+        timeout: opts.timeout,
+        on_output,
+        output_format: agent.and_then(AgentConfig::output_format),
+        output_retry: agent.is_some_and(AgentConfig::output_retry),
+    };
+    runner::run_with_fallback(&prepared.chain, &prepared.prompt, state, &run_opts)
+        .map(|outcome| outcome.report(run.agent.as_deref()))
+}
+
+/// Answer with `text/event-stream`: an `output` event per line of provider
+/// output, then a `result` event with the report (or an `error` event).
+fn stream_run(
+    request: Request,
+    run: &RunRequest,
+    prepared: &Prepared,
+    state: &StateDb,
+    opts: &ServeOptions,
+) {
+    respond_with_events(request, |events| {
+        let forward = |stream: OutputStream, line: &str| {
+            let data = serde_json::json!({ "stream": stream, "line": line });
+            events.send(&sse_event("output", &data.to_string()));
+        };
+        let event = match execute(run, prepared, state, opts, Some(&forward)) {
+            Ok(report) => sse_event(
+                "result",
+                &serde_json::to_string(&report).unwrap_or_default(),
+            ),
+            Err(e) => sse_event("error", &error_body(&e).to_string()),
+        };
+        events.send(&event);
+    });
+}
+
+/// Answer `request` with a `text/event-stream` body made of whatever
+/// `produce` sends, ending when it returns.
+fn respond_with_events(request: Request, produce: impl FnOnce(&EventStream)) {
+    let events = EventStream::start(request);
+    produce(&events);
+    events.finish();
+}
+
+fn sse_event(event: &str, data: &str) -> Vec<u8> {
+    format!("event: {event}\ndata: {data}\n\n").into_bytes()
+}
+
+/// A response written straight to the connection, bypassing tiny_http's
+/// buffered body, so each event reaches the client as soon as it is sent.
+/// Write errors mean the client went away and are ignored.
+struct EventStream {
+    writer: Mutex<Box<dyn Write + Send>>,
+    /// HTTP/1.1 clients get chunked framing so the connection can be
+    /// reused; older ones read until it closes.
+    chunked: bool,
+}
+
+impl EventStream {
+    fn start(request: Request) -> Self {
+        let version = request.http_version().clone();
+        let chunked = version >= HTTPVersion(1, 1);
+        let mut writer = request.into_writer();
+        let framing = if chunked {
+            "Transfer-Encoding: chunked"
+        } else {
+            "Connection: close"
+        };
+        let _ = write!(
+            writer,
+            "HTTP/{version} 200 OK\r\nContent-Type: text/event-stream\r\n\
+             Cache-Control: no-cache\r\n{framing}\r\n\r\n"
+        );
+        let _ = writer.flush();
+        EventStream {
+            writer: Mutex::new(writer),
+            chunked,
+        }
+    }
+
+    /// Write one event and flush it.
+    fn send(&self, event: &[u8]) {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = if self.chunked {
+            write!(writer, "{:x}\r\n", event.len())
+                .and_then(|_| writer.write_all(event))
+                .and_then(|_| writer.write_all(b"\r\n"))
+        } else {
+            writer.write_all(event)
+        };
+        let _ = writer.flush();
+    }
+
+    fn finish(self) {
+        let mut writer = self.writer.into_inner().unwrap_or_else(|e| e.into_inner());
+        if self.chunked {
+            let _ = writer.write_all(b"0\r\n\r\n");
+        }
+        let _ = writer.flush();
+    }
+}
+
+fn list_models(models: &HashMap<String, ModelConfig>) -> Vec<ModelInfo> {
+    let mut list: Vec<ModelInfo> = models
+        .values()
+        .map(|m| ModelInfo {
+            name: m.name.clone(),
+            prompt_mode: m.prompt_mode,
+            provider_count: m.providers.len(),
+        })
+        .collect();
+    list.sort_by(|a, b| a.name.cmp(&b.name));
+    list
+}
+
+fn list_agents(agents: &HashMap<String, AgentConfig>) -> Vec<AgentInfo> {
+    let mut list: Vec<AgentInfo> = agents
+        .values()
+        .map(|a| AgentInfo {
+            name: a.name.clone(),
+            description: a.description.clone(),
+            models: a.model_chain().into_iter().map(String::from).collect(),
+        })
+        .collect();
+    list.sort_by(|a, b| a.name.cmp(&b.name));
+    list
+}
+
+fn error_body(message: &str) -> serde_json::Value {
+    serde_json::json!({ "error": message })
+}
+
+fn json_response<T: Serialize>(status: u16, body: &T) -> Response<std::io::Cursor<Vec<u8>>> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    Response::from_data(body)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_support::{sh_models, sh_provider};
+    use std::io::BufRead;
+    use std::net::TcpStream;
+
+    fn models() -> HashMap<String, ModelConfig> {
+        sh_models(&[
+            ("echo", "echo \"$0\"; echo done"),
+            ("ticker", "echo a; sleep 2; echo b"),
+        ])
+    }
+
+    fn options<'a>(
+        dir: &'a Path,
+        state_path: &'a Path,
+        models: &'a HashMap<String, ModelConfig>,
+        agents: &'a HashMap<String, AgentConfig>,
+    ) -> ServeOptions<'a> {
+        ServeOptions {
+            token: "t0k",
+            workers: 2,
+            models,
+            agents,
+            diagnostics_model: None,
+            timeout: None,
+            base_dir: dir,
+            state_path: Some(state_path),
+            openai: true,
+            chat_template: None,
+        }
+    }
+
+    /// Send one request and return the status code and raw body.
+    fn request(addr: &str, method: &str, path: &str, token: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {token}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response
+            .split_once("\r\n\r\n")
+            .map(|(_, b)| b.to_string())
+            .unwrap_or_default();
+        (status, body)
+    }
+
+    #[test]
+    fn serves_models_and_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.db");
+        let models = models();
+        let agents = HashMap::new();
+        let opts = options(dir.path(), &state_path, &models, &agents);
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.addr();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| server.run(&opts));
+
+            let (status, _) = request(&addr, "GET", "/v1/models", "wrong", "");
+            assert_eq!(status, 401);
+
+            let (status, body) = request(&addr, "GET", "/v1/models", "t0k", "");
+            assert_eq!(status, 200);
+            assert!(body.contains("\"name\":\"echo\""), "{body}");
+
+            let (status, body) = request(
+                &addr,
+                "POST",
+                "/v1/run",
+                "t0k",
+                r#"{"model": "echo", "prompt": "hi"}"#,
+            );
+            assert_eq!(status, 200);
+            let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(report["stdout"], "hi\ndone\n");
+            assert_eq!(report["success"], true);
+
+            let (_, body) = request(
+                &addr,
+                "POST",
+                "/v1/run",
+                "t0k",
+                r#"{"model": "echo", "prompt": "hi", "stream": true}"#,
+            );
+            let first = body.find("event: output").unwrap();
+            let result = body.find("event: result").unwrap();
+            assert!(first < result, "{body}");
+            assert!(body.contains(r#""line":"done\n""#), "{body}");
+
+            let (status, body) = request(
+                &addr,
+                "POST",
+                "/v1/run",
+                "t0k",
+                r#"{"model": "nope", "prompt": "x"}"#,
+            );
+            assert_eq!(status, 400);
+            assert!(body.contains("Unknown model"), "{body}");
+            for outside in [
+                r#""project": "/etc""#,
+                r#""project": "../x""#,
+                r#""context": ["../*"]"#,
+            ] {
+                let run = format!(r#"{{"model": "echo", "prompt": "x", {outside}}}"#);
+                let (status, body) = request(&addr, "POST", "/v1/run", "t0k", &run);
+                assert_eq!(status, 400, "{outside}");
+                assert!(body.contains("without '..'"), "{body}");
+            }
+            assert_eq!(request(&addr, "GET", "/v1/run", "t0k", "").0, 405);
+            assert_eq!(request(&addr, "GET", "/nope", "t0k", "").0, 404);
+
+            let (status, body) = request(&addr, "GET", "/openai/v1/models", "t0k", "");
+            assert_eq!(status, 200);
+            assert!(body.contains(r#""id":"echo""#), "{body}");
+
+            let chat = r#"{"model": "echo", "messages": [{"role": "user", "content": "hi"}]}"#;
+            let (status, body) = request(&addr, "POST", "/openai/v1/chat/completions", "t0k", chat);
+            assert_eq!(status, 200);
+            let completion: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(completion["object"], "chat.completion");
+            assert_eq!(completion["choices"][0]["message"]["content"], "hi\ndone");
+
+            let streamed = chat.replace("]}", r#"], "stream": true}"#);
+            let (_, body) = request(
+                &addr,
+                "POST",
+                "/openai/v1/chat/completions",
+                "t0k",
+                &streamed,
+            );
+            assert!(body.contains(r#""delta":{"content":"done\n"}"#), "{body}");
+            assert!(body.contains(r#""finish_reason":"stop""#), "{body}");
+            assert!(body.contains("data: [DONE]"), "{body}");
+
+            let unknown = chat.replace("echo", "gpt-4o");
+            let (status, body) = request(
+                &addr,
+                "POST",
+                "/openai/v1/chat/completions",
+                "t0k",
+                &unknown,
+            );
+            assert_eq!(status, 404);
+            assert!(body.contains("model_not_found"), "{body}");
+
+            server.stop(opts.workers);
+        });
+    }
+
+    #[test]
+    fn streams_each_event_as_it_happens() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.db");
+        let models = models();
+        let agents = HashMap::new();
+        let opts = options(dir.path(), &state_path, &models, &agents);
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.addr();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| server.run(&opts));
+
+            let body = r#"{"model": "ticker", "prompt": "x", "stream": true}"#;
+            let mut stream = TcpStream::connect(&addr).unwrap();
+            write!(
+                stream,
+                "POST /v1/run HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer t0k\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+            let start = std::time::Instant::now();
+            let mut lines = std::io::BufReader::new(stream).lines();
+            assert!(
+                lines.any(|line| line.unwrap().contains(r#""line":"a\n""#)),
+                "no event for the first line"
+            );
+            // The provider is still sleeping before its second line.
+            assert!(start.elapsed() < Duration::from_millis(1500));
+            let rest: Vec<String> = lines.map(Result::unwrap).collect();
+            assert!(rest.iter().any(|l| l.contains(r#""line":"b\n""#)));
+            assert!(rest.iter().any(|l| l == "event: result"));
+
+            server.stop(opts.workers);
+        });
+    }
+
+    #[test]
+    fn openai_stream_sends_only_the_final_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.db");
+        let mut models = sh_models(&[
+            (
+                "flaky",
+                "echo partial; echo 'rate limit exceeded' >&2; exit 1",
+            ),
+            ("chatty", r#"echo 'Sure: {"a": 1}'"#),
+        ]);
+        let flaky = models.get_mut("flaky").unwrap();
+        flaky.providers.push(flaky.providers[0].clone());
+        flaky.providers.push(sh_provider("echo whole"));
+        let agent = AgentConfig {
+            name: "structured".to_string(),
+            model: "chatty".to_string(),
+            output_format: Some(Some(crate::config::OutputFormat::Json)),
+            ..Default::default()
+        };
+        let agents = HashMap::from([("structured".to_string(), agent)]);
+        let opts = options(dir.path(), &state_path, &models, &agents);
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.addr();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| server.run(&opts));
+
+            let chat = |model: &str| {
+                let body = format!(
+                    r#"{{"model": "{model}", "stream": true, "messages": [{{"role": "user", "content": "hi"}}]}}"#
+                );
+                request(&addr, "POST", "/openai/v1/chat/completions", "t0k", &body).1
+            };
+
+            let body = chat("flaky");
+            assert!(!body.contains("partial"), "{body}");
+            assert!(body.contains(r#""delta":{"content":"whole\n"}"#), "{body}");
+
+            let body = chat("structured");
+            assert!(!body.contains("Sure"), "{body}");
+            assert!(body.contains(r#""finish_reason":"stop""#), "{body}");
+
+            server.stop(opts.workers);
+        });
+    }
+}
@@ -0,0 +1,350 @@
+//! The OpenAI-compatible endpoints under `/openai/v1`, so SDKs that only
+//! speak `/v1/chat/completions` can use configured models and agents.
+
+use super::{
+    Prepared, RunRequest, ServeOptions, execute, json_response, prepare, read_json,
+    respond_with_events,
+};
+use crate::config::{AgentConfig, ModelConfig, TemplateContext, render_template};
+use crate::diagnostics::ErrorCategory;
+use crate::executor::{OutputSink, OutputStream};
+use crate::runner::RunReport;
+use crate::state::StateDb;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use tiny_http::Request;
+
+/// How messages are flattened into a prompt unless `--chat-template` is given.
+pub const DEFAULT_CHAT_TEMPLATE: &str = "{{system}}\n\n{{conversation}}";
+
+/// Body of `POST /openai/v1/chat/completions`. Sampling parameters and tools
+/// are accepted and ignored; the CLIs behind the models do not take them.
+#[derive(Debug, Deserialize)]
+pub struct ChatRequest {
+    /// A model name, or failing that an agent name.
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    /// A string, or a list of parts of which only `text` ones are kept.
+    #[serde(default)]
+    pub content: Value,
+}
+
+/// Render `messages` with `template`, which may use:
+///
+/// - `{{system}}`: the system (and developer) messages
+/// - `{{conversation}}` (or `{{prompt}}`): the other messages as
+///   `Role: text` paragraphs, or just the text of a lone user message
+/// - `{{last_user}}`: the last user message
+pub fn flatten(messages: &[ChatMessage], template: &str) -> Result<String, String> {
+    let mut system = Vec::new();
+    let mut turns = Vec::new();
+    for message in messages {
+        let text = content_text(&message.content);
+        match message.role.as_str() {
+            "system" | "developer" => system.push(text),
+            role => turns.push((role, text)),
+        }
+    }
+
+    let conversation = match turns.as_slice() {
+        [("user", text)] => text.clone(),
+        _ => turns
+            .iter()
+            .map(|(role, text)| format!("{}: {text}", role_label(role)))
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+    };
+    let last_user = turns
+        .iter()
+        .rev()
+        .find(|(role, _)| *role == "user")
+        .map(|(_, text)| text.clone())
+        .unwrap_or_default();
+
+    let ctx = TemplateContext {
+        prompt: conversation.clone(),
+        project_dir: None,
+        vars: HashMap::from([
+            ("system".to_string(), system.join("\n\n")),
+            ("conversation".to_string(), conversation),
+            ("last_user".to_string(), last_user),
+        ]),
+    };
+    let prompt = render_template(template, &ctx, &HashMap::new())
+        .map_err(|e| format!("Chat template: {e}"))?;
+    Ok(prompt.trim().to_string())
+}
+
+fn content_text(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter(|p| p["type"] == "text")
+            .filter_map(|p| p["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+fn role_label(role: &str) -> String {
+    let mut chars = role.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// `GET /openai/v1/models`: every model, then every agent not shadowed by one.
+pub(super) fn list_models(opts: &ServeOptions) -> Value {
+    let mut ids: Vec<&String> = opts.models.keys().collect();
+    ids.sort();
+    let mut agents: Vec<&String> = opts
+        .agents
+        .keys()
+        .filter(|name| !opts.models.contains_key(*name))
+        .collect();
+    agents.sort();
+    ids.extend(agents);
+
+    let data: Vec<Value> = ids
+        .into_iter()
+        .map(|id| json!({ "id": id, "object": "model", "created": 0, "owned_by": "agent-runner" }))
+        .collect();
+    json!({ "object": "list", "data": data })
+}
+
+/// `POST /openai/v1/chat/completions`.
+pub(super) fn chat_completions(mut request: Request, state: &StateDb, opts: &ServeOptions) {
+    let prepared = read_json::<ChatRequest>(&mut request)
+        .map_err(|e| (400, error_body(&e, "invalid_request_error", None)))
+        .and_then(|chat| {
+            let run = run_request(&chat, opts)?;
+            let prepared = prepare(&run, opts)
+                .map_err(|e| (400, error_body(&e, "invalid_request_error", None)))?;
+            Ok((chat, run, prepared))
+        });
+    let (chat, run, prepared) = match prepared {
+        Ok(prepared) => prepared,
+        Err((status, body)) => {
+            let _ = request.respond(json_response(status, &body));
+            return;
+        }
+    };
+
+    let completion = Completion {
+        id: format!("chatcmpl-{}", uuid::Uuid::new_v4().simple()),
+        created: chrono::Utc::now().timestamp(),
+        model: chat.model,
+    };
+    if chat.stream {
+        return stream(request, &completion, &run, &prepared, state, opts);
+    }
+
+    let response = match execute(&run, &prepared, state, opts, None) {
+        Ok(report) if report.success => json_response(
+            200,
+            &json!({
+                "id": completion.id,
+                "object": "chat.completion",
+                "created": completion.created,
+                "model": completion.model,
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": report.stdout.trim_end() },
+                    "finish_reason": "stop",
+                }],
+            }),
+        ),
+        Ok(report) => {
+            let (status, body) = failure(&report);
+            json_response(status, &body)
+        }
+        Err(e) => json_response(500, &error_body(&e, "server_error", None)),
+    };
+    let _ = request.respond(response);
+}
+
+/// Map the request's `model` to a model, or else an agent, of that name.
+fn run_request(chat: &ChatRequest, opts: &ServeOptions) -> Result<RunRequest, (u16, Value)> {
+    let template = opts.chat_template.unwrap_or(DEFAULT_CHAT_TEMPLATE);
+    let prompt = flatten(&chat.messages, template)
+        .map_err(|e| (400, error_body(&e, "invalid_request_error", None)))?;
+    if prompt.is_empty() {
+        return Err((
+            400,
+            error_body("messages contain no text", "invalid_request_error", None),
+        ));
+    }
+
+    let mut run = RunRequest {
+        prompt,
+        ..Default::default()
+    };
+    if opts.models.contains_key(&chat.model) {
+        run.model = Some(chat.model.clone());
+    } else if opts.agents.contains_key(&chat.model) {
+        run.agent = Some(chat.model.clone());
+    } else {
+        return Err((
+            404,
+            error_body(
+                &format!("The model '{}' does not exist", chat.model),
+                "invalid_request_error",
+                Some("model_not_found"),
+            ),
+        ));
+    }
+    Ok(run)
+}
+
+/// Fields shared by a completion and each of its chunks.
+struct Completion {
+    id: String,
+    created: i64,
+    model: String,
+}
+
+impl Completion {
+    fn chunk(&self, delta: Value, finish_reason: Option<&str>) -> Vec<u8> {
+        let chunk = json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        });
+        sse_data(&chunk.to_string())
+    }
+}
+
+/// Send stdout as `chat.completion.chunk` deltas, then a final chunk (or an
+/// error) and `[DONE]`.
+///
+/// Sent chunks cannot be taken back, so stdout is only streamed line by line
+/// when the run has a single attempt. Otherwise a failed provider's output or
+/// a rejected `output_format` attempt could reach the client; the final
+/// (validated) stdout is then sent as one chunk once the run ends.
+fn stream(
+    request: Request,
+    completion: &Completion,
+    run: &RunRequest,
+    prepared: &Prepared,
+    state: &StateDb,
+    opts: &ServeOptions,
+) {
+    let chunk = |delta, finish_reason| completion.chunk(delta, finish_reason);
+    let formatted = run
+        .agent
+        .as_ref()
+        .and_then(|name| opts.agents.get(name))
+        .and_then(AgentConfig::output_format)
+        .is_some();
+    let live = !formatted && single_attempt(&prepared.chain);
+
+    respond_with_events(request, |events| {
+        events.send(&chunk(json!({ "role": "assistant", "content": "" }), None));
+        let forward = |stream: OutputStream, line: &str| {
+            if stream == OutputStream::Stdout {
+                events.send(&chunk(json!({ "content": line }), None));
+            }
+        };
+        let on_output: Option<OutputSink> = if live { Some(&forward) } else { None };
+        let last = match execute(run, prepared, state, opts, on_output) {
+            Ok(report) if report.success => {
+                if !live && !report.stdout.is_empty() {
+                    events.send(&chunk(json!({ "content": report.stdout }), None));
+                }
+                chunk(json!({}), Some("stop"))
+            }
+            Ok(report) => sse_data(&failure(&report).1.to_string()),
+            Err(e) => sse_data(&error_body(&e, "server_error", None).to_string()),
+        };
+        events.send(&last);
+        events.send(&sse_data("[DONE]"));
+    });
+}
+
+/// Whether a run on `chain` can only ever make one provider attempt.
+fn single_attempt(chain: &[&ModelConfig]) -> bool {
+    match chain {
+        [model] => model.providers.len() <= 1 || model.retry.max_attempts <= 1,
+        _ => false,
+    }
+}
+
+/// The status and error body for a run that did not succeed.
+fn failure(report: &RunReport) -> (u16, Value) {
+    let category = report.diagnosis.as_ref().map(|d| d.category);
+    let status = match category {
+        Some(ErrorCategory::RateLimit | ErrorCategory::QuotaExhausted) => 429,
+        Some(ErrorCategory::Timeout) => 504,
+        _ => 502,
+    };
+    let message = report
+        .output_error
+        .clone()
+        .or_else(|| report.diagnosis.as_ref().map(|d| d.summary.clone()))
+        .unwrap_or_else(|| format!("provider exited with code {}", report.exit_code));
+    (
+        status,
+        error_body(
+            &message,
+            "provider_error",
+            category.as_ref().map(ErrorCategory::as_str),
+        ),
+    )
+}
+
+fn error_body(message: &str, kind: &str, code: Option<&str>) -> Value {
+    json!({ "error": { "message": message, "type": kind, "code": code } })
+}
+
+fn sse_data(data: &str) -> Vec<u8> {
+    format!("data: {data}\n\n").into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(raw: Value) -> Vec<ChatMessage> {
+        serde_json::from_value(raw).unwrap()
+    }
+
+    #[test]
+    fn flattens_messages() {
+        let lone = messages(json!([
+            {"role": "system", "content": "Be terse."},
+            {"role": "user", "content": [{"type": "text", "text": "Hi"}, {"type": "image_url"}]},
+        ]));
+        assert_eq!(
+            flatten(&lone, DEFAULT_CHAT_TEMPLATE).unwrap(),
+            "Be terse.\n\nHi"
+        );
+
+        let history = messages(json!([
+            {"role": "user", "content": "2+2?"},
+            {"role": "assistant", "content": "4"},
+            {"role": "user", "content": "And 3+3?"},
+        ]));
+        assert_eq!(
+            flatten(&history, DEFAULT_CHAT_TEMPLATE).unwrap(),
+            "User: 2+2?\n\nAssistant: 4\n\nUser: And 3+3?"
+        );
+        assert_eq!(
+            flatten(&history, "Q: {{last_user}}").unwrap(),
+            "Q: And 3+3?"
+        );
+        assert!(flatten(&history, "{{nope}}").is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::state::{configure_connection, migrations};
 use rusqlite::{Connection, params};
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
@@ -78,39 +79,19 @@ pub struct VersionRecord {
 }
 
 impl VersionTracker {
-    /// Open (or create) the version-tracking table inside the given SQLite
-    /// database file.
+    /// Open (or create) the version-tracking tables inside the given SQLite
+    /// database file, migrating it to the current schema.
     pub fn open(db_path: &Path) -> Result<Self, String> {
         if let Some(parent) = db_path.parent() {
             std::fs::create_dir_all(parent)
                 .map_err(|e| format!("Failed to create directory for version DB: {e}"))?;
         }
-        let conn =
+        let mut conn =
             Connection::open(db_path).map_err(|e| format!("Failed to open version DB: {e}"))?;
 
-        conn.execute_batch("PRAGMA journal_mode=WAL;")
-            .map_err(|e| format!("Failed to set WAL mode: {e}"))?;
-
-        conn.execute_batch(
-            "CREATE TABLE IF NOT EXISTS cli_versions (
-                cli_name    TEXT PRIMARY KEY,
-                version     TEXT NOT NULL,
-                path        TEXT,
-                detected_at TEXT NOT NULL
-            );
-
-            CREATE TABLE IF NOT EXISTS cli_version_history (
-                id          INTEGER PRIMARY KEY AUTOINCREMENT,
-                cli_name    TEXT NOT NULL,
-                version     TEXT NOT NULL,
-                path        TEXT,
-                detected_at TEXT NOT NULL
-            );
-
-            CREATE INDEX IF NOT EXISTS idx_cli_version_history_name
-                ON cli_version_history (cli_name, detected_at);",
-        )
-        .map_err(|e| format!("Failed to create cli_versions tables: {e}"))?;
+        configure_connection(&conn)?;
+
+        migrations::migrate(&mut conn)?;
 
         Ok(VersionTracker { conn })
     }
@@ -1,3 +1,4 @@
+use crate::state::{configure_connection, migrations};
 use rusqlite::{Connection, params};
 use serde::Serialize;
 use std::path::Path;
@@ -38,49 +39,12 @@ impl MemoryGraph {
                 .map_err(|e| format!("Failed to create directory: {e}"))?;
         }
 
-        let conn = Connection::open(path).map_err(|e| format!("Failed to open memory DB: {e}"))?;
-
-        conn.execute_batch("PRAGMA journal_mode=WAL;")
-            .map_err(|e| format!("Failed to set WAL mode: {e}"))?;
-
-        conn.execute_batch(
-            "CREATE TABLE IF NOT EXISTS memory_nodes (
-                id TEXT PRIMARY KEY,
-                node_type TEXT NOT NULL,
-                label TEXT NOT NULL,
-                data TEXT NOT NULL,
-                created_at TEXT NOT NULL,
-                updated_at TEXT NOT NULL
-            );
-
-            CREATE TABLE IF NOT EXISTS memory_edges (
-                source_id TEXT NOT NULL,
-                target_id TEXT NOT NULL,
-                edge_type TEXT NOT NULL,
-                data TEXT,
-                created_at TEXT NOT NULL,
-                PRIMARY KEY (source_id, target_id, edge_type)
-            );
-
-            CREATE TABLE IF NOT EXISTS setup_sessions (
-                id TEXT PRIMARY KEY,
-                started_at TEXT NOT NULL,
-                ended_at TEXT,
-                outcome TEXT,
-                turn_count INTEGER DEFAULT 0
-            );
-
-            CREATE TABLE IF NOT EXISTS setup_turns (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                session_id TEXT NOT NULL,
-                turn_number INTEGER NOT NULL,
-                agent_prompt TEXT NOT NULL,
-                agent_response TEXT NOT NULL,
-                events_emitted TEXT NOT NULL,
-                created_at TEXT NOT NULL
-            );",
-        )
-        .map_err(|e| format!("Failed to create memory tables: {e}"))?;
+        let mut conn =
+            Connection::open(path).map_err(|e| format!("Failed to open memory DB: {e}"))?;
+
+        configure_connection(&conn)?;
+
+        migrations::migrate(&mut conn)?;
 
         Ok(MemoryGraph { conn })
     }
@@ -1,12 +1,105 @@
 use chrono::{DateTime, Utc};
-use rusqlite::{Connection, params};
+use rusqlite::{Connection, Transaction, TransactionBehavior, params};
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 
+use super::{configure_connection, migrations};
+
 pub struct StateDb {
     conn: Connection,
 }
 
+/// One provider invocation to be logged by [`StateDb::record_invocation`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NewInvocation<'a> {
+    pub model_name: &'a str,
+    pub provider_index: usize,
+    pub success: bool,
+    pub exit_code: i32,
+    pub error_category: Option<&'a str>,
+    /// Stored (truncated) as the provider's last error when the invocation failed.
+    pub stderr_snippet: Option<&'a str>,
+    /// Wall-clock time from spawn to exit.
+    pub duration_ms: Option<u64>,
+    pub prompt_bytes: Option<u64>,
+    pub stdout_bytes: Option<u64>,
+    pub working_dir: Option<&'a str>,
+    /// The agent the prompt came from, if any.
+    pub agent_name: Option<&'a str>,
+    /// Links the invocations of one pipeline run.
+    pub run_id: Option<&'a str>,
+    /// The provider was picked with [`StateDb::reserve_provider`], which
+    /// already counted this invocation.
+    pub reserved: bool,
+    /// Stopped by the caller. Logged as unsuccessful with the
+    /// [`CANCELLED_CATEGORY`], but not counted as a provider error.
+    pub cancelled: bool,
+}
+
+/// `error_category` of invocations that were cancelled rather than failed.
+pub const CANCELLED_CATEGORY: &str = "cancelled";
+
+/// A run that fell through from one model of an agent's list to the next.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelHopRecord {
+    pub from_model: String,
+    pub to_model: String,
+    pub reason: String,
+    pub agent_name: Option<String>,
+    pub hopped_at: DateTime<Utc>,
+}
+
+/// Latency and payload sizes of one provider's timed invocations.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderLatency {
+    pub provider_index: usize,
+    pub samples: u64,
+    pub avg_duration_ms: f64,
+    pub max_duration_ms: u64,
+    pub avg_prompt_bytes: Option<f64>,
+    pub avg_stdout_bytes: Option<f64>,
+}
+
+/// Which invocations the aggregate queries behind `stats` look at.
+#[derive(Debug, Clone, Default)]
+pub struct StatsFilter {
+    pub model_name: Option<String>,
+    /// Inclusive lower bound on `created_at`.
+    pub since: Option<DateTime<Utc>>,
+    /// Exclusive upper bound on `created_at`.
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// Invocation totals for one provider of one model.
+#[derive(Debug, Clone, Serialize)]
+pub struct InvocationSummary {
+    pub model_name: String,
+    pub provider_index: usize,
+    pub invocations: u64,
+    pub errors: u64,
+    pub avg_duration_ms: Option<f64>,
+    pub first_invoked_at: Option<DateTime<Utc>>,
+    pub last_invoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorCategoryCount {
+    pub model_name: String,
+    pub provider_index: usize,
+    pub category: String,
+    pub count: u64,
+}
+
+/// Consecutive successes of one provider, ignoring other providers' calls.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SuccessStreak {
+    pub model_name: String,
+    pub provider_index: usize,
+    /// Successes since the most recent failure.
+    pub current: u64,
+    pub longest: u64,
+}
+
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct ProviderRecord {
@@ -20,16 +113,27 @@ pub struct ProviderRecord {
 }
 
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
 pub struct InvocationRecord {
     pub model_name: String,
     pub provider_index: usize,
     pub success: bool,
     pub exit_code: i32,
     pub error_category: Option<String>,
+    pub agent_name: Option<String>,
     pub created_at: DateTime<Utc>,
 }
 
+/// A provider that should not be selected until `until`.
+#[derive(Debug, Clone)]
+pub struct CooldownRecord {
+    pub model_name: String,
+    pub provider_index: usize,
+    pub until: DateTime<Utc>,
+    pub reason: String,
+    /// Consecutive cooldowns without a success in between; drives backoff.
+    pub strikes: u32,
+}
+
 // --- Model discovery entities ---
 
 /// The type of a model parameter, stored as JSON in SQLite.
@@ -153,6 +257,10 @@ pub struct AccountRecord {
     pub auth_method: AuthMethod,
     pub auth_status: AuthStatus,
     pub created_at: String,
+    /// The CLI config directory holding this account's login. Providers bound
+    /// to the account default to `<data dir>/accounts/<provider>/<id>`.
+    #[serde(default)]
+    pub config_dir: Option<String>,
 }
 
 impl StateDb {
@@ -162,115 +270,12 @@ impl StateDb {
                 .map_err(|e| format!("Failed to create state directory: {e}"))?;
         }
 
-        let conn = Connection::open(path).map_err(|e| format!("Failed to open state DB: {e}"))?;
-
-        conn.execute_batch("PRAGMA journal_mode=WAL;")
-            .map_err(|e| format!("Failed to set WAL mode: {e}"))?;
-
-        conn.execute_batch(
-            "CREATE TABLE IF NOT EXISTS providers (
-                model_name TEXT NOT NULL,
-                provider_index INTEGER NOT NULL,
-                invocation_count INTEGER NOT NULL DEFAULT 0,
-                error_count INTEGER NOT NULL DEFAULT 0,
-                last_error TEXT,
-                last_error_at TEXT,
-                last_invoked_at TEXT,
-                PRIMARY KEY (model_name, provider_index)
-            );
-
-            CREATE TABLE IF NOT EXISTS invocations (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                model_name TEXT NOT NULL,
-                provider_index INTEGER NOT NULL,
-                success INTEGER NOT NULL,
-                exit_code INTEGER NOT NULL,
-                error_category TEXT,
-                created_at TEXT NOT NULL
-            );
-
-            CREATE INDEX IF NOT EXISTS idx_invocations_model
-                ON invocations (model_name, provider_index, created_at);
-
-            CREATE TABLE IF NOT EXISTS memory_nodes (
-                id TEXT PRIMARY KEY,
-                node_type TEXT NOT NULL,
-                label TEXT NOT NULL,
-                data TEXT NOT NULL,
-                created_at TEXT NOT NULL,
-                updated_at TEXT NOT NULL
-            );
-
-            CREATE TABLE IF NOT EXISTS memory_edges (
-                source_id TEXT NOT NULL REFERENCES memory_nodes(id),
-                target_id TEXT NOT NULL REFERENCES memory_nodes(id),
-                edge_type TEXT NOT NULL,
-                data TEXT,
-                created_at TEXT NOT NULL,
-                PRIMARY KEY (source_id, target_id, edge_type)
-            );
-
-            CREATE TABLE IF NOT EXISTS setup_sessions (
-                id TEXT PRIMARY KEY,
-                started_at TEXT NOT NULL,
-                ended_at TEXT,
-                outcome TEXT,
-                turn_count INTEGER DEFAULT 0
-            );
-
-            CREATE TABLE IF NOT EXISTS setup_turns (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                session_id TEXT NOT NULL,
-                turn_number INTEGER NOT NULL,
-                agent_prompt TEXT NOT NULL,
-                agent_response TEXT NOT NULL,
-                events_emitted TEXT NOT NULL,
-                created_at TEXT NOT NULL
-            );
-
-            CREATE TABLE IF NOT EXISTS cli_providers (
-                cli_name TEXT PRIMARY KEY,
-                display_name TEXT NOT NULL,
-                installed INTEGER NOT NULL DEFAULT 0,
-                version TEXT,
-                config_dir TEXT,
-                last_synced TEXT
-            );
-
-            CREATE TABLE IF NOT EXISTS accounts (
-                id TEXT NOT NULL,
-                provider TEXT NOT NULL REFERENCES cli_providers(cli_name),
-                profile_name TEXT NOT NULL,
-                auth_method TEXT NOT NULL,
-                auth_status TEXT NOT NULL DEFAULT 'unknown',
-                created_at TEXT NOT NULL,
-                PRIMARY KEY (id, provider)
-            );
-
-            CREATE INDEX IF NOT EXISTS idx_accounts_provider
-                ON accounts (provider);
-
-            CREATE TABLE IF NOT EXISTS discovered_models (
-                canonical_name TEXT NOT NULL,
-                provider TEXT NOT NULL,
-                discovered_at TEXT NOT NULL,
-                cli_version TEXT NOT NULL,
-                PRIMARY KEY (canonical_name, provider)
-            );
-
-            CREATE TABLE IF NOT EXISTS model_parameters (
-                model_name TEXT NOT NULL,
-                provider TEXT NOT NULL,
-                name TEXT NOT NULL,
-                display_name TEXT NOT NULL,
-                param_type TEXT NOT NULL,
-                description TEXT NOT NULL DEFAULT '',
-                cli_mapping TEXT NOT NULL,
-                PRIMARY KEY (model_name, provider, name)
-            );
-            ",
-        )
-        .map_err(|e| format!("Failed to initialize schema: {e}"))?;
+        let mut conn =
+            Connection::open(path).map_err(|e| format!("Failed to open state DB: {e}"))?;
+
+        configure_connection(&conn)?;
+
+        migrations::migrate(&mut conn)?;
 
         Ok(StateDb { conn })
     }
@@ -282,33 +287,92 @@ impl StateDb {
         Self::open(&db_path)
     }
 
-    pub fn record_invocation(
-        &self,
-        model_name: &str,
-        provider_index: usize,
-        success: bool,
-        exit_code: i32,
-        error_category: Option<&str>,
-        stderr_snippet: Option<&str>,
-    ) -> Result<(), String> {
-        let now = Utc::now().to_rfc3339();
+    /// Run `f` in an immediate transaction: the write lock is taken up front,
+    /// so everything `f` reads stays current until it commits. Rolls back if
+    /// `f` fails.
+    pub fn immediate<T>(&self, f: impl FnOnce(&Self) -> Result<T, String>) -> Result<T, String> {
+        let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)
+            .map_err(|e| format!("Failed to begin transaction: {e}"))?;
+        let value = f(self)?;
+        tx.commit()
+            .map_err(|e| format!("Failed to commit transaction: {e}"))?;
+        Ok(value)
+    }
 
-        // Upsert provider stats
+    /// Count an invocation against the provider before it runs, so runs that
+    /// select concurrently see it. Pair with `reserved: true` in
+    /// [`NewInvocation`] when recording the result.
+    pub fn reserve_provider(&self, model_name: &str, provider_index: usize) -> Result<(), String> {
+        let now = Utc::now().to_rfc3339();
         self.conn
             .execute(
                 "INSERT INTO providers (model_name, provider_index, invocation_count, error_count, last_invoked_at)
-                 VALUES (?1, ?2, 1, ?3, ?4)
+                 VALUES (?1, ?2, 1, 0, ?3)
                  ON CONFLICT (model_name, provider_index)
                  DO UPDATE SET
                     invocation_count = invocation_count + 1,
-                    error_count = error_count + ?3,
-                    last_invoked_at = ?4",
-                params![model_name, provider_index as i64, if success { 0i64 } else { 1 }, &now],
+                    last_invoked_at = ?3",
+                params![model_name, provider_index as i64, &now],
             )
-            .map_err(|e| format!("Failed to upsert provider: {e}"))?;
+            .map_err(|e| format!("Failed to reserve provider: {e}"))?;
+        Ok(())
+    }
+
+    pub fn record_invocation(&self, invocation: &NewInvocation) -> Result<(), String> {
+        self.immediate(|db| db.insert_invocation(invocation))
+    }
+
+    fn insert_invocation(&self, invocation: &NewInvocation) -> Result<(), String> {
+        let NewInvocation {
+            model_name,
+            provider_index,
+            success,
+            exit_code,
+            error_category,
+            stderr_snippet,
+            duration_ms,
+            prompt_bytes,
+            stdout_bytes,
+            working_dir,
+            agent_name,
+            run_id,
+            reserved,
+            cancelled,
+        } = *invocation;
+        let now = Utc::now().to_rfc3339();
+        let failed = !success && !cancelled;
+        let error_category = if cancelled {
+            Some(CANCELLED_CATEGORY)
+        } else {
+            error_category
+        };
+
+        // Upsert provider stats; a reservation already counted the call.
+        if reserved {
+            self.conn
+                .execute(
+                    "UPDATE providers SET error_count = error_count + ?3
+                     WHERE model_name = ?1 AND provider_index = ?2",
+                    params![model_name, provider_index as i64, failed as i64],
+                )
+                .map_err(|e| format!("Failed to update provider: {e}"))?;
+        } else {
+            self.conn
+                .execute(
+                    "INSERT INTO providers (model_name, provider_index, invocation_count, error_count, last_invoked_at)
+                     VALUES (?1, ?2, 1, ?3, ?4)
+                     ON CONFLICT (model_name, provider_index)
+                     DO UPDATE SET
+                        invocation_count = invocation_count + 1,
+                        error_count = error_count + ?3,
+                        last_invoked_at = ?4",
+                    params![model_name, provider_index as i64, failed as i64, &now],
+                )
+                .map_err(|e| format!("Failed to upsert provider: {e}"))?;
+        }
 
         // Record error details if failed
-        if !success {
+        if failed {
             let snippet = stderr_snippet
                 .unwrap_or("")
                 .chars()
@@ -326,8 +390,9 @@ impl StateDb {
         // Insert invocation log
         self.conn
             .execute(
-                "INSERT INTO invocations (model_name, provider_index, success, exit_code, error_category, created_at)
-                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                "INSERT INTO invocations (model_name, provider_index, success, exit_code, error_category, created_at,
+                                          duration_ms, prompt_bytes, stdout_bytes, working_dir, agent_name, run_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
                 params![
                     model_name,
                     provider_index as i64,
@@ -335,6 +400,12 @@ impl StateDb {
                     exit_code,
                     error_category,
                     &now,
+                    duration_ms.map(|d| d as i64),
+                    prompt_bytes.map(|b| b as i64),
+                    stdout_bytes.map(|b| b as i64),
+                    working_dir,
+                    agent_name,
+                    run_id,
                 ],
             )
             .map_err(|e| format!("Failed to insert invocation: {e}"))?;
@@ -393,8 +464,14 @@ impl StateDb {
             .query_row(
                 "SELECT COUNT(*) FROM invocations
                  WHERE model_name = ?1 AND provider_index = ?2
-                   AND success = 0 AND created_at > ?3",
-                params![model_name, provider_index as i64, &cutoff],
+                   AND success = 0 AND created_at > ?3
+                   AND error_category IS NOT ?4",
+                params![
+                    model_name,
+                    provider_index as i64,
+                    &cutoff,
+                    CANCELLED_CATEGORY
+                ],
                 |row| row.get(0),
             )
             .map_err(|e| format!("Failed to count recent errors: {e}"))?;
@@ -402,6 +479,465 @@ impl StateDb {
         Ok(count as u64)
     }
 
+    /// The provider most recently picked for the model, including reserved
+    /// invocations that are still running.
+    pub fn last_invoked_provider(&self, model_name: &str) -> Result<Option<usize>, String> {
+        let result = self.conn.query_row(
+            "SELECT provider_index FROM providers
+             WHERE model_name = ?1 AND last_invoked_at IS NOT NULL
+             ORDER BY last_invoked_at DESC LIMIT 1",
+            params![model_name],
+            |row| row.get::<_, i64>(0),
+        );
+
+        match result {
+            Ok(idx) => Ok(Some(idx as usize)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(format!("Failed to query last invocation: {e}")),
+        }
+    }
+
+    /// Average duration of the provider's last `sample_size` successful
+    /// invocations, or `None` if none were timed.
+    pub fn recent_average_latency_ms(
+        &self,
+        model_name: &str,
+        provider_index: usize,
+        sample_size: usize,
+    ) -> Result<Option<f64>, String> {
+        self.conn
+            .query_row(
+                "SELECT AVG(duration_ms) FROM (
+                    SELECT duration_ms FROM invocations
+                    WHERE model_name = ?1 AND provider_index = ?2
+                      AND success = 1 AND duration_ms IS NOT NULL
+                    ORDER BY id DESC LIMIT ?3
+                 )",
+                params![model_name, provider_index as i64, sample_size as i64],
+                |row| row.get(0),
+            )
+            .map_err(|e| format!("Failed to query latency: {e}"))
+    }
+
+    /// Per-provider latency of the model's successful invocations, slowest first.
+    pub fn provider_latencies(&self, model_name: &str) -> Result<Vec<ProviderLatency>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT provider_index, COUNT(*), AVG(duration_ms), MAX(duration_ms),
+                        AVG(prompt_bytes), AVG(stdout_bytes)
+                 FROM invocations
+                 WHERE model_name = ?1 AND success = 1 AND duration_ms IS NOT NULL
+                 GROUP BY provider_index
+                 ORDER BY AVG(duration_ms) DESC",
+            )
+            .map_err(|e| format!("Failed to prepare latency query: {e}"))?;
+
+        let rows = stmt
+            .query_map(params![model_name], |row| {
+                Ok(ProviderLatency {
+                    provider_index: row.get::<_, i64>(0)? as usize,
+                    samples: row.get::<_, i64>(1)? as u64,
+                    avg_duration_ms: row.get(2)?,
+                    max_duration_ms: row.get::<_, i64>(3)? as u64,
+                    avg_prompt_bytes: row.get(4)?,
+                    avg_stdout_bytes: row.get(5)?,
+                })
+            })
+            .map_err(|e| format!("Failed to query latencies: {e}"))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read latency row: {e}"))
+    }
+
+    /// The invocations recorded under `run_id`, oldest first.
+    pub fn run_invocations(&self, run_id: &str) -> Result<Vec<InvocationRecord>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT model_name, provider_index, success, exit_code, error_category, agent_name, created_at
+                 FROM invocations WHERE run_id = ?1 ORDER BY id",
+            )
+            .map_err(|e| format!("Failed to prepare run query: {e}"))?;
+
+        let rows = stmt
+            .query_map(params![run_id], |row| {
+                Ok(InvocationRecord {
+                    model_name: row.get(0)?,
+                    provider_index: row.get::<_, i64>(1)? as usize,
+                    success: row.get::<_, i64>(2)? != 0,
+                    exit_code: row.get(3)?,
+                    error_category: row.get(4)?,
+                    agent_name: row.get(5)?,
+                    created_at: parse_timestamp(row.get(6)?).unwrap_or_default(),
+                })
+            })
+            .map_err(|e| format!("Failed to query run: {e}"))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read invocation row: {e}"))
+    }
+
+    // --- Aggregate queries (stats) ---
+
+    /// Per-provider invocation counts, error counts and average duration.
+    /// Cancelled invocations are counted, but not as errors.
+    pub fn invocation_summaries(
+        &self,
+        filter: &StatsFilter,
+    ) -> Result<Vec<InvocationSummary>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT model_name, provider_index, COUNT(*),
+                        SUM(success = 0 AND error_category IS NOT ?4), AVG(duration_ms),
+                        MIN(created_at), MAX(created_at)
+                 FROM invocations
+                 WHERE (?1 IS NULL OR model_name = ?1)
+                   AND (?2 IS NULL OR created_at >= ?2)
+                   AND (?3 IS NULL OR created_at < ?3)
+                 GROUP BY model_name, provider_index
+                 ORDER BY model_name, provider_index",
+            )
+            .map_err(|e| format!("Failed to prepare summary query: {e}"))?;
+
+        let rows = stmt
+            .query_map(filter_params(filter), |row| {
+                Ok(InvocationSummary {
+                    model_name: row.get(0)?,
+                    provider_index: row.get::<_, i64>(1)? as usize,
+                    invocations: row.get::<_, i64>(2)? as u64,
+                    errors: row.get::<_, i64>(3)? as u64,
+                    avg_duration_ms: row.get(4)?,
+                    first_invoked_at: parse_timestamp(row.get(5)?),
+                    last_invoked_at: parse_timestamp(row.get(6)?),
+                })
+            })
+            .map_err(|e| format!("Failed to query summaries: {e}"))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read summary row: {e}"))
+    }
+
+    /// How often each error category occurred per provider, most frequent first.
+    pub fn error_category_counts(
+        &self,
+        filter: &StatsFilter,
+    ) -> Result<Vec<ErrorCategoryCount>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT model_name, provider_index, error_category, COUNT(*)
+                 FROM invocations
+                 WHERE success = 0 AND error_category IS NOT NULL
+                   AND error_category IS NOT ?4
+                   AND (?1 IS NULL OR model_name = ?1)
+                   AND (?2 IS NULL OR created_at >= ?2)
+                   AND (?3 IS NULL OR created_at < ?3)
+                 GROUP BY model_name, provider_index, error_category
+                 ORDER BY model_name, provider_index, COUNT(*) DESC, error_category",
+            )
+            .map_err(|e| format!("Failed to prepare category query: {e}"))?;
+
+        let rows = stmt
+            .query_map(filter_params(filter), |row| {
+                Ok(ErrorCategoryCount {
+                    model_name: row.get(0)?,
+                    provider_index: row.get::<_, i64>(1)? as usize,
+                    category: row.get(2)?,
+                    count: row.get::<_, i64>(3)? as u64,
+                })
+            })
+            .map_err(|e| format!("Failed to query error categories: {e}"))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read category row: {e}"))
+    }
+
+    /// Current and longest success streak of every provider within the filter.
+    /// Cancelled invocations neither extend nor break a streak.
+    pub fn success_streaks(&self, filter: &StatsFilter) -> Result<Vec<SuccessStreak>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT model_name, provider_index, success
+                 FROM invocations
+                 WHERE error_category IS NOT ?4
+                   AND (?1 IS NULL OR model_name = ?1)
+                   AND (?2 IS NULL OR created_at >= ?2)
+                   AND (?3 IS NULL OR created_at < ?3)
+                 ORDER BY model_name, provider_index, id",
+            )
+            .map_err(|e| format!("Failed to prepare streak query: {e}"))?;
+
+        let rows = stmt
+            .query_map(filter_params(filter), |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)? as usize,
+                    row.get::<_, i64>(2)? != 0,
+                ))
+            })
+            .map_err(|e| format!("Failed to query streaks: {e}"))?;
+
+        let mut streaks: Vec<SuccessStreak> = Vec::new();
+        for row in rows {
+            let (model_name, provider_index, success) =
+                row.map_err(|e| format!("Failed to read streak row: {e}"))?;
+
+            let same = streaks
+                .last()
+                .is_some_and(|s| s.model_name == model_name && s.provider_index == provider_index);
+            if !same {
+                streaks.push(SuccessStreak {
+                    model_name,
+                    provider_index,
+                    ..Default::default()
+                });
+            }
+            let streak = streaks.last_mut().unwrap();
+            if success {
+                streak.current += 1;
+                streak.longest = streak.longest.max(streak.current);
+            } else {
+                streak.current = 0;
+            }
+        }
+        Ok(streaks)
+    }
+
+    /// Every provider with recorded stats, optionally limited to one model.
+    pub fn list_providers(&self, model_name: Option<&str>) -> Result<Vec<ProviderRecord>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT model_name, provider_index, invocation_count, error_count,
+                        last_error, last_error_at, last_invoked_at
+                 FROM providers
+                 WHERE ?1 IS NULL OR model_name = ?1
+                 ORDER BY model_name, provider_index",
+            )
+            .map_err(|e| format!("Failed to prepare query: {e}"))?;
+
+        let rows = stmt
+            .query_map(params![model_name], |row| {
+                Ok(ProviderRecord {
+                    model_name: row.get(0)?,
+                    provider_index: row.get::<_, i64>(1)? as usize,
+                    invocation_count: row.get::<_, i64>(2)? as u64,
+                    error_count: row.get::<_, i64>(3)? as u64,
+                    last_error: row.get(4)?,
+                    last_error_at: parse_timestamp(row.get(5)?),
+                    last_invoked_at: parse_timestamp(row.get(6)?),
+                })
+            })
+            .map_err(|e| format!("Failed to query providers: {e}"))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Failed to read provider row: {e}"))
+    }
+
+    // --- In-flight leases ---
+
+    /// Mark one invocation of the provider as running until it is released
+    /// or `ttl` passes, whichever comes first. Expired leases are purged.
+    pub fn acquire_lease(
+        &self,
+        model_name: &str,
+        provider_index: usize,
+        ttl: chrono::Duration,
+    ) -> Result<i64, String> {
+        let now = Utc::now();
+        self.conn
+            .execute(
+                "DELETE FROM provider_leases WHERE expires_at <= ?1",
+                params![now.to_rfc3339()],
+            )
+            .map_err(|e| format!("Failed to purge leases: {e}"))?;
+        self.conn
+            .execute(
+                "INSERT INTO provider_leases (model_name, provider_index, pid, acquired_at, expires_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    model_name,
+                    provider_index as i64,
+                    std::process::id() as i64,
+                    now.to_rfc3339(),
+                    (now + ttl).to_rfc3339(),
+                ],
+            )
+            .map_err(|e| format!("Failed to acquire lease: {e}"))?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Push the lease's expiry to `ttl` from now.
+    pub fn renew_lease(&self, lease_id: i64, ttl: chrono::Duration) -> Result<(), String> {
+        self.conn
+            .execute(
+                "UPDATE provider_leases SET expires_at = ?2 WHERE id = ?1",
+                params![lease_id, (Utc::now() + ttl).to_rfc3339()],
+            )
+            .map_err(|e| format!("Failed to renew lease: {e}"))?;
+        Ok(())
+    }
+
+    pub fn release_lease(&self, lease_id: i64) -> Result<(), String> {
+        self.conn
+            .execute(
+                "DELETE FROM provider_leases WHERE id = ?1",
+                params![lease_id],
+            )
+            .map_err(|e| format!("Failed to release lease: {e}"))?;
+        Ok(())
+    }
+
+    /// Invocations currently running on the provider (unexpired leases).
+    pub fn in_flight_count(&self, model_name: &str, provider_index: usize) -> Result<u64, String> {
+        let count: i64 = self
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM provider_leases
+                 WHERE model_name = ?1 AND provider_index = ?2 AND expires_at > ?3",
+                params![model_name, provider_index as i64, Utc::now().to_rfc3339()],
+                |row| row.get(0),
+            )
+            .map_err(|e| format!("Failed to count leases: {e}"))?;
+        Ok(count as u64)
+    }
+
+    // --- Model fallback ---
+
+    pub fn record_model_hop(
+        &self,
+        from_model: &str,
+        to_model: &str,
+        reason: &str,
+        agent_name: Option<&str>,
+    ) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT INTO model_hops (from_model, to_model, reason, agent_name, hopped_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    from_model,
+                    to_model,
+                    reason,
+                    agent_name,
+                    Utc::now().to_rfc3339()
+                ],
+            )
+            .map_err(|e| format!("Failed to record model hop: {e}"))?;
+        Ok(())
+    }
+
+    /// Hops since `since` (all when `None`), oldest first.
+    pub fn list_model_hops(
+        &self,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<ModelHopRecord>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT from_model, to_model, reason, agent_name, hopped_at FROM model_hops
+                 WHERE ?1 IS NULL OR hopped_at >= ?1
+                 ORDER BY id",
+            )
+            .map_err(|e| format!("Failed to query model hops: {e}"))?;
+        let rows = stmt
+            .query_map(params![since.map(|t| t.to_rfc3339())], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, String>(4)?,
+                ))
+            })
+            .map_err(|e| format!("Failed to query model hops: {e}"))?;
+
+        let mut hops = Vec::new();
+        for row in rows {
+            let (from_model, to_model, reason, agent_name, hopped_at) =
+                row.map_err(|e| format!("Failed to read model hop: {e}"))?;
+            hops.push(ModelHopRecord {
+                from_model,
+                to_model,
+                reason,
+                agent_name,
+                hopped_at: parse_timestamp(Some(hopped_at)).unwrap_or_else(Utc::now),
+            });
+        }
+        Ok(hops)
+    }
+
+    // --- Cooldown operations ---
+
+    /// Put a provider into cooldown until `until`, incrementing its strike count.
+    pub fn set_cooldown(
+        &self,
+        model_name: &str,
+        provider_index: usize,
+        until: DateTime<Utc>,
+        reason: &str,
+    ) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT INTO provider_cooldowns (model_name, provider_index, until, reason, strikes)
+                 VALUES (?1, ?2, ?3, ?4, 1)
+                 ON CONFLICT (model_name, provider_index)
+                 DO UPDATE SET
+                    until = ?3,
+                    reason = ?4,
+                    strikes = strikes + 1",
+                params![model_name, provider_index as i64, until.to_rfc3339(), reason],
+            )
+            .map_err(|e| format!("Failed to set cooldown: {e}"))?;
+        Ok(())
+    }
+
+    /// The provider's cooldown record, including expired ones (their strike
+    /// count still matters for backoff until a success clears it).
+    pub fn get_cooldown(
+        &self,
+        model_name: &str,
+        provider_index: usize,
+    ) -> Result<Option<CooldownRecord>, String> {
+        let result = self.conn.query_row(
+            "SELECT until, reason, strikes FROM provider_cooldowns
+             WHERE model_name = ?1 AND provider_index = ?2",
+            params![model_name, provider_index as i64],
+            |row| {
+                let until: String = row.get(0)?;
+                Ok(CooldownRecord {
+                    model_name: model_name.to_string(),
+                    provider_index,
+                    until: DateTime::parse_from_rfc3339(&until)
+                        .map(|dt| dt.with_timezone(&Utc))
+                        .unwrap_or_else(|_| Utc::now()),
+                    reason: row.get(1)?,
+                    strikes: row.get::<_, i64>(2)? as u32,
+                })
+            },
+        );
+
+        match result {
+            Ok(record) => Ok(Some(record)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(format!("Failed to query cooldown: {e}")),
+        }
+    }
+
+    /// Remove a provider's cooldown and reset its strikes.
+    pub fn clear_cooldown(&self, model_name: &str, provider_index: usize) -> Result<(), String> {
+        self.conn
+            .execute(
+                "DELETE FROM provider_cooldowns WHERE model_name = ?1 AND provider_index = ?2",
+                params![model_name, provider_index as i64],
+            )
+            .map_err(|e| format!("Failed to clear cooldown: {e}"))?;
+        Ok(())
+    }
+
     // --- CLI Provider operations ---
 
     /// Insert or update a CLI provider record.
@@ -494,8 +1030,8 @@ impl StateDb {
     pub fn insert_account(&self, account: &AccountRecord) -> Result<(), String> {
         self.conn
             .execute(
-                "INSERT INTO accounts (id, provider, profile_name, auth_method, auth_status, created_at)
-                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                "INSERT INTO accounts (id, provider, profile_name, auth_method, auth_status, created_at, config_dir)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
                 params![
                     &account.id,
                     &account.provider,
@@ -503,6 +1039,7 @@ impl StateDb {
                     &account.auth_method.to_db_string(),
                     account.auth_status.as_str(),
                     &account.created_at,
+                    &account.config_dir,
                 ],
             )
             .map_err(|e| format!("Failed to insert account: {e}"))?;
@@ -514,12 +1051,12 @@ impl StateDb {
         let (sql, bind_provider);
         match provider {
             Some(p) => {
-                sql = "SELECT id, provider, profile_name, auth_method, auth_status, created_at
+                sql = "SELECT id, provider, profile_name, auth_method, auth_status, created_at, config_dir
                        FROM accounts WHERE provider = ?1 ORDER BY id";
                 bind_provider = Some(p.to_string());
             }
             None => {
-                sql = "SELECT id, provider, profile_name, auth_method, auth_status, created_at
+                sql = "SELECT id, provider, profile_name, auth_method, auth_status, created_at, config_dir
                        FROM accounts ORDER BY provider, id";
                 bind_provider = None;
             }
@@ -746,10 +1283,29 @@ impl StateDb {
             auth_method: AuthMethod::from_db_string(&auth_method_str),
             auth_status: AuthStatus::from_str(&auth_status_str),
             created_at: row.get(5)?,
+            config_dir: row.get(6)?,
         })
     }
 }
 
+/// Bind a [`StatsFilter`] as `?1` (model), `?2` (since) and `?3` (until).
+/// Parameters of the stats queries: the filter, then the category they leave
+/// out of error counts.
+fn filter_params(filter: &StatsFilter) -> [Option<String>; 4] {
+    [
+        filter.model_name.clone(),
+        filter.since.map(|t| t.to_rfc3339()),
+        filter.until.map(|t| t.to_rfc3339()),
+        Some(CANCELLED_CATEGORY.to_string()),
+    ]
+}
+
+fn parse_timestamp(value: Option<String>) -> Option<DateTime<Utc>> {
+    value
+        .and_then(|s| DateTime::parse_from_rfc3339(&s).ok())
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -766,16 +1322,19 @@ mod tests {
     #[test]
     fn record_and_query() {
         let db = test_db();
-        db.record_invocation("test-model", 0, true, 0, None, None)
-            .unwrap();
-        db.record_invocation(
-            "test-model",
-            0,
-            false,
-            1,
-            Some("rate_limit"),
-            Some("429 Too Many Requests"),
-        )
+        db.record_invocation(&NewInvocation {
+            model_name: "test-model",
+            success: true,
+            ..Default::default()
+        })
+        .unwrap();
+        db.record_invocation(&NewInvocation {
+            model_name: "test-model",
+            exit_code: 1,
+            error_category: Some("rate_limit"),
+            stderr_snippet: Some("429 Too Many Requests"),
+            ..Default::default()
+        })
         .unwrap();
 
         let provider = db.get_provider("test-model", 0).unwrap().unwrap();
@@ -787,11 +1346,160 @@ mod tests {
     #[test]
     fn recent_errors() {
         let db = test_db();
-        db.record_invocation("m", 0, false, 1, None, None).unwrap();
-        db.record_invocation("m", 0, true, 0, None, None).unwrap();
+        db.record_invocation(&NewInvocation {
+            model_name: "m",
+            exit_code: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        db.record_invocation(&NewInvocation {
+            model_name: "m",
+            success: true,
+            ..Default::default()
+        })
+        .unwrap();
+
+        db.record_invocation(&NewInvocation {
+            model_name: "m",
+            exit_code: 130,
+            run_id: Some("r"),
+            cancelled: true,
+            ..Default::default()
+        })
+        .unwrap();
 
         let count = db.recent_error_count("m", 0, 60).unwrap();
         assert_eq!(count, 1);
+        let provider = db.get_provider("m", 0).unwrap().unwrap();
+        assert_eq!((provider.invocation_count, provider.error_count), (3, 1));
+        let log = db.run_invocations("r").unwrap();
+        assert_eq!(log[0].error_category.as_deref(), Some(CANCELLED_CATEGORY));
+    }
+
+    #[test]
+    fn average_latency_uses_recent_successes() {
+        let db = test_db();
+        assert_eq!(db.recent_average_latency_ms("m", 0, 10).unwrap(), None);
+
+        db.record_invocation(&NewInvocation {
+            model_name: "m",
+            success: true,
+            duration_ms: Some(100),
+            ..Default::default()
+        })
+        .unwrap();
+        db.record_invocation(&NewInvocation {
+            model_name: "m",
+            success: true,
+            duration_ms: Some(300),
+            ..Default::default()
+        })
+        .unwrap();
+        db.record_invocation(&NewInvocation {
+            model_name: "m",
+            exit_code: 1,
+            duration_ms: Some(5000),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(
+            db.recent_average_latency_ms("m", 0, 10).unwrap(),
+            Some(200.0)
+        );
+        assert_eq!(
+            db.recent_average_latency_ms("m", 0, 1).unwrap(),
+            Some(300.0)
+        );
+    }
+
+    #[test]
+    fn records_invocation_metrics() {
+        let db = test_db();
+        db.record_invocation(&NewInvocation {
+            model_name: "opus",
+            success: true,
+            duration_ms: Some(1000),
+            prompt_bytes: Some(10),
+            stdout_bytes: Some(100),
+            working_dir: Some("/tmp/project"),
+            agent_name: Some("reviewer"),
+            ..Default::default()
+        })
+        .unwrap();
+        db.record_invocation(&NewInvocation {
+            model_name: "opus",
+            provider_index: 1,
+            success: true,
+            duration_ms: Some(4000),
+            prompt_bytes: Some(20),
+            stdout_bytes: Some(300),
+            ..Default::default()
+        })
+        .unwrap();
+        db.record_invocation(&NewInvocation {
+            model_name: "opus",
+            provider_index: 1,
+            success: true,
+            duration_ms: Some(2000),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let latencies = db.provider_latencies("opus").unwrap();
+        assert_eq!(latencies.len(), 2);
+        assert_eq!(latencies[0].provider_index, 1);
+        assert_eq!(latencies[0].samples, 2);
+        assert_eq!(latencies[0].avg_duration_ms, 3000.0);
+        assert_eq!(latencies[0].max_duration_ms, 4000);
+        assert_eq!(latencies[0].avg_prompt_bytes, Some(20.0));
+        assert_eq!(latencies[1].avg_stdout_bytes, Some(100.0));
+
+        let (working_dir, agent): (Option<String>, Option<String>) = db
+            .conn
+            .query_row(
+                "SELECT working_dir, agent_name FROM invocations WHERE provider_index = 0",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(working_dir.as_deref(), Some("/tmp/project"));
+        assert_eq!(agent.as_deref(), Some("reviewer"));
+    }
+
+    #[test]
+    fn adds_missing_columns_to_old_databases() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.db");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE invocations (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    model_name TEXT NOT NULL,
+                    provider_index INTEGER NOT NULL,
+                    success INTEGER NOT NULL,
+                    exit_code INTEGER NOT NULL,
+                    error_category TEXT,
+                    created_at TEXT NOT NULL
+                );",
+            )
+            .unwrap();
+        }
+
+        let db = StateDb::open(&path).unwrap();
+        db.record_invocation(&NewInvocation {
+            model_name: "m",
+            success: true,
+            duration_ms: Some(42),
+            agent_name: Some("a"),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            db.recent_average_latency_ms("m", 0, 10).unwrap(),
+            Some(42.0)
+        );
     }
 
     #[test]
@@ -800,6 +1508,167 @@ mod tests {
         assert!(db.get_provider("nonexistent", 0).unwrap().is_none());
     }
 
+    #[test]
+    fn leases_count_until_released_or_expired() {
+        let db = test_db();
+        let a = db
+            .acquire_lease("m", 0, chrono::Duration::minutes(5))
+            .unwrap();
+        db.acquire_lease("m", 0, chrono::Duration::minutes(5))
+            .unwrap();
+        db.acquire_lease("m", 1, chrono::Duration::minutes(5))
+            .unwrap();
+        assert_eq!(db.in_flight_count("m", 0).unwrap(), 2);
+
+        db.release_lease(a).unwrap();
+        assert_eq!(db.in_flight_count("m", 0).unwrap(), 1);
+
+        // A crashed run's lease stops counting once it expires.
+        let stale = db
+            .acquire_lease("m", 2, chrono::Duration::seconds(-1))
+            .unwrap();
+        assert_eq!(db.in_flight_count("m", 2).unwrap(), 0);
+
+        // A running one keeps counting as long as it is renewed.
+        db.renew_lease(stale, chrono::Duration::minutes(2)).unwrap();
+        assert_eq!(db.in_flight_count("m", 2).unwrap(), 1);
+    }
+
+    #[test]
+    fn cooldown_roundtrip_and_strikes() {
+        let db = test_db();
+        assert!(db.get_cooldown("m", 0).unwrap().is_none());
+
+        let until = Utc::now() + chrono::Duration::minutes(5);
+        db.set_cooldown("m", 0, until, "rate_limit").unwrap();
+        db.set_cooldown("m", 0, until, "quota_exhausted").unwrap();
+
+        let cooldown = db.get_cooldown("m", 0).unwrap().unwrap();
+        assert_eq!(cooldown.strikes, 2);
+        assert_eq!(cooldown.reason, "quota_exhausted");
+        assert_eq!(cooldown.until.timestamp(), until.timestamp());
+
+        db.clear_cooldown("m", 0).unwrap();
+        assert!(db.get_cooldown("m", 0).unwrap().is_none());
+    }
+
+    fn record(db: &StateDb, model: &str, provider: usize, category: Option<&str>) {
+        db.record_invocation(&NewInvocation {
+            model_name: model,
+            provider_index: provider,
+            success: category.is_none(),
+            exit_code: if category.is_none() { 0 } else { 1 },
+            error_category: category,
+            stderr_snippet: category,
+            duration_ms: Some(100),
+            ..Default::default()
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn invocation_summaries_group_by_provider() {
+        let db = test_db();
+        record(&db, "opus", 0, None);
+        record(&db, "opus", 0, Some("rate_limit"));
+        record(&db, "opus", 1, None);
+        record(&db, "haiku", 0, None);
+
+        let all = db.invocation_summaries(&StatsFilter::default()).unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].model_name, "haiku");
+        assert_eq!(all[1].invocations, 2);
+        assert_eq!(all[1].errors, 1);
+        assert_eq!(all[1].avg_duration_ms, Some(100.0));
+        assert!(all[1].first_invoked_at <= all[1].last_invoked_at);
+
+        let opus = StatsFilter {
+            model_name: Some("opus".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(db.invocation_summaries(&opus).unwrap().len(), 2);
+
+        let future = StatsFilter {
+            since: Some(Utc::now() + chrono::Duration::hours(1)),
+            ..Default::default()
+        };
+        assert!(db.invocation_summaries(&future).unwrap().is_empty());
+
+        let past = StatsFilter {
+            until: Some(Utc::now() - chrono::Duration::hours(1)),
+            ..Default::default()
+        };
+        assert!(db.invocation_summaries(&past).unwrap().is_empty());
+    }
+
+    #[test]
+    fn error_categories_are_counted() {
+        let db = test_db();
+        record(&db, "opus", 0, Some("rate_limit"));
+        record(&db, "opus", 0, Some("network_error"));
+        record(&db, "opus", 0, Some("rate_limit"));
+        record(&db, "opus", 0, None);
+
+        let counts = db.error_category_counts(&StatsFilter::default()).unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[0].category, "rate_limit");
+        assert_eq!(counts[0].count, 2);
+        assert_eq!(counts[1].category, "network_error");
+    }
+
+    #[test]
+    fn success_streaks_track_current_and_longest() {
+        let db = test_db();
+        for category in [None, None, None, Some("rate_limit"), None] {
+            record(&db, "opus", 0, category);
+        }
+        record(&db, "opus", 1, Some("auth_expired"));
+
+        let streaks = db.success_streaks(&StatsFilter::default()).unwrap();
+        assert_eq!(streaks.len(), 2);
+        assert_eq!((streaks[0].current, streaks[0].longest), (1, 3));
+        assert_eq!((streaks[1].current, streaks[1].longest), (0, 0));
+    }
+
+    #[test]
+    fn stats_do_not_count_cancelled_invocations_as_errors() {
+        let db = test_db();
+        record(&db, "opus", 0, None);
+        record(&db, "opus", 0, Some("rate_limit"));
+        record(&db, "opus", 0, None);
+        db.record_invocation(&NewInvocation {
+            model_name: "opus",
+            exit_code: 130,
+            cancelled: true,
+            ..Default::default()
+        })
+        .unwrap();
+        record(&db, "opus", 0, None);
+
+        let filter = StatsFilter::default();
+        let summaries = db.invocation_summaries(&filter).unwrap();
+        assert_eq!((summaries[0].invocations, summaries[0].errors), (5, 1));
+        let counts = db.error_category_counts(&filter).unwrap();
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts[0].category, "rate_limit");
+        let streaks = db.success_streaks(&filter).unwrap();
+        assert_eq!((streaks[0].current, streaks[0].longest), (2, 2));
+    }
+
+    #[test]
+    fn list_providers_includes_last_error() {
+        let db = test_db();
+        record(&db, "opus", 0, Some("rate_limit"));
+        record(&db, "haiku", 0, None);
+
+        let all = db.list_providers(None).unwrap();
+        assert_eq!(all.len(), 2);
+        let opus = db.list_providers(Some("opus")).unwrap();
+        assert_eq!(opus.len(), 1);
+        assert_eq!(opus[0].last_error.as_deref(), Some("rate_limit"));
+        assert!(opus[0].last_error_at.is_some());
+    }
+
     // --- CLI Provider & Account tests ---
 
     fn sample_provider() -> CliProviderRecord {
@@ -862,6 +1731,7 @@ mod tests {
             auth_method: AuthMethod::OAuth,
             auth_status: AuthStatus::Valid,
             created_at: "2026-02-19T00:00:00Z".to_string(),
+            config_dir: Some("/home/user/.claude-work".to_string()),
         };
         db.insert_account(&acct).unwrap();
 
@@ -875,6 +1745,7 @@ mod tests {
             },
             auth_status: AuthStatus::Unknown,
             created_at: "2026-02-19T00:00:00Z".to_string(),
+            config_dir: None,
         };
         db.insert_account(&acct2).unwrap();
 
@@ -887,6 +1758,11 @@ mod tests {
         assert_eq!(claude_accounts.len(), 2);
         assert_eq!(claude_accounts[0].id, "personal");
         assert_eq!(claude_accounts[1].id, "work");
+        assert_eq!(
+            claude_accounts[1].config_dir.as_deref(),
+            Some("/home/user/.claude-work")
+        );
+        assert_eq!(claude_accounts[0].config_dir, None);
 
         let empty = db.list_accounts(Some("codex")).unwrap();
         assert!(empty.is_empty());
@@ -906,6 +1782,7 @@ mod tests {
             },
             auth_status: AuthStatus::NoAuth,
             created_at: "2026-02-19T00:00:00Z".to_string(),
+            config_dir: None,
         };
         db.insert_account(&acct).unwrap();
         assert_eq!(db.list_accounts(None).unwrap().len(), 1);
@@ -0,0 +1,448 @@
+//! Versioned schema for the shared SQLite database.
+//!
+//! `StateDb`, `MemoryGraph` and `VersionTracker` all open the same file and
+//! go through [`migrate`], which applies every step newer than the
+//! database's `PRAGMA user_version` and records the new version. Steps are
+//! append-only: never edit one that has shipped, add a new one instead.
+
+use rusqlite::{Connection, TransactionBehavior};
+
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    up: fn(&Connection) -> Result<(), String>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        up: initial_schema,
+    },
+    Migration {
+        version: 2,
+        description: "provider cooldowns",
+        up: provider_cooldowns,
+    },
+    Migration {
+        version: 3,
+        description: "invocation duration, sizes, working dir and agent",
+        up: invocation_metrics,
+    },
+    Migration {
+        version: 4,
+        description: "in-flight provider leases",
+        up: provider_leases,
+    },
+    Migration {
+        version: 5,
+        description: "agent model fallback hops",
+        up: model_hops,
+    },
+    Migration {
+        version: 6,
+        description: "account config directories",
+        up: account_config_dirs,
+    },
+    Migration {
+        version: 7,
+        description: "invocation run ids",
+        up: invocation_run_ids,
+    },
+];
+
+/// The schema version this build creates and understands.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub fn user_version(conn: &Connection) -> Result<u32, String> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(|e| format!("Failed to read schema version: {e}"))
+}
+
+/// Bring the database up to [`latest_version`].
+///
+/// Each step runs in its own immediate transaction, so concurrent processes
+/// opening the same file apply it once. Fails without touching the database
+/// if it was written by a newer build.
+pub fn migrate(conn: &mut Connection) -> Result<(), String> {
+    let latest = latest_version();
+    let current = user_version(conn)?;
+    if current > latest {
+        return Err(format!(
+            "Database schema version {current} is newer than this build supports ({latest}); \
+             upgrade oulipoly-agent-runner"
+        ));
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(|e| format!("Failed to start migration: {e}"))?;
+
+        // Another process may have migrated while we waited for the lock.
+        if user_version(&tx)? >= migration.version {
+            continue;
+        }
+
+        (migration.up)(&tx).map_err(|e| {
+            format!(
+                "Migration {} ({}) failed: {e}",
+                migration.version, migration.description
+            )
+        })?;
+        tx.pragma_update(None, "user_version", migration.version)
+            .map_err(|e| format!("Failed to record schema version: {e}"))?;
+        tx.commit()
+            .map_err(|e| format!("Failed to commit migration: {e}"))?;
+    }
+
+    Ok(())
+}
+
+/// Tables as they existed before versioning. `IF NOT EXISTS` lets
+/// unversioned databases adopt this step as-is.
+fn initial_schema(conn: &Connection) -> Result<(), String> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS providers (
+            model_name TEXT NOT NULL,
+            provider_index INTEGER NOT NULL,
+            invocation_count INTEGER NOT NULL DEFAULT 0,
+            error_count INTEGER NOT NULL DEFAULT 0,
+            last_error TEXT,
+            last_error_at TEXT,
+            last_invoked_at TEXT,
+            PRIMARY KEY (model_name, provider_index)
+        );
+
+        CREATE TABLE IF NOT EXISTS invocations (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            model_name TEXT NOT NULL,
+            provider_index INTEGER NOT NULL,
+            success INTEGER NOT NULL,
+            exit_code INTEGER NOT NULL,
+            error_category TEXT,
+            created_at TEXT NOT NULL
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_invocations_model
+            ON invocations (model_name, provider_index, created_at);
+
+        CREATE TABLE IF NOT EXISTS memory_nodes (
+            id TEXT PRIMARY KEY,
+            node_type TEXT NOT NULL,
+            label TEXT NOT NULL,
+            data TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS memory_edges (
+            source_id TEXT NOT NULL REFERENCES memory_nodes(id),
+            target_id TEXT NOT NULL REFERENCES memory_nodes(id),
+            edge_type TEXT NOT NULL,
+            data TEXT,
+            created_at TEXT NOT NULL,
+            PRIMARY KEY (source_id, target_id, edge_type)
+        );
+
+        CREATE TABLE IF NOT EXISTS setup_sessions (
+            id TEXT PRIMARY KEY,
+            started_at TEXT NOT NULL,
+            ended_at TEXT,
+            outcome TEXT,
+            turn_count INTEGER DEFAULT 0
+        );
+
+        CREATE TABLE IF NOT EXISTS setup_turns (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            session_id TEXT NOT NULL,
+            turn_number INTEGER NOT NULL,
+            agent_prompt TEXT NOT NULL,
+            agent_response TEXT NOT NULL,
+            events_emitted TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS cli_providers (
+            cli_name TEXT PRIMARY KEY,
+            display_name TEXT NOT NULL,
+            installed INTEGER NOT NULL DEFAULT 0,
+            version TEXT,
+            config_dir TEXT,
+            last_synced TEXT
+        );
+
+        CREATE TABLE IF NOT EXISTS accounts (
+            id TEXT NOT NULL,
+            provider TEXT NOT NULL REFERENCES cli_providers(cli_name),
+            profile_name TEXT NOT NULL,
+            auth_method TEXT NOT NULL,
+            auth_status TEXT NOT NULL DEFAULT 'unknown',
+            created_at TEXT NOT NULL,
+            PRIMARY KEY (id, provider)
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_accounts_provider
+            ON accounts (provider);
+
+        CREATE TABLE IF NOT EXISTS discovered_models (
+            canonical_name TEXT NOT NULL,
+            provider TEXT NOT NULL,
+            discovered_at TEXT NOT NULL,
+            cli_version TEXT NOT NULL,
+            PRIMARY KEY (canonical_name, provider)
+        );
+
+        CREATE TABLE IF NOT EXISTS model_parameters (
+            model_name TEXT NOT NULL,
+            provider TEXT NOT NULL,
+            name TEXT NOT NULL,
+            display_name TEXT NOT NULL,
+            param_type TEXT NOT NULL,
+            description TEXT NOT NULL DEFAULT '',
+            cli_mapping TEXT NOT NULL,
+            PRIMARY KEY (model_name, provider, name)
+        );
+
+        CREATE TABLE IF NOT EXISTS cli_versions (
+            cli_name    TEXT PRIMARY KEY,
+            version     TEXT NOT NULL,
+            path        TEXT,
+            detected_at TEXT NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS cli_version_history (
+            id          INTEGER PRIMARY KEY AUTOINCREMENT,
+            cli_name    TEXT NOT NULL,
+            version     TEXT NOT NULL,
+            path        TEXT,
+            detected_at TEXT NOT NULL
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_cli_version_history_name
+            ON cli_version_history (cli_name, detected_at);",
+    )
+    .map_err(|e| e.to_string())
+}
+
+fn provider_cooldowns(conn: &Connection) -> Result<(), String> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS provider_cooldowns (
+            model_name TEXT NOT NULL,
+            provider_index INTEGER NOT NULL,
+            until TEXT NOT NULL,
+            reason TEXT NOT NULL,
+            strikes INTEGER NOT NULL DEFAULT 1,
+            PRIMARY KEY (model_name, provider_index)
+        );",
+    )
+    .map_err(|e| e.to_string())
+}
+
+fn invocation_metrics(conn: &Connection) -> Result<(), String> {
+    // Unversioned databases from earlier builds may already have some of these.
+    add_column_if_missing(conn, "invocations", "duration_ms", "INTEGER")?;
+    add_column_if_missing(conn, "invocations", "prompt_bytes", "INTEGER")?;
+    add_column_if_missing(conn, "invocations", "stdout_bytes", "INTEGER")?;
+    add_column_if_missing(conn, "invocations", "working_dir", "TEXT")?;
+    add_column_if_missing(conn, "invocations", "agent_name", "TEXT")
+}
+
+fn provider_leases(conn: &Connection) -> Result<(), String> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS provider_leases (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            model_name TEXT NOT NULL,
+            provider_index INTEGER NOT NULL,
+            pid INTEGER NOT NULL,
+            acquired_at TEXT NOT NULL,
+            expires_at TEXT NOT NULL
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_provider_leases
+            ON provider_leases (model_name, provider_index, expires_at);",
+    )
+    .map_err(|e| e.to_string())
+}
+
+fn model_hops(conn: &Connection) -> Result<(), String> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS model_hops (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            from_model TEXT NOT NULL,
+            to_model TEXT NOT NULL,
+            reason TEXT NOT NULL,
+            agent_name TEXT,
+            hopped_at TEXT NOT NULL
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_model_hops_time
+            ON model_hops (hopped_at);",
+    )
+    .map_err(|e| e.to_string())
+}
+
+fn account_config_dirs(conn: &Connection) -> Result<(), String> {
+    add_column_if_missing(conn, "accounts", "config_dir", "TEXT")
+}
+
+fn invocation_run_ids(conn: &Connection) -> Result<(), String> {
+    add_column_if_missing(conn, "invocations", "run_id", "TEXT")?;
+    conn.execute_batch(
+        "CREATE INDEX IF NOT EXISTS idx_invocations_run
+            ON invocations (run_id);",
+    )
+    .map_err(|e| e.to_string())
+}
+
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> Result<(), String> {
+    if !has_column(conn, table, column)? {
+        conn.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"))
+            .map_err(|e| format!("Failed to add {table}.{column}: {e}"))?;
+    }
+    Ok(())
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, String> {
+    let mut stmt = conn
+        .prepare(&format!("PRAGMA table_info({table})"))
+        .map_err(|e| format!("Failed to inspect {table}: {e}"))?;
+    let names = stmt
+        .query_map([], |row| row.get::<_, String>(1))
+        .map_err(|e| format!("Failed to inspect {table}: {e}"))?
+        .filter_map(Result::ok)
+        .collect::<Vec<_>>();
+    Ok(names.iter().any(|name| name == column))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::setup::detection::VersionTracker;
+    use crate::setup::memory::MemoryGraph;
+    use crate::state::StateDb;
+
+    fn table_names(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap();
+        stmt.query_map([], |row| row.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn versions_are_contiguous_from_one() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as u32 + 1, "{}", migration.description);
+        }
+    }
+
+    #[test]
+    fn fresh_database_reaches_latest_version() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(user_version(&conn).unwrap(), latest_version());
+        let tables = table_names(&conn);
+        for table in [
+            "invocations",
+            "provider_cooldowns",
+            "provider_leases",
+            "model_hops",
+            "memory_nodes",
+            "cli_versions",
+        ] {
+            assert!(tables.iter().any(|t| t == table), "missing {table}");
+        }
+        assert!(has_column(&conn, "invocations", "agent_name").unwrap());
+        assert!(has_column(&conn, "invocations", "run_id").unwrap());
+    }
+
+    #[test]
+    fn migrate_is_idempotent() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn upgrades_unversioned_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE invocations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                model_name TEXT NOT NULL,
+                provider_index INTEGER NOT NULL,
+                success INTEGER NOT NULL,
+                exit_code INTEGER NOT NULL,
+                error_category TEXT,
+                created_at TEXT NOT NULL,
+                duration_ms INTEGER
+            );
+            INSERT INTO invocations (model_name, provider_index, success, exit_code, created_at)
+                VALUES ('m', 0, 1, 0, '2026-01-01T00:00:00+00:00');",
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(user_version(&conn).unwrap(), latest_version());
+        assert!(has_column(&conn, "invocations", "stdout_bytes").unwrap());
+        let rows: i64 = conn
+            .query_row("SELECT COUNT(*) FROM invocations", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 1);
+    }
+
+    #[test]
+    fn applies_only_newer_steps() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        initial_schema(&conn).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        assert!(table_names(&conn).iter().any(|t| t == "provider_cooldowns"));
+        assert_eq!(user_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn every_store_refuses_newer_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.db");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.pragma_update(None, "user_version", latest_version() + 1)
+                .unwrap();
+        }
+
+        let err = StateDb::open(&path).err().unwrap();
+        assert!(err.contains("newer than this build"), "{err}");
+        assert!(MemoryGraph::open(&path).is_err());
+        assert!(VersionTracker::open(&path).is_err());
+    }
+
+    #[test]
+    fn stores_share_one_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.db");
+
+        VersionTracker::open(&path).unwrap();
+        MemoryGraph::open(&path).unwrap();
+        StateDb::open(&path).unwrap();
+
+        let conn = Connection::open(&path).unwrap();
+        assert_eq!(user_version(&conn).unwrap(), latest_version());
+        assert!(has_column(&conn, "invocations", "duration_ms").unwrap());
+    }
+}
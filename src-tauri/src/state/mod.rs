@@ -1,5 +1,24 @@
 mod db;
+pub mod migrations;
 
-pub use db::StateDb;
+pub use db::CooldownRecord;
 pub use db::{AccountRecord, AuthMethod, AuthStatus, CliProviderRecord};
 pub use db::{CliMapping, DiscoveredModel, ModelParameter, ParamType};
+pub use db::{
+    ErrorCategoryCount, InvocationSummary, ModelHopRecord, NewInvocation, ProviderLatency,
+    ProviderRecord, StateDb, StatsFilter, SuccessStreak,
+};
+
+/// How long a connection waits for another process's write lock before
+/// failing with "database is locked".
+pub const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Connection settings shared by every store that opens `state.db`: WAL so
+/// readers never block writers, and a busy timeout so concurrent runs queue
+/// for the write lock instead of failing.
+pub fn configure_connection(conn: &rusqlite::Connection) -> Result<(), String> {
+    conn.busy_timeout(BUSY_TIMEOUT)
+        .map_err(|e| format!("Failed to set busy timeout: {e}"))?;
+    conn.execute_batch("PRAGMA journal_mode=WAL; PRAGMA synchronous=NORMAL;")
+        .map_err(|e| format!("Failed to set WAL mode: {e}"))
+}
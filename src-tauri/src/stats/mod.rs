@@ -0,0 +1,333 @@
+use crate::state::{StateDb, StatsFilter};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Invocation history and provider health over a time range.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsReport {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub models: Vec<ModelStats>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelStats {
+    pub model_name: String,
+    pub invocations: u64,
+    pub errors: u64,
+    pub error_rate: f64,
+    pub providers: Vec<ProviderStats>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderStats {
+    pub provider_index: usize,
+    pub invocations: u64,
+    pub errors: u64,
+    pub error_rate: f64,
+    pub avg_duration_ms: Option<f64>,
+    pub first_invoked_at: Option<DateTime<Utc>>,
+    pub last_invoked_at: Option<DateTime<Utc>>,
+    /// Error category → occurrences within the range.
+    pub error_categories: BTreeMap<String, u64>,
+    pub current_streak: u64,
+    pub longest_streak: u64,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<DateTime<Utc>>,
+}
+
+/// Gather the report for every model/provider matching `filter`.
+pub fn collect(state: &StateDb, filter: &StatsFilter) -> Result<StatsReport, String> {
+    let summaries = state.invocation_summaries(filter)?;
+    let categories = state.error_category_counts(filter)?;
+    let streaks = state.success_streaks(filter)?;
+    let records = state.list_providers(filter.model_name.as_deref())?;
+
+    let mut models: Vec<ModelStats> = Vec::new();
+    for summary in summaries {
+        let key = (summary.model_name.as_str(), summary.provider_index);
+        let streak = streaks
+            .iter()
+            .find(|s| (s.model_name.as_str(), s.provider_index) == key);
+        // The providers table only keeps the latest error; show it only if
+        // it falls inside the range.
+        let last_error = records
+            .iter()
+            .find(|r| (r.model_name.as_str(), r.provider_index) == key)
+            .filter(|r| r.last_error_at.is_some_and(|at| in_range(at, filter)));
+
+        let provider = ProviderStats {
+            provider_index: summary.provider_index,
+            invocations: summary.invocations,
+            errors: summary.errors,
+            error_rate: rate(summary.errors, summary.invocations),
+            avg_duration_ms: summary.avg_duration_ms,
+            first_invoked_at: summary.first_invoked_at,
+            last_invoked_at: summary.last_invoked_at,
+            error_categories: categories
+                .iter()
+                .filter(|c| (c.model_name.as_str(), c.provider_index) == key)
+                .map(|c| (c.category.clone(), c.count))
+                .collect(),
+            current_streak: streak.map(|s| s.current).unwrap_or(0),
+            longest_streak: streak.map(|s| s.longest).unwrap_or(0),
+            last_error: last_error.and_then(|r| r.last_error.clone()),
+            last_error_at: last_error.and_then(|r| r.last_error_at),
+        };
+
+        match models.last_mut() {
+            Some(model) if model.model_name == summary.model_name => model.providers.push(provider),
+            _ => models.push(ModelStats {
+                model_name: summary.model_name,
+                invocations: 0,
+                errors: 0,
+                error_rate: 0.0,
+                providers: vec![provider],
+            }),
+        }
+    }
+
+    for model in &mut models {
+        model.invocations = model.providers.iter().map(|p| p.invocations).sum();
+        model.errors = model.providers.iter().map(|p| p.errors).sum();
+        model.error_rate = rate(model.errors, model.invocations);
+    }
+
+    Ok(StatsReport {
+        since: filter.since,
+        until: filter.until,
+        models,
+    })
+}
+
+/// Parse a `--since`/`--until` value: a relative age like `30m`, `24h`,
+/// `7d` or `2w`, a date (`2026-02-19`), or an RFC 3339 timestamp.
+pub fn parse_time_bound(value: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    let value = value.trim();
+
+    if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+        return Ok(dt.with_timezone(&Utc));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc());
+    }
+
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: i64 = amount
+        .parse()
+        .map_err(|_| format!("Invalid time '{value}': expected e.g. 24h, 7d or 2026-02-19"))?;
+    let age = match unit {
+        "s" => Duration::seconds(amount),
+        "m" => Duration::minutes(amount),
+        "h" => Duration::hours(amount),
+        "d" => Duration::days(amount),
+        "w" => Duration::weeks(amount),
+        _ => {
+            return Err(format!(
+                "Invalid time unit in '{value}': use s, m, h, d or w"
+            ));
+        }
+    };
+    Ok(now - age)
+}
+
+/// Render the report as an aligned plain-text table.
+pub fn render_table(report: &StatsReport) -> String {
+    let mut out = format!(
+        "Invocations {}\n\n",
+        match (report.since, report.until) {
+            (Some(since), Some(until)) =>
+                format!("from {} to {}", format_time(since), format_time(until)),
+            (Some(since), None) => format!("since {}", format_time(since)),
+            (None, Some(until)) => format!("before {}", format_time(until)),
+            (None, None) => "(all time)".to_string(),
+        }
+    );
+
+    if report.models.is_empty() {
+        out.push_str("No invocations recorded.\n");
+        return out;
+    }
+
+    out.push_str(&format!(
+        "{:<28} {:>7} {:>7} {:>7} {:>9} {:>7} {:>7}  {}\n",
+        "MODEL / PROVIDER", "CALLS", "ERRORS", "ERR %", "AVG MS", "STREAK", "BEST", "LAST CALL"
+    ));
+
+    for model in &report.models {
+        out.push_str(&format!(
+            "{:<28} {:>7} {:>7} {:>6.1}%\n",
+            model.model_name,
+            model.invocations,
+            model.errors,
+            model.error_rate * 100.0
+        ));
+
+        for p in &model.providers {
+            out.push_str(&format!(
+                "{:<28} {:>7} {:>7} {:>6.1}% {:>9} {:>7} {:>7}  {}\n",
+                format!("  provider {}", p.provider_index),
+                p.invocations,
+                p.errors,
+                p.error_rate * 100.0,
+                p.avg_duration_ms
+                    .map(|ms| format!("{ms:.0}"))
+                    .unwrap_or_else(|| "-".to_string()),
+                p.current_streak,
+                p.longest_streak,
+                p.last_invoked_at.map(format_time).unwrap_or_default()
+            ));
+
+            if !p.error_categories.is_empty() {
+                let categories: Vec<String> = p
+                    .error_categories
+                    .iter()
+                    .map(|(category, count)| format!("{category}={count}"))
+                    .collect();
+                out.push_str(&format!("      errors: {}\n", categories.join(", ")));
+            }
+            if let (Some(error), Some(at)) = (&p.last_error, p.last_error_at) {
+                out.push_str(&format!(
+                    "      last error ({}): {}\n",
+                    format_time(at),
+                    first_line(error)
+                ));
+            }
+        }
+    }
+
+    out
+}
+
+fn rate(errors: u64, invocations: u64) -> f64 {
+    if invocations == 0 {
+        0.0
+    } else {
+        errors as f64 / invocations as f64
+    }
+}
+
+fn in_range(at: DateTime<Utc>, filter: &StatsFilter) -> bool {
+    filter.since.is_none_or(|since| at >= since) && filter.until.is_none_or(|until| at < until)
+}
+
+fn format_time(t: DateTime<Utc>) -> String {
+    t.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+fn first_line(text: &str) -> String {
+    let line = text.lines().find(|l| !l.trim().is_empty()).unwrap_or("");
+    if line.chars().count() > 100 {
+        format!("{}...", line.chars().take(100).collect::<String>())
+    } else {
+        line.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::NewInvocation;
+    use std::path::Path;
+
+    fn record(db: &StateDb, model: &str, provider: usize, category: Option<&str>) {
+        db.record_invocation(&NewInvocation {
+            model_name: model,
+            provider_index: provider,
+            success: category.is_none(),
+            exit_code: if category.is_none() { 0 } else { 1 },
+            error_category: category,
+            stderr_snippet: category.map(|_| "429 Too Many Requests\nmore detail"),
+            duration_ms: Some(250),
+            ..Default::default()
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn collects_models_and_providers() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        record(&db, "opus", 0, None);
+        record(&db, "opus", 0, Some("rate_limit"));
+        record(&db, "opus", 1, None);
+        record(&db, "opus", 1, None);
+        record(&db, "haiku", 0, None);
+
+        let report = collect(&db, &StatsFilter::default()).unwrap();
+        assert_eq!(report.models.len(), 2);
+
+        let opus = &report.models[1];
+        assert_eq!(opus.model_name, "opus");
+        assert_eq!(opus.invocations, 4);
+        assert_eq!(opus.errors, 1);
+        assert_eq!(opus.error_rate, 0.25);
+
+        let p0 = &opus.providers[0];
+        assert_eq!(p0.error_categories.get("rate_limit"), Some(&1));
+        assert_eq!(p0.current_streak, 0);
+        assert_eq!(p0.longest_streak, 1);
+        assert!(p0.last_error.is_some());
+        assert_eq!(opus.providers[1].current_streak, 2);
+
+        let table = render_table(&report);
+        assert!(table.contains("  provider 0"));
+        assert!(table.contains("errors: rate_limit=1"));
+        assert!(table.contains("last error"));
+        assert!(table.contains("429 Too Many Requests"));
+        assert!(!table.contains("more detail"));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["models"][1]["providers"][0]["errors"], 1);
+    }
+
+    #[test]
+    fn last_error_outside_range_is_hidden() {
+        let db = StateDb::open(Path::new(":memory:")).unwrap();
+        record(&db, "opus", 0, Some("rate_limit"));
+
+        let filter = StatsFilter {
+            until: Some(Utc::now() + Duration::hours(1)),
+            ..Default::default()
+        };
+        let report = collect(&db, &filter).unwrap();
+        assert!(report.models[0].providers[0].last_error.is_some());
+
+        let filter = StatsFilter {
+            since: Some(Utc::now() + Duration::hours(1)),
+            ..Default::default()
+        };
+        let report = collect(&db, &filter).unwrap();
+        assert!(report.models.is_empty());
+        assert!(render_table(&report).contains("No invocations recorded."));
+    }
+
+    #[test]
+    fn parses_time_bounds() {
+        let now = DateTime::parse_from_rfc3339("2026-02-19T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(
+            parse_time_bound("24h", now).unwrap(),
+            now - Duration::hours(24)
+        );
+        assert_eq!(
+            parse_time_bound("7d", now).unwrap(),
+            now - Duration::days(7)
+        );
+        assert_eq!(
+            parse_time_bound("2026-02-01", now).unwrap().to_rfc3339(),
+            "2026-02-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            parse_time_bound("2026-02-19T10:00:00+02:00", now).unwrap(),
+            now - Duration::hours(4)
+        );
+        assert!(parse_time_bound("yesterday", now).is_err());
+        assert!(parse_time_bound("5y", now).is_err());
+    }
+}
@@ -0,0 +1,31 @@
+//! Fixtures shared by the unit tests.
+
+use crate::config::{ModelConfig, PromptMode, ProviderConfig};
+use std::collections::HashMap;
+
+/// A provider that runs `script` with `sh -c`.
+pub fn sh_provider(script: &str) -> ProviderConfig {
+    ProviderConfig {
+        command: "sh".to_string(),
+        args: vec!["-c".to_string(), script.to_string()],
+        ..Default::default()
+    }
+}
+
+/// A model with one [`sh_provider`], given the prompt as `$0`.
+pub fn sh_model(name: &str, script: &str) -> ModelConfig {
+    ModelConfig {
+        name: name.to_string(),
+        prompt_mode: PromptMode::Arg,
+        providers: vec![sh_provider(script)],
+        ..Default::default()
+    }
+}
+
+/// [`sh_model`]s keyed by name, from `(name, script)` pairs.
+pub fn sh_models(models: &[(&str, &str)]) -> HashMap<String, ModelConfig> {
+    models
+        .iter()
+        .map(|(name, script)| (name.to_string(), sh_model(name, script)))
+        .collect()
+}
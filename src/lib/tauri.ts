@@ -4,6 +4,7 @@ import type {
 	DetectionReport,
 	ModelConfig,
 	ModelSummary,
+	OutputChunk,
 	PoolSummary,
 	SetupEvent,
 	TestModelResult,
@@ -70,8 +71,11 @@ export function reloadModels(): Promise<void> {
 	return tauriInvoke<void>("reload_models");
 }
 
-export function testModel(name: string): Promise<TestModelResult> {
-	return tauriInvoke<TestModelResult>("test_model", { name });
+export function testModel(
+	name: string,
+	onOutput?: Channel<OutputChunk>,
+): Promise<TestModelResult> {
+	return tauriInvoke<TestModelResult>("test_model", { name, onOutput });
 }
 
 export function chatSend(
@@ -113,17 +113,55 @@ export interface TestModelResult {
 	exit_code: number;
 }
 
+export interface OutputChunk {
+	stream: "stdout" | "stderr";
+	line: string;
+}
+
 export interface ProviderConfig {
 	command: string;
 	args: string[];
-}
-
-export type PromptMode = "stdin" | "arg";
+	timeout?: number | null;
+	weight?: number | null;
+	max_concurrent?: number | null;
+	env?: Record<string, string>;
+	env_remove?: string[];
+	env_clear?: boolean;
+	account?: string | null;
+}
+
+export type PromptMode = "stdin" | "arg" | "file" | "env" | "arg_template";
+
+export type ErrorCategory =
+	| "rate_limit"
+	| "quota_exhausted"
+	| "auth_expired"
+	| "cli_version_mismatch"
+	| "network_error"
+	| "timeout"
+	| "unknown";
+
+export interface RetryPolicy {
+	max_attempts: number;
+	on: ErrorCategory[];
+}
+
+export type BalanceStrategy =
+	| "least_used"
+	| "round_robin"
+	| "weighted"
+	| "priority"
+	| "least_latency";
 
 export interface ModelConfig {
 	name: string;
 	prompt_mode: PromptMode;
+	prompt_env?: string | null;
 	providers: ProviderConfig[];
+	retry?: RetryPolicy;
+	timeout?: number | null;
+	strategy?: BalanceStrategy;
+	wait_for_slot?: number | null;
 }
 
 export interface ModelSummary {